@@ -1,6 +1,75 @@
+use std::{fs::OpenOptions, io::Write, path::PathBuf};
+
+use ota_sign::{ed25519_dalek::SigningKey, encode_key};
+
+/// Relative to this crate, i.e. the project root. `OTA_PUBLIC_KEY` overrides it.
+const DEFAULT_OTA_KEY: &str = "../../ota-key.pub";
+/// With `HITACHI_OTA_DEV_KEY=1` and no `ota-key.pub`, a key pair made on the first build and
+/// kept next to it, so images signed with `ota-dev-key.sec` go on taking.
+const DEV_OTA_KEY: &str = "../../ota-dev-key";
+
 fn main() {
     embuild::espidf::sysenv::output();
+    embed_ota_key();
+    println!("cargo::rustc-check-cfg=cfg(ota_dev_key)");
     println!("cargo::rustc-check-cfg=cfg(esp_idf_log_colors)");
     println!("cargo::rustc-check-cfg=cfg(esp_idf_log_timestamp_source_rtos)");
     println!("cargo::rustc-check-cfg=cfg(esp_idf_log_timestamp_source_system)");
 }
+
+/// Writes the key OTA images have to be signed with to `$OUT_DIR/ota_public_key.rs`.
+fn embed_ota_key() {
+    println!("cargo::rerun-if-env-changed=OTA_PUBLIC_KEY");
+    println!("cargo::rerun-if-env-changed=HITACHI_OTA_DEV_KEY");
+    println!("cargo::rerun-if-changed={DEFAULT_OTA_KEY}");
+    let path = match std::env::var("OTA_PUBLIC_KEY") {
+        Ok(path) => path,
+        Err(_) if PathBuf::from(DEFAULT_OTA_KEY).exists() => DEFAULT_OTA_KEY.into(),
+        Err(_) if std::env::var("HITACHI_OTA_DEV_KEY").is_ok_and(|v| v == "1") => {
+            println!(
+                "cargo::warning=no ota-key.pub in the project root, using the dev key in \
+                 ota-dev-key.pub - don't hand out wands built with it"
+            );
+            println!("cargo::rustc-cfg=ota_dev_key");
+            dev_key()
+        }
+        Err(_) => panic!(
+            "no ota-key.pub in the project root. generate one with \
+             `cargo run --manifest-path tools/ota-sign/Cargo.toml -- keygen ota-key`, \
+             or set HITACHI_OTA_DEV_KEY=1 to build with a throwaway key"
+        ),
+    };
+    println!("cargo::rerun-if-changed={path}");
+
+    let key = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("can't read the OTA public key from {path}: {e}"));
+    let key = ota_sign::decode_public_key(&key)
+        .unwrap_or_else(|e| panic!("{path} isn't an OTA public key: {e}"));
+
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("ota_public_key.rs");
+    std::fs::write(out, format!("{key:?}")).unwrap();
+}
+
+/// The public half of the dev key, generating the pair if it isn't there yet.
+fn dev_key() -> String {
+    let (secret, public) = (format!("{DEV_OTA_KEY}.sec"), format!("{DEV_OTA_KEY}.pub"));
+    if PathBuf::from(&public).exists() {
+        return public;
+    }
+
+    let mut seed = [0; 32];
+    getrandom::getrandom(&mut seed).expect("failed to get randomness for the dev OTA key");
+    let key = SigningKey::from_bytes(&seed);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&secret)
+        .and_then(|mut file| writeln!(file, "{}", encode_key(&key.to_bytes())))
+        .unwrap_or_else(|e| panic!("failed to write {secret}: {e}"));
+    std::fs::write(&public, encode_key(&key.verifying_key().to_bytes()) + "\n")
+        .unwrap_or_else(|e| panic!("failed to write {public}: {e}"));
+    public
+}
@@ -0,0 +1,64 @@
+//! Plumbing for the policies that run on a millisecond clock: the sleep timer, the link-loss
+//! failsafe and the pairing window. Each is a plain state machine that's told the time; a
+//! [`Clocked`] shares it between threads, supplies the time and ticks it from its own thread.
+
+use std::{
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// A `T` behind a lock, clocked from when this was created.
+pub struct Clocked<T> {
+    inner: Arc<Mutex<T>>,
+    epoch: Instant,
+}
+
+impl<T> Clone for Clocked<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            epoch: self.epoch,
+        }
+    }
+}
+
+impl<T> Clocked<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    /// Locks the `T` and calls `f` with it and the current time.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T, u64) -> R) -> R {
+        let now_ms = self.now_ms();
+        f(&mut self.inner.lock(), now_ms)
+    }
+}
+
+impl<T: Send + 'static> Clocked<T> {
+    /// Spawns a thread that calls `tick` every `interval`.
+    pub fn spawn_ticker(
+        &self,
+        name: &str,
+        interval: Duration,
+        mut tick: impl FnMut(&Clocked<T>) + Send + 'static,
+    ) -> std::io::Result<JoinHandle<()>> {
+        let clocked = self.clone();
+        std::thread::Builder::new()
+            .name(name.into())
+            .stack_size(3072)
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                tick(&clocked);
+            })
+    }
+}
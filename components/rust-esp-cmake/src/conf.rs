@@ -1,12 +1,234 @@
-use std::fmt::Display;
+use std::{fmt::Display, fs::File, time::Duration};
 
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    conn::{emulation::Emulation, lovense::LovenseDevice},
+    intensity::{Curve, IntensityMap, MAX_INTENSITY},
+};
+
+pub const CONFIG_PATH: &str = "/littlefs/config.json";
+
+/// Stands in for secrets in [`Config::to_redacted`]. Sending it back keeps the secret as it was.
+pub const REDACTED: &str = "********";
+const SECRETS: [&str; 3] = [
+    "wifi.password",
+    "ble.security.passkey.value",
+    "http.admin_token",
+];
+
+/// Sections that take effect as soon as they're saved. Everything else needs a restart.
+pub const LIVE_SECTIONS: [&str; 4] = ["motor", "sleep", "control", "http"];
+
+/// Held from loading the config to saving it in [`Config::edit`], so the console and HTTP can't
+/// undo each other's changes.
+static EDIT_LOCK: Mutex<()> = parking_lot::const_mutex(());
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub wifi: WifiConfig,
     pub motor: MotorConfig,
     pub remote_log: RemoteLogConfig,
+    #[serde(default)]
+    pub thermal: ThermalConfig,
+    #[serde(default)]
+    pub sleep: SleepConfig,
+    #[serde(default)]
+    pub ble: BleConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Self> {
+        Ok(serde_json::from_reader(File::open(CONFIG_PATH)?)?)
+    }
+
+    /// Checks the sections that get replaced by their defaults at boot if they're invalid.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.motor
+            .validate()
+            .map_err(|e| anyhow::anyhow!("motor: {e}"))?;
+        self.thermal
+            .validate()
+            .map_err(|e| anyhow::anyhow!("thermal: {e}"))?;
+        self.ble
+            .security
+            .validate()
+            .map_err(|e| anyhow::anyhow!("ble.security: {e}"))?;
+        self.control
+            .validate()
+            .map_err(|e| anyhow::anyhow!("control: {e}"))?;
+        self.http
+            .validate()
+            .map_err(|e| anyhow::anyhow!("http: {e}"))?;
+
+        Ok(())
+    }
+
+    /// Loads the config and hands it to `edit`, saving whatever comes back if it's any different.
+    /// The outer error is from reading or writing the file, the inner one is `edit`'s own.
+    pub fn edit<T, E>(
+        edit: impl FnOnce(Config) -> Result<(Config, T), E>,
+    ) -> anyhow::Result<Result<(Config, T), E>> {
+        let _lock = EDIT_LOCK.lock();
+        let current = Config::load()?;
+        let before = serde_json::to_value(&current)?;
+
+        let (config, res) = match edit(current) {
+            Ok(edited) => edited,
+            Err(e) => return Ok(Err(e)),
+        };
+        if serde_json::to_value(&config)? != before {
+            config.save()?;
+        }
+
+        Ok(Ok((config, res)))
+    }
+
+    /// Writes a temporary file and renames it over [`CONFIG_PATH`], so losing power halfway
+    /// through can't leave a truncated config behind.
+    fn save(&self) -> anyhow::Result<()> {
+        let tmp = format!("{CONFIG_PATH}.tmp");
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.sync_all()?;
+        drop(file);
+
+        std::fs::rename(&tmp, CONFIG_PATH)?;
+        Ok(())
+    }
+
+    /// The config as JSON, with non-empty secrets replaced by [`REDACTED`].
+    pub fn to_redacted(&self) -> serde_json::Result<Value> {
+        let mut value = serde_json::to_value(self)?;
+        for path in SECRETS {
+            if let Some(secret) = lookup_mut(&mut value, path) {
+                if *secret != "" {
+                    *secret = REDACTED.into();
+                }
+            }
+        }
+
+        Ok(value)
+    }
+
+    /// Applies a JSON merge patch (RFC 7396): objects are merged key by key, anything else
+    /// replaces what was there, and `null` drops a setting back to its default. Returns the
+    /// validated result and the dotted paths of the settings that changed.
+    pub fn patched(&self, patch: Value) -> anyhow::Result<(Config, Vec<String>)> {
+        let old = serde_json::to_value(self)?;
+        let mut new = old.clone();
+        merge(&mut new, &patch);
+
+        for path in SECRETS {
+            let old_secret = lookup(&old, path);
+            if let (Some(secret), Some(old_secret)) = (lookup_mut(&mut new, path), old_secret) {
+                if *secret == REDACTED {
+                    *secret = old_secret.clone();
+                }
+            }
+        }
+
+        let config: Config = serde_json::from_value(new)?;
+        config.validate()?;
+
+        // normalised, so defaults filled in by serde don't count as changes
+        let new = serde_json::to_value(&config)?;
+        let mut unknown = Vec::new();
+        find_unknown(&patch, &new, String::new(), &mut unknown);
+        if !unknown.is_empty() {
+            return Err(anyhow::anyhow!("unknown settings: {}", unknown.join(", ")));
+        }
+
+        let mut changed = Vec::new();
+        diff(&old, &new, String::new(), &mut changed);
+        Ok((config, changed))
+    }
+}
+
+/// Whether the setting at `path` (as reported by [`Config::patched`]) takes effect without a
+/// restart.
+pub fn applies_live(path: &str) -> bool {
+    let section = path.split('.').next().unwrap_or_default();
+    LIVE_SECTIONS.contains(&section)
+}
+
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, key| value.get(key))
+}
+
+fn lookup_mut<'a>(value: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    path.split('.')
+        .try_fold(value, |value, key| value.get_mut(key))
+}
+
+fn merge(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!()
+    };
+
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge(target.entry(key.as_str()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.into()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+/// Settings in `edit` that didn't make it into the parsed config, i.e. typos.
+fn find_unknown(edit: &Value, config: &Value, path: String, unknown: &mut Vec<String>) {
+    let Value::Object(edit) = edit else {
+        return;
+    };
+
+    for (key, value) in edit {
+        let path = join(&path, key);
+        match config.get(key) {
+            Some(config) => find_unknown(value, config, path, unknown),
+            None if !value.is_null() => unknown.push(path),
+            None => {}
+        }
+    }
+}
+
+fn diff(old: &Value, new: &Value, path: String, changed: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let added = new.keys().filter(|key| !old.contains_key(*key));
+            for key in old.keys().chain(added) {
+                diff(
+                    old.get(key).unwrap_or(&Value::Null),
+                    new.get(key).unwrap_or(&Value::Null),
+                    join(&path, key),
+                    changed,
+                );
+            }
+        }
+        (old, new) if old != new => changed.push(path),
+        _ => {}
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -71,10 +293,438 @@ impl WifiConfig {
     }
 }
 
+/// Fastest ramp, in steps per second. Even at 1 step that's the full range in a millisecond.
+pub const MAX_RAMP: u32 = 1000;
+
 #[derive(Serialize, Deserialize)]
 pub struct MotorConfig {
+    /// duty at full intensity, in percent
     pub max_power: i64,
+    /// duty at the lowest non-zero intensity, in percent
     pub min_power: i64,
+    /// number of steps the buttons move through
+    #[serde(default = "default_steps")]
+    pub steps: u32,
+    #[serde(default)]
+    pub curve: Curve,
+    /// acceleration limit in steps per second, up to [`MAX_RAMP`]. 0 disables the ramp
+    #[serde(default = "default_ramp_up")]
+    pub ramp_up: u32,
+    /// deceleration limit in steps per second, up to [`MAX_RAMP`]. 0 disables the ramp
+    #[serde(default = "default_ramp_down")]
+    pub ramp_down: u32,
+    /// whether stop commands cut the motor immediately instead of ramping down
+    #[serde(default = "default_hard_stop")]
+    pub hard_stop: bool,
+}
+
+impl MotorConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0..=100).contains(&self.min_power) || !(0..=100).contains(&self.max_power) {
+            return Err(anyhow::anyhow!(
+                "min_power and max_power must be between 0 and 100"
+            ));
+        }
+
+        if self.min_power >= self.max_power {
+            return Err(anyhow::anyhow!(
+                "min_power ({}) must be lower than max_power ({})",
+                self.min_power,
+                self.max_power
+            ));
+        }
+
+        if self.steps == 0 || self.steps > MAX_INTENSITY {
+            return Err(anyhow::anyhow!(
+                "steps must be between 1 and {MAX_INTENSITY}"
+            ));
+        }
+
+        if self.ramp_up > MAX_RAMP || self.ramp_down > MAX_RAMP {
+            return Err(anyhow::anyhow!(
+                "ramp_up and ramp_down must be between 0 and {MAX_RAMP}"
+            ));
+        }
+
+        self.curve.validate()
+    }
+
+    /// Checks the config and builds the intensity to duty mapping from it.
+    pub fn intensity_map(&self) -> anyhow::Result<IntensityMap> {
+        self.validate()?;
+
+        Ok(IntensityMap::new(
+            self.min_power as u32 * 10,
+            self.max_power as u32 * 10,
+            self.steps,
+            self.curve.clone(),
+        ))
+    }
+}
+
+impl Default for MotorConfig {
+    fn default() -> Self {
+        Self {
+            max_power: 100,
+            min_power: 50,
+            steps: default_steps(),
+            curve: Curve::Linear,
+            ramp_up: default_ramp_up(),
+            ramp_down: default_ramp_down(),
+            hard_stop: default_hard_stop(),
+        }
+    }
+}
+
+fn default_steps() -> u32 {
+    20
+}
+
+fn default_ramp_up() -> u32 {
+    20
+}
+
+fn default_ramp_down() -> u32 {
+    40
+}
+
+fn default_hard_stop() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ThermalConfig {
+    /// above this, motor intensity is capped linearly down to 0 at `hard_limit_c`
+    pub soft_limit_c: f32,
+    /// at or above this, the motor is shut down until it cools down by `hysteresis_c`
+    pub hard_limit_c: f32,
+    pub hysteresis_c: f32,
+    pub poll_interval_ms: u32,
+}
+
+impl ThermalConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.soft_limit_c.is_finite() || !self.hard_limit_c.is_finite() {
+            return Err(anyhow::anyhow!("temperature limits must be numbers"));
+        }
+
+        if self.soft_limit_c >= self.hard_limit_c {
+            return Err(anyhow::anyhow!(
+                "soft_limit_c ({}) must be lower than hard_limit_c ({})",
+                self.soft_limit_c,
+                self.hard_limit_c
+            ));
+        }
+
+        if self.hysteresis_c.is_nan() || self.hysteresis_c <= 0.0 {
+            return Err(anyhow::anyhow!("hysteresis_c must be positive"));
+        }
+
+        if self.poll_interval_ms == 0 {
+            return Err(anyhow::anyhow!("poll_interval_ms must be positive"));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        Self {
+            soft_limit_c: 60.0,
+            hard_limit_c: 75.0,
+            hysteresis_c: 10.0,
+            poll_interval_ms: 1000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SleepConfig {
+    /// turn the motor off after this many minutes without any input. 0 disables it
+    pub idle_timeout_min: u32,
+}
+
+impl SleepConfig {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_min > 0).then(|| Duration::from_secs(self.idle_timeout_min as u64 * 60))
+    }
+}
+
+impl Default for SleepConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_min: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ControlConfig {
+    /// highest intensity (0-1000) remote sources can ask for. the buttons can always go to max
+    pub remote_max_intensity: u32,
+}
+
+impl ControlConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.remote_max_intensity > MAX_INTENSITY {
+            return Err(anyhow::anyhow!(
+                "remote_max_intensity must be between 0 and {MAX_INTENSITY}, got {}",
+                self.remote_max_intensity
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            remote_max_intensity: MAX_INTENSITY,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct HttpConfig {
+    /// needed to change the config or the web panel over HTTP, as `Authorization: Bearer
+    /// <token>`. empty turns both off. read on every request, so changes apply straight away
+    #[serde(default)]
+    pub admin_token: String,
+}
+
+impl HttpConfig {
+    pub const MIN_TOKEN_LEN: usize = 8;
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let token = &self.admin_token;
+        if !token.is_empty() && token.len() < Self::MIN_TOKEN_LEN {
+            return Err(anyhow::anyhow!(
+                "admin_token must be empty or at least {} characters",
+                Self::MIN_TOKEN_LEN
+            ));
+        }
+
+        if token.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(anyhow::anyhow!("admin_token can't contain spaces"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct BleConfig {
+    /// advertised name. empty uses the profile's default
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub profile: BleProfile,
+    /// put a summary of the device state in the scan response - see [`crate::conn::beacon`]
+    #[serde(default)]
+    pub broadcast_state: bool,
+    #[serde(default)]
+    pub failsafe: FailsafeConfig,
+    #[serde(default)]
+    pub security: BleSecurityConfig,
+    #[serde(default)]
+    pub hid_remote: HidRemoteConfig,
+}
+
+/// A BLE media remote or presentation clicker we connect to as a central.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct HidRemoteConfig {
+    #[serde(default)]
+    pub enable: bool,
+    /// `aa:bb:cc:dd:ee:ff`. empty takes a bonded remote, or any remote while the pairing window
+    /// is open
+    #[serde(default)]
+    pub address: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct BleSecurityConfig {
+    #[serde(default)]
+    pub passkey: Passkey,
+    #[serde(default)]
+    pub io_cap: IoCapability,
+    /// drop connections from centrals we don't have a bond with, unless the pairing window is open.
+    /// on by default - apps that can't pair need it off
+    #[serde(default = "default_bonded_only")]
+    pub bonded_only: bool,
+    /// how long a long press on the stop button opens the pairing window for
+    #[serde(default = "default_pairing_window_s")]
+    pub pairing_window_s: u32,
+    /// what a central needs before it can use the NUS console
+    #[serde(default = "default_nus_security")]
+    pub nus: LinkSecurity,
+    /// same for the toy control service (Lovense/emulated). most apps can't pair, so keep it open
+    #[serde(default)]
+    pub control: LinkSecurity,
+}
+
+impl BleSecurityConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Passkey::Static { value } = self.passkey {
+            if value > 999_999 {
+                return Err(anyhow::anyhow!("passkey must have at most 6 digits, got {value}"));
+            }
+        }
+
+        if self.pairing_window_s == 0 {
+            return Err(anyhow::anyhow!("pairing_window_s must be positive"));
+        }
+
+        // without any way to show or enter a passkey, pairing can't be authenticated
+        let needs_mitm = [self.nus, self.control].contains(&LinkSecurity::Authenticated);
+        if needs_mitm && self.io_cap == IoCapability::NoInputNoOutput {
+            return Err(anyhow::anyhow!(
+                "authenticated links need an io_cap other than no_input_no_output"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for BleSecurityConfig {
+    fn default() -> Self {
+        Self {
+            passkey: Passkey::default(),
+            io_cap: IoCapability::default(),
+            bonded_only: default_bonded_only(),
+            pairing_window_s: default_pairing_window_s(),
+            nus: default_nus_security(),
+            control: LinkSecurity::Open,
+        }
+    }
+}
+
+fn default_bonded_only() -> bool {
+    true
+}
+
+fn default_pairing_window_s() -> u32 {
+    120
+}
+
+fn default_nus_security() -> LinkSecurity {
+    LinkSecurity::Encrypted
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Passkey {
+    Static { value: u32 },
+    /// a new one every boot, written to the log
+    Random,
+}
+
+impl Default for Passkey {
+    fn default() -> Self {
+        Passkey::Static { value: 123456 }
+    }
+}
+
+/// What the wand claims it can do to confirm a pairing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IoCapability {
+    /// the passkey is "displayed" in the log
+    DisplayOnly,
+    DisplayYesNo,
+    KeyboardOnly,
+    #[default]
+    NoInputNoOutput,
+    KeyboardDisplay,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkSecurity {
+    #[default]
+    Open,
+    /// the central has to pair first
+    Encrypted,
+    /// the central has to pair with a passkey or numeric comparison
+    Authenticated,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct FailsafeConfig {
+    /// what to do when the BLE connection that last sent a motor command goes away. WebSockets
+    /// always stop
+    #[serde(default)]
+    pub link_loss: LinkLossPolicy,
+    /// also treat that connection as lost after this many seconds without a write. 0 disables it
+    #[serde(default)]
+    pub heartbeat_timeout_s: u32,
+}
+
+impl Default for FailsafeConfig {
+    fn default() -> Self {
+        Self {
+            link_loss: LinkLossPolicy::default(),
+            heartbeat_timeout_s: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum LinkLossPolicy {
+    /// stop the motor (honours `motor.hard_stop`)
+    Stop,
+    /// fade out to 0 over `secs`
+    RampDown { secs: u32 },
+    /// leave the motor running
+    Keep,
+}
+
+impl Default for LinkLossPolicy {
+    fn default() -> Self {
+        LinkLossPolicy::RampDown { secs: 3 }
+    }
+}
+
+/// Which toy we pretend to be over BLE.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BleProfile {
+    Lovense {
+        /// model letter reported to `DeviceType;`
+        #[serde(default = "default_lovense_model")]
+        model: String,
+    },
+    #[serde(rename = "wevibe")]
+    WeVibe,
+    MagicMotion,
+    Kiiroo,
+}
+
+impl BleProfile {
+    /// The toy to emulate, or `None` for Lovense.
+    pub fn emulation(&self) -> Option<Emulation> {
+        match self {
+            BleProfile::Lovense { .. } => None,
+            BleProfile::WeVibe => Some(Emulation::WeVibe),
+            BleProfile::MagicMotion => Some(Emulation::MagicMotion),
+            BleProfile::Kiiroo => Some(Emulation::Kiiroo),
+        }
+    }
+}
+
+impl Default for BleProfile {
+    fn default() -> Self {
+        BleProfile::Lovense {
+            model: default_lovense_model(),
+        }
+    }
+}
+
+fn default_lovense_model() -> String {
+    LovenseDevice::DEFAULT_TYPE.into()
 }
 
 #[derive(Serialize, Deserialize)]
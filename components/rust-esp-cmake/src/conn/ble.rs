@@ -7,126 +7,330 @@ const NUS_SERVICE_ID: BleUuid = uuid128!("6E400001-B5A3-F393-E0A9-E50E24DCCA9E")
 const NUS_RX_CHAR: BleUuid = uuid128!("6E400002-B5A3-F393-E0A9-E50E24DCCA9E");
 const NUS_TX_CHAR: BleUuid = uuid128!("6E400003-B5A3-F393-E0A9-E50E24DCCA9E");
 
-use std::{sync::Arc, time::Duration};
+const DEVICE_INFO_SERVICE_ID: BleUuid = BleUuid::Uuid16(0x180A);
+const MODEL_NUMBER_CHAR: BleUuid = BleUuid::Uuid16(0x2A24);
+const SERIAL_NUMBER_CHAR: BleUuid = BleUuid::Uuid16(0x2A25);
+const FIRMWARE_REVISION_CHAR: BleUuid = BleUuid::Uuid16(0x2A26);
+const MANUFACTURER_NAME_CHAR: BleUuid = BleUuid::Uuid16(0x2A29);
+
+const ENV_SENSING_SERVICE_ID: BleUuid = BleUuid::Uuid16(0x181A);
+const TEMPERATURE_CHAR: BleUuid = BleUuid::Uuid16(0x2A6E);
+const USER_DESCRIPTION_DESC: BleUuid = BleUuid::Uuid16(0x2901);
+/// not a standard characteristic - there's no SIG-assigned one for "motor level"
+const MOTOR_LEVEL_CHAR: BleUuid = uuid128!("7a6e0101-3b1e-4a8c-9c2f-8d5e6f1a2b3c");
+
+const HID_SERVICE_ID: BleUuid = BleUuid::Uuid16(0x1812);
+const HID_REPORT_MAP_CHAR: BleUuid = BleUuid::Uuid16(0x2A4B);
+const HID_REPORT_CHAR: BleUuid = BleUuid::Uuid16(0x2A4D);
+const REPORT_REFERENCE_DESC: BleUuid = BleUuid::Uuid16(0x2908);
+/// report type in a report reference descriptor
+const REPORT_TYPE_INPUT: u8 = 1;
+
+const HID_SCAN_MS: i32 = 10_000;
+/// Time between looking for the HID remote, and between reconnects.
+const HID_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the state broadcast is refreshed, if it changed.
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// BLE OTA requests that can be waiting on a flash write before writes get dropped.
+const OTA_QUEUE_LEN: usize = 16;
+const OTA_BUSY: [u8; 9] = [OtaStatus::Busy as u8, 0, 0, 0, 0, 0, 0, 0, 0];
+
+const MANUFACTURER: &str = "esp-hitachi";
+const MODEL: &str = "hitachi-2";
+
+use std::{
+    ffi::CStr,
+    fmt::Display,
+    io::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use esp32_nimble::{
     enums::{AuthReq, SecurityIOCap},
     utilities::BleUuid,
-    uuid128, BLEAdvertisementData, BLEDevice, NimbleProperties,
+    uuid128, BLEAddress, BLEAdvertisementData, BLEClient, BLEConnDesc, BLEDevice, BLEServer,
+    DescriptorProperties, NimbleProperties,
+};
+use esp_idf_hal::task::block_on;
+use esp_idf_svc::ota::EspOta;
+use esp_idf_sys::{
+    ble_att_mtu, esp_app_get_description, esp_mac_type_t_ESP_MAC_BT, esp_random, esp_read_mac,
+    os_msys_num_free,
 };
+use parking_lot::Mutex;
 use thingbuf::{
     mpsc::blocking::{Receiver, Sender, StaticSender},
     recycling::WithCapacity,
 };
 
-use crate::event_queue::Event;
+use crate::{
+    arbiter::Source,
+    conf::{
+        BleConfig, BleProfile, BleSecurityConfig, HidRemoteConfig, IoCapability, LinkSecurity,
+        Passkey,
+    },
+    event_queue::Event,
+    failsafe::{supervisor::LinkWatchHandle, Link},
+    idf_libs::button::ButtonEvent,
+    pairing::supervisor::PairingHandle,
+    pattern::PatternCommand,
+    state::StateHandle,
+};
+
+use super::{
+    beacon,
+    ble_ota::{self, OtaCommand, OtaStatus},
+    emulation::Emulation,
+    hid::{RemoteAction, RemoteKeys, ReportMap},
+    nus,
+    ota::{self, OtaRequest},
+    session::{SessionMsg, Sessions},
+    wand,
+};
+
+pub use super::lovense::{
+    LovenseDecoder, LovenseDevice, LovenseError, LovenseMessage, LOVENSE_STEPS,
+};
 
 pub fn run_ble(
+    config: BleConfig,
+    state: StateHandle,
     sender: StaticSender<Event>,
-    uart_tx: Receiver<Vec<u8>, WithCapacity>,
-    uart_rx: Sender<String, WithCapacity>,
+    sessions: Sessions,
+    link: LinkWatchHandle,
+    pairing: PairingHandle,
+    ota: Arc<Mutex<EspOta>>,
+    uart_tx: Receiver<SessionMsg<Vec<u8>>, WithCapacity>,
+    uart_tx_send: Sender<SessionMsg<Vec<u8>>, WithCapacity>,
+    uart_rx: Sender<SessionMsg<String>, WithCapacity>,
 ) {
     let device = BLEDevice::take();
-
-    // device
-    device
-        .security()
-        .set_auth(AuthReq::Bond) // Bonding enables key storage for reconnection
-        .set_passkey(123456) // Optional, sets the passkey for pairing
-        .set_io_cap(SecurityIOCap::NoInputNoOutput) // You can choose any IO capability
-        .resolve_rpa(); // Crucial for managing iOS's dynamic Bluetooth addresses
+    let security = config.security;
+    configure_security(device, &security);
 
     let advertising = device.get_advertising();
 
     let server = device.get_server();
 
-    server.on_connect(|server, desc| {
+    if config.hid_remote.enable {
+        spawn_hid_remote(config.hid_remote, sender.clone(), pairing.clone());
+    }
+
+    let connect_sessions = sessions.clone();
+    server.on_connect(move |server, desc| {
+        if security.bonded_only && !pairing.is_open() && !is_bonded(desc) {
+            log::warn!("dropping unbonded central {}", desc.address());
+            if let Err(e) = server.disconnect(desc.conn_handle()) {
+                log::error!("failed to disconnect {}: {e:?}", desc.address());
+            }
+            return;
+        }
+
         log::info!("hewwo to {desc:?}");
+        log::info!(
+            "conn params: interval {} (x1.25ms), latency {}, timeout {} (x10ms)",
+            desc.interval(),
+            desc.latency(),
+            desc.timeout()
+        );
+        connect_sessions.open(desc.conn_handle());
+
         if server.connected_count() < (esp_idf_svc::sys::CONFIG_BT_NIMBLE_MAX_CONNECTIONS as _) {
             log::info!("Multi-connect support: start advertising");
             advertising.lock().start().unwrap();
         }
     });
 
-    server.on_disconnect(|desc, reason| {
+    let disconnect_sessions = sessions.clone();
+    let disconnect_link = link.clone();
+    server.on_disconnect(move |desc, reason| {
         log::info!("{desc:?} has left: {reason:?}");
+        disconnect_sessions.close(desc.conn_handle());
+        disconnect_link.disconnected(Link::Ble(desc.conn_handle()));
     });
 
     server.on_authentication_complete(|desc, result| {
         log::info!("auth completed: {desc:?}: {result:?}")
     });
 
-    let lovense_service = server.create_service(LOVENSE_SERVICE_ID);
+    let mut mac = [0u8; 6];
+    unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_BT) };
+    let lovense_model = match &config.profile {
+        BleProfile::Lovense { model } if !model.is_empty() => model.clone(),
+        _ => LovenseDevice::DEFAULT_TYPE.to_string(),
+    };
+    let lovense = Arc::new(Mutex::new(LovenseDevice::new(lovense_model, mac)));
 
-    let lovense_rx = lovense_service.lock().create_characteristic(
-        LOVENSE_RX_CHAR,
-        NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP,
-    );
+    let (default_name, service_id) = match config.profile.emulation() {
+        None => {
+            create_lovense_service(
+                server,
+                sender.clone(),
+                Arc::clone(&lovense),
+                sessions.clone(),
+                link.clone(),
+                security.control,
+            );
+            ("LOVE-Calor", LOVENSE_SERVICE_ID)
+        }
+        Some(emulation) => (
+            emulation.default_name(),
+            create_emulated_service(
+                server,
+                emulation,
+                sender.clone(),
+                link.clone(),
+                security.control,
+            ),
+        ),
+    };
 
-    let lovense_tx = lovense_service.lock().create_characteristic(
-        LOVENSE_TX_CHAR,
-        NimbleProperties::READ | NimbleProperties::NOTIFY,
+    create_device_info_service(server, mac);
+    create_telemetry_service(server, state.clone());
+    create_wand_service(
+        server,
+        sender.clone(),
+        state.clone(),
+        link.clone(),
+        security.control,
     );
-
-    let lovense_sender = sender.clone();
-
-    lovense_rx.lock().on_write(move |args| {
-        if let Some(msg) = std::str::from_utf8(args.recv_data())
-            .ok()
-            .and_then(LovenseMessage::parse)
-        {
-            lovense_sender.try_send(Event::Lovense(msg));
-        }
-        // let _ = lovense.req_tx.send(args.recv_data().to_vec());
-        // println!("from lovense: {}", std::str::from_utf8(args.recv_data()).unwrap());
-    });
+    // flashing firmware is at least as sensitive as the console
+    create_ota_service(server, ota, state.clone(), security.nus);
 
     let nus_service = server.create_service(NUS_SERVICE_ID);
+    // the console can change wifi settings and restart us, so it gets its own security level
     let nus_rx = nus_service.lock().create_characteristic(
         NUS_RX_CHAR,
-        NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP,
+        NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP | write_security(security.nus),
     );
 
     let nus_tx = nus_service.lock().create_characteristic(
         NUS_TX_CHAR,
-        NimbleProperties::READ | NimbleProperties::NOTIFY,
+        NimbleProperties::READ | NimbleProperties::NOTIFY | read_security(security.nus),
     );
 
     let nus_tx_handle = Arc::clone(&nus_tx);
+    let rx_sessions = sessions.clone();
+    let rx_link = link.clone();
+    let mut nus_lovense_reply = String::with_capacity(32);
 
     nus_rx.lock().on_write(move |args| {
-        let Some(msg) = std::str::from_utf8(args.recv_data()).ok() else {
-            return;
+        let data = args.recv_data();
+        let conn_handle = args.desc().conn_handle();
+        rx_link.seen(Link::Ble(conn_handle));
+        // replies only go back to the connection that asked
+        let reply = |msg: &[u8]| {
+            if let Err(e) = nus_tx_handle.lock().notify_with(msg, conn_handle) {
+                log::warn!("NUS notify to {conn_handle} failed: {e:?}");
+            }
         };
-        if let Some(msg) = LovenseMessage::parse(msg) {
-            sender.try_send(Event::Lovense(msg));
-        } else if let Ok(mut res_slot) = uart_rx.try_send_ref() {
-            res_slot.clear();
-            res_slot.push_str(msg);
-        }
-        // if let Ok(data) = std::str::from_utf8(args.recv_data()) {
-        //     log::info!("NUS RX: {data}");
-        //     nus_tx_handle.lock().set_value(data.as_bytes());
-        //     nus_tx_handle.lock().notify();
-        // }
+
+        rx_sessions.with(conn_handle, |session| {
+            // only sniff at a line boundary, so a console command ending in `;` still gets through
+            if session.input.is_empty() {
+                if let Some(msgs) = nus::sniff_lovense(data) {
+                    for msg in msgs {
+                        if msg.is_control() {
+                            rx_link.command(Link::Ble(conn_handle));
+                            let _ = sender.try_send(Event::Lovense(msg));
+                        }
+
+                        lovense.lock().respond(msg, &mut nus_lovense_reply);
+                        reply(nus_lovense_reply.as_bytes());
+                    }
+                    return;
+                }
+            }
+
+            session.input.feed(data, |line| match line {
+                Ok(line) => {
+                    if let Ok(mut res_slot) = uart_rx.try_send_ref() {
+                        res_slot.session = conn_handle;
+                        res_slot.body.clear();
+                        res_slot.body.push_str(line);
+                    } else {
+                        queue_reply(&uart_tx_send, conn_handle, "console busy, try again");
+                    }
+                }
+                Err(e) => queue_reply(&uart_tx_send, conn_handle, e),
+            });
+        });
     });
 
     advertising
         .lock()
         .set_data(
             BLEAdvertisementData::new()
-                .name("LOVE-Calor")
-                .add_service_uuid(LOVENSE_SERVICE_ID), // .add_service_uuid(ESPWAND_SERVICE_ID)
-                                                       // .add_service_uuid(LOVENSE_SERVICE_ID),
+                .name(if config.name.is_empty() {
+                    default_name
+                } else {
+                    config.name.as_str()
+                })
+                .add_service_uuid(service_id),
         )
         .unwrap();
 
     advertising.lock().start().unwrap();
 
+    if config.broadcast_state {
+        let spawned = std::thread::Builder::new()
+            .name("ble-beacon".into())
+            .stack_size(4096)
+            .spawn(move || {
+                let mut last = None;
+                loop {
+                    let data = beacon::encode(&state.snapshot());
+                    if last != Some(data) {
+                        let res = advertising.lock().scan_response_data(
+                            BLEAdvertisementData::new().manufacturer_data(&data),
+                        );
+                        match res {
+                            Ok(()) => last = Some(data),
+                            Err(e) => log::warn!("failed to update the scan response: {e:?}"),
+                        }
+                    }
+
+                    std::thread::sleep(BEACON_INTERVAL);
+                }
+            });
+
+        if let Err(e) = spawned {
+            log::error!("failed to start the state broadcast: {e}");
+        }
+    }
+
     while let Some(res_slot) = uart_tx.recv_ref() {
-        let mut handle = nus_tx.lock();
-        handle.set_value(&res_slot);
-        handle.notify();
+        let conn_handle = res_slot.session;
+        let Some(mtu) = sessions.with(conn_handle, |session| {
+            let mtu = unsafe { ble_att_mtu(conn_handle) };
+            if mtu != session.mtu {
+                log::info!("MTU for connection {conn_handle} is now {mtu}");
+                session.mtu = mtu;
+            }
+            mtu
+        }) else {
+            // the connection went away while the command ran
+            continue;
+        };
+
+        let response = &res_slot.body;
+        let notify = |chunk: &[u8]| {
+            wait_for_notify_buffers();
+            if let Err(e) = nus_tx.lock().notify_with(chunk, conn_handle) {
+                log::warn!("NUS notify to {conn_handle} failed: {e:?}");
+            }
+        };
+
+        for chunk in nus::LineChunks::new(response, nus::payload_len(mtu)) {
+            notify(chunk);
+        }
+
+        if !response.ends_with(b"\n") {
+            notify(b"\n");
+        }
     }
 
     loop {
@@ -134,26 +338,618 @@ pub fn run_ble(
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum LovenseMessage {
-    Vibrate(u8),
-    Unrecognized,
+fn configure_security(device: &mut BLEDevice, config: &BleSecurityConfig) {
+    let passkey = match config.passkey {
+        Passkey::Static { value } => value,
+        Passkey::Random => {
+            let passkey = unsafe { esp_random() } % 1_000_000;
+            log::warn!("BLE pairing passkey for this boot: {passkey:06}");
+            passkey
+        }
+    };
+
+    let io_cap = match config.io_cap {
+        IoCapability::DisplayOnly => SecurityIOCap::DisplayOnly,
+        IoCapability::DisplayYesNo => SecurityIOCap::DisplayYesNo,
+        IoCapability::KeyboardOnly => SecurityIOCap::KeyboardOnly,
+        IoCapability::NoInputNoOutput => SecurityIOCap::NoInputNoOutput,
+        IoCapability::KeyboardDisplay => SecurityIOCap::KeyboardDisplay,
+    };
+
+    // bonding stores keys for reconnection. MITM protection needs some way to confirm a pairing
+    let mut auth = AuthReq::Bond | AuthReq::Sc;
+    if config.io_cap != IoCapability::NoInputNoOutput {
+        auth |= AuthReq::Mitm;
+    }
+
+    device
+        .security()
+        .set_auth(auth)
+        .set_passkey(passkey)
+        .set_io_cap(io_cap)
+        .resolve_rpa(); // Crucial for managing iOS's dynamic Bluetooth addresses
+}
+
+fn is_bonded(desc: &BLEConnDesc) -> bool {
+    is_bonded_address(&desc.id_address())
+}
+
+fn is_bonded_address(address: &BLEAddress) -> bool {
+    match BLEDevice::take().bonded_addresses() {
+        Ok(bonds) => bonds.contains(address),
+        Err(e) => {
+            log::error!("failed to read bonds: {e:?}");
+            false
+        }
+    }
+}
+
+fn write_security(security: LinkSecurity) -> NimbleProperties {
+    match security {
+        LinkSecurity::Open => NimbleProperties::empty(),
+        LinkSecurity::Encrypted => NimbleProperties::WRITE_ENC,
+        LinkSecurity::Authenticated => NimbleProperties::WRITE_ENC | NimbleProperties::WRITE_AUTHEN,
+    }
+}
+
+fn read_security(security: LinkSecurity) -> NimbleProperties {
+    match security {
+        LinkSecurity::Open => NimbleProperties::empty(),
+        LinkSecurity::Encrypted => NimbleProperties::READ_ENC,
+        LinkSecurity::Authenticated => NimbleProperties::READ_ENC | NimbleProperties::READ_AUTHEN,
+    }
+}
+
+fn create_lovense_service(
+    server: &mut BLEServer,
+    sender: StaticSender<Event>,
+    lovense: Arc<Mutex<LovenseDevice>>,
+    sessions: Sessions,
+    link: LinkWatchHandle,
+    security: LinkSecurity,
+) {
+    let lovense_service = server.create_service(LOVENSE_SERVICE_ID);
+
+    let lovense_rx = lovense_service.lock().create_characteristic(
+        LOVENSE_RX_CHAR,
+        NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP | write_security(security),
+    );
+
+    let lovense_tx = lovense_service.lock().create_characteristic(
+        LOVENSE_TX_CHAR,
+        NimbleProperties::READ | NimbleProperties::NOTIFY | read_security(security),
+    );
+
+    let mut lovense_reply = String::with_capacity(32);
+
+    lovense_rx.lock().on_write(move |args| {
+        let conn_handle = args.desc().conn_handle();
+        link.seen(Link::Ble(conn_handle));
+        sessions.with(conn_handle, |session| {
+            session.lovense.feed(args.recv_data());
+
+            while let Some(res) = session.lovense.next_message() {
+                match res {
+                    Ok(msg) => {
+                        if msg.is_control() {
+                            link.command(Link::Ble(conn_handle));
+                            let _ = sender.try_send(Event::Lovense(msg));
+                        }
+
+                        lovense.lock().respond(msg, &mut lovense_reply);
+                    }
+                    // stray `;`s - nothing to answer
+                    Err(LovenseError::Empty) => continue,
+                    Err(e) => {
+                        log::warn!("bad lovense command: {e}");
+                        lovense.lock().respond_error(e, &mut lovense_reply);
+                    }
+                }
+
+                if let Err(e) = lovense_tx
+                    .lock()
+                    .notify_with(lovense_reply.as_bytes(), conn_handle)
+                {
+                    log::warn!("lovense notify to {conn_handle} failed: {e:?}");
+                }
+            }
+        });
+        // let _ = lovense.req_tx.send(args.recv_data().to_vec());
+        // println!("from lovense: {}", std::str::from_utf8(args.recv_data()).unwrap());
+    });
 }
 
-impl LovenseMessage {
-    pub fn parse(args: &str) -> Option<LovenseMessage> {
-        let Some(msg_end) = args.find(';') else {
-            return None;
+/// Sets up the GATT layout of another vendor's toy. Returns the service UUID to advertise.
+fn create_emulated_service(
+    server: &mut BLEServer,
+    emulation: Emulation,
+    sender: StaticSender<Event>,
+    link: LinkWatchHandle,
+    security: LinkSecurity,
+) -> BleUuid {
+    let layout = emulation.layout();
+    let service_id = BleUuid::from_uuid128_string(layout.service).unwrap();
+    let service = server.create_service(service_id);
+
+    let rx = service.lock().create_characteristic(
+        BleUuid::from_uuid128_string(layout.rx).unwrap(),
+        NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP | write_security(security),
+    );
+
+    if let Some(tx) = layout.tx {
+        service.lock().create_characteristic(
+            BleUuid::from_uuid128_string(tx).unwrap(),
+            NimbleProperties::READ | NimbleProperties::NOTIFY | read_security(security),
+        );
+    }
+
+    rx.lock().on_write(move |args| {
+        let conn_handle = args.desc().conn_handle();
+        link.seen(Link::Ble(conn_handle));
+        if let Some(level) = emulation.decode(args.recv_data()) {
+            link.command(Link::Ble(conn_handle));
+            let _ = sender.try_send(Event::SetIntensity(Source::Ble, level));
+        }
+    });
+
+    log::info!("emulating {emulation:?}");
+
+    service_id
+}
+
+const WAND_NOTIFY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Our own typed control service - see [`wand`] for the schema.
+fn create_wand_service(
+    server: &mut BLEServer,
+    sender: StaticSender<Event>,
+    state: StateHandle,
+    link: LinkWatchHandle,
+    security: LinkSecurity,
+) {
+    let uuid = |s: &str| BleUuid::from_uuid128_string(s).unwrap();
+    let service = server.create_service(uuid(wand::SERVICE));
+
+    let schema = service
+        .lock()
+        .create_characteristic(uuid(wand::SCHEMA_CHAR), NimbleProperties::READ);
+    schema
+        .lock()
+        .set_value(&wand::SCHEMA_VERSION.to_le_bytes());
+
+    let firmware = service
+        .lock()
+        .create_characteristic(uuid(wand::FIRMWARE_CHAR), NimbleProperties::READ);
+    if let Some(version) = firmware_version() {
+        firmware.lock().set_value(version.as_bytes());
+    }
+
+    let intensity = service.lock().create_characteristic(
+        uuid(wand::INTENSITY_CHAR),
+        NimbleProperties::READ | NimbleProperties::WRITE | write_security(security),
+    );
+    let intensity_sender = sender.clone();
+    let intensity_link = link.clone();
+    intensity.lock().on_write(move |args| match wand::decode_intensity(args.recv_data()) {
+        Ok(level) => {
+            intensity_link.command(Link::Ble(args.desc().conn_handle()));
+            let _ = intensity_sender.try_send(Event::SetIntensity(Source::Ble, level));
+        }
+        Err(e) => {
+            log::warn!("bad intensity write: {e}");
+            args.reject();
+        }
+    });
+
+    let pattern = service.lock().create_characteristic(
+        uuid(wand::PATTERN_CHAR),
+        NimbleProperties::WRITE | write_security(security),
+    );
+    let pattern_sender = sender.clone();
+    let pattern_link = link.clone();
+    pattern.lock().on_write(move |args| match wand::decode_pattern(args.recv_data()) {
+        Ok(cmd) => {
+            pattern_link.command(Link::Ble(args.desc().conn_handle()));
+            let _ = pattern_sender.try_send(Event::Pattern(Source::Ble, cmd));
+        }
+        Err(e) => {
+            log::warn!("bad pattern write: {e}");
+            args.reject();
+        }
+    });
+
+    // stopping is always allowed
+    let stop = service
+        .lock()
+        .create_characteristic(uuid(wand::STOP_CHAR), NimbleProperties::WRITE);
+    stop.lock().on_write(move |args| match wand::decode_stop(args.recv_data()) {
+        Ok(true) => {
+            link.seen(Link::Ble(args.desc().conn_handle()));
+            let _ = sender.try_send(Event::Pattern(Source::Ble, PatternCommand::Stop));
+        }
+        Ok(false) => {}
+        Err(e) => {
+            log::warn!("bad stop write: {e}");
+            args.reject();
+        }
+    });
+
+    let notify = NimbleProperties::READ | NimbleProperties::NOTIFY;
+    let actual = service
+        .lock()
+        .create_characteristic(uuid(wand::ACTUAL_CHAR), notify);
+    let temperature = service
+        .lock()
+        .create_characteristic(uuid(wand::TEMPERATURE_CHAR), notify);
+    let state_char = service
+        .lock()
+        .create_characteristic(uuid(wand::STATE_CHAR), notify);
+
+    let spawned = std::thread::Builder::new()
+        .name("wand-notify".into())
+        .stack_size(4096)
+        .spawn(move || {
+            let mut last = None;
+            loop {
+                std::thread::sleep(WAND_NOTIFY_INTERVAL);
+
+                let snapshot = state.snapshot();
+                let prev = last.replace(snapshot);
+                if prev == Some(snapshot) {
+                    continue;
+                }
+
+                intensity
+                    .lock()
+                    .set_value(&wand::encode_intensity(snapshot.motor.target));
+
+                if prev.is_none_or(|p| p.motor.actual != snapshot.motor.actual) {
+                    actual
+                        .lock()
+                        .set_value(&wand::encode_intensity(snapshot.motor.actual))
+                        .notify();
+                }
+
+                if prev.is_none_or(|p| p.device.motor_temp_c != snapshot.device.motor_temp_c) {
+                    temperature
+                        .lock()
+                        .set_value(&wand::encode_temperature(snapshot.device.motor_temp_c))
+                        .notify();
+                }
+
+                let encoded = wand::encode_state(&snapshot);
+                if prev.is_none_or(|p| wand::encode_state(&p) != encoded) {
+                    state_char.lock().set_value(&encoded).notify();
+                }
+            }
+        });
+
+    if let Err(e) = spawned {
+        log::error!("failed to start wand notifications: {e}");
+    }
+}
+
+/// Connects to a HID remote as a central and turns its keys into button presses, reconnecting
+/// whenever it goes away. See [`super::hid`] for the parsing.
+fn spawn_hid_remote(config: HidRemoteConfig, sender: StaticSender<Event>, pairing: PairingHandle) {
+    let spawned = std::thread::Builder::new()
+        .name("hid-remote".into())
+        .stack_size(8192)
+        .spawn(move || loop {
+            if let Err(e) = block_on(run_hid_remote(&config, &sender, &pairing)) {
+                log::warn!("HID remote: {e}");
+            }
+
+            std::thread::sleep(HID_RETRY_INTERVAL);
+        });
+
+    if let Err(e) = spawned {
+        log::error!("failed to start the HID remote: {e}");
+    }
+}
+
+/// One scan, and if the remote turns up, one connection until it drops.
+async fn run_hid_remote(
+    config: &HidRemoteConfig,
+    sender: &StaticSender<Event>,
+    pairing: &PairingHandle,
+) -> anyhow::Result<()> {
+    let device = BLEDevice::take();
+    let scan = device.get_scan();
+    scan.active_scan(true).interval(100).window(50);
+
+    let found = scan
+        .start(device, HID_SCAN_MS, |found, data| {
+            let wanted = if config.address.is_empty() {
+                // don't grab the neighbour's keyboard - only ones we've paired with on purpose
+                data.is_advertising_service(&HID_SERVICE_ID)
+                    && (pairing.is_open() || is_bonded_address(found.addr()))
+            } else {
+                found.addr().to_string().eq_ignore_ascii_case(&config.address)
+            };
+
+            wanted.then(|| *found.addr())
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("scan failed: {e:?}"))?;
+
+    let Some(addr) = found else {
+        return Ok(());
+    };
+
+    let nimble = |e| anyhow::anyhow!("{addr}: {e:?}");
+    let mut client = BLEClient::new();
+    client.connect(&addr).await.map_err(nimble)?;
+    // remotes expect to bond, and most won't send reports over an unencrypted link
+    client.secure_connection().await.map_err(nimble)?;
+
+    let service = client.get_service(HID_SERVICE_ID).await.map_err(nimble)?;
+    let report_map = service
+        .get_characteristic(HID_REPORT_MAP_CHAR)
+        .await
+        .map_err(nimble)?
+        .read_value()
+        .await
+        .map_err(nimble)?;
+    let report_map = Arc::new(ReportMap::parse(&report_map)?);
+
+    let mut subscribed = 0;
+    for report in service.get_characteristics().await.map_err(nimble)? {
+        if report.uuid() != HID_REPORT_CHAR || !report.can_notify() {
+            continue;
+        }
+
+        let reference = report
+            .get_descriptor(REPORT_REFERENCE_DESC)
+            .await
+            .map_err(nimble)?
+            .read_value()
+            .await
+            .map_err(nimble)?;
+        let [report_id, REPORT_TYPE_INPUT] = reference[..] else {
+            continue;
         };
+        if !report_map.has_report(report_id) {
+            continue;
+        }
+
+        let report_map = Arc::clone(&report_map);
+        let sender = sender.clone();
+        let mut keys = RemoteKeys::default();
+        report
+            .on_notify(move |data| {
+                for action in keys.update(report_id, report_map.pressed(report_id, data)) {
+                    let _ = sender.try_send(remote_button(action));
+                }
+            })
+            .subscribe_notify(false)
+            .await
+            .map_err(nimble)?;
+        subscribed += 1;
+    }
+
+    if subscribed == 0 {
+        let _ = client.disconnect();
+        return Err(anyhow::anyhow!("{addr} has no input reports we understand"));
+    }
+
+    log::info!("HID remote {addr} connected, listening to {subscribed} reports");
+    while client.connected() {
+        std::thread::sleep(HID_RETRY_INTERVAL);
+    }
+    log::info!("HID remote {addr} has left");
+
+    Ok(())
+}
 
-        let args: Vec<&str> = args[..msg_end].split_terminator(':').collect();
+/// The physical button press a remote's key stands in for.
+fn remote_button(action: RemoteAction) -> Event {
+    match action {
+        RemoteAction::Increase => Event::Button(ButtonEvent::SingleClick, 6),
+        RemoteAction::Decrease => Event::Button(ButtonEvent::SingleClick, 7),
+        RemoteAction::Stop => Event::Button(ButtonEvent::SingleClick, 8),
+        RemoteAction::NextPattern => Event::Button(ButtonEvent::DoubleClick, 6),
+        RemoteAction::PreviousPattern => Event::Button(ButtonEvent::DoubleClick, 7),
+    }
+}
+
+/// Queues an error for the session's console output, which the UART task chunks and sends.
+fn queue_reply(
+    uart_tx: &Sender<SessionMsg<Vec<u8>>, WithCapacity>,
+    conn_handle: u16,
+    error: impl Display,
+) {
+    let Ok(mut slot) = uart_tx.try_send_ref() else {
+        log::warn!("console output full, dropping an error for {conn_handle}: {error}");
+        return;
+    };
+
+    slot.session = conn_handle;
+    slot.body.clear();
+    let _ = writeln!(slot.body, "Error!: {error}");
+}
+
+const MIN_FREE_MBUFS: i32 = 4;
+const NOTIFY_BACKPRESSURE_TIMEOUT: Duration = Duration::from_millis(500);
 
-        match args[0] {
-            "Vibrate" => args
-                .get(1)
-                .and_then(|v| v.parse::<u8>().ok())
-                .map(LovenseMessage::Vibrate),
-            _ => Some(LovenseMessage::Unrecognized),
+/// NimBLE drops notifications when it runs out of mbufs, so give it a chance to drain the
+/// ones it has queued before sending more.
+fn wait_for_notify_buffers() {
+    let deadline = Instant::now() + NOTIFY_BACKPRESSURE_TIMEOUT;
+    while unsafe { os_msys_num_free() } < MIN_FREE_MBUFS {
+        if Instant::now() >= deadline {
+            log::warn!("notify queue still full, sending anyway");
+            return;
         }
+
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+fn firmware_version() -> Option<String> {
+    let desc = unsafe { esp_app_get_description().as_ref() }?;
+    CStr::from_bytes_until_nul(&desc.version)
+        .ok()
+        .map(|v| v.to_string_lossy().into_owned())
+}
+
+/// Standard Device Information Service, so OS settings panels and generic tools can identify us.
+fn create_device_info_service(server: &mut BLEServer, mac: [u8; 6]) {
+    let service = server.create_service(DEVICE_INFO_SERVICE_ID);
+
+    let serial: String = mac.iter().map(|b| format!("{b:02X}")).collect();
+    let firmware = firmware_version().unwrap_or_default();
+
+    for (uuid, value) in [
+        (MANUFACTURER_NAME_CHAR, MANUFACTURER),
+        (MODEL_NUMBER_CHAR, MODEL),
+        (FIRMWARE_REVISION_CHAR, firmware.as_str()),
+        (SERIAL_NUMBER_CHAR, serial.as_str()),
+    ] {
+        service
+            .lock()
+            .create_characteristic(uuid, NimbleProperties::READ)
+            .lock()
+            .set_value(value.as_bytes());
+    }
+}
+
+/// Chunked, resumable firmware updates - see [`ble_ota`] for the protocol.
+fn create_ota_service(
+    server: &mut BLEServer,
+    ota: Arc<Mutex<EspOta>>,
+    state: StateHandle,
+    security: LinkSecurity,
+) {
+    let uuid = |s: &str| BleUuid::from_uuid128_string(s).unwrap();
+    let service = server.create_service(uuid(ble_ota::SERVICE));
+
+    let control = service.lock().create_characteristic(
+        uuid(ble_ota::CONTROL_CHAR),
+        NimbleProperties::WRITE
+            | NimbleProperties::NOTIFY
+            | write_security(security)
+            | read_security(security),
+    );
+    let data = service.lock().create_characteristic(
+        uuid(ble_ota::DATA_CHAR),
+        NimbleProperties::WRITE_NO_RSP | write_security(security),
+    );
+
+    let (requests_tx, requests_rx) = std::sync::mpsc::sync_channel(OTA_QUEUE_LEN);
+
+    let control_requests = requests_tx.clone();
+    let control_handle = Arc::clone(&control);
+    control
+        .lock()
+        .on_write(move |args| match ble_ota::decode_command(args.recv_data()) {
+            Ok(cmd) => {
+                let request = match cmd {
+                    OtaCommand::Begin { size } => OtaRequest::Begin { size },
+                    OtaCommand::Status => OtaRequest::Status,
+                    OtaCommand::Finish => OtaRequest::Finish,
+                    OtaCommand::Abort => OtaRequest::Abort,
+                };
+                if control_requests.try_send(request).is_err() {
+                    control_handle.lock().set_value(&OTA_BUSY).notify();
+                }
+            }
+            Err(e) => {
+                log::warn!("bad OTA command: {e}");
+                args.reject();
+            }
+        });
+
+    let data_control = Arc::clone(&control);
+    data.lock()
+        .on_write(move |args| match ble_ota::decode_chunk(args.recv_data()) {
+            Ok((offset, chunk)) => {
+                let request = OtaRequest::Chunk {
+                    offset,
+                    data: chunk.to_vec(),
+                };
+                // the worker notices the gap and tells the client where to resume
+                if requests_tx.try_send(request).is_err() {
+                    data_control.lock().set_value(&OTA_BUSY).notify();
+                }
+            }
+            Err(e) => log::warn!("bad OTA chunk: {e}"),
+        });
+
+    let spawned = std::thread::Builder::new()
+        .name("ble-ota".into())
+        .stack_size(8192)
+        .spawn(move || {
+            ota::run_ble_worker(
+                ota,
+                requests_rx,
+                || {
+                    let motor = state.snapshot().motor;
+                    motor.target > 0 || motor.actual > 0
+                },
+                |status, offset, size| {
+                    control
+                        .lock()
+                        .set_value(&ble_ota::encode_progress(status, offset, size))
+                        .notify();
+                },
+            )
+        });
+
+    if let Err(e) = spawned {
+        log::error!("failed to start BLE OTA: {e}");
+    }
+}
+
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Environmental Sensing temperatures (motor NTC and chip), plus the motor level, notified
+/// every [`TELEMETRY_INTERVAL`].
+fn create_telemetry_service(server: &mut BLEServer, state: StateHandle) {
+    let service = server.create_service(ENV_SENSING_SERVICE_ID);
+
+    let characteristic = |uuid: BleUuid, description: &str| {
+        let chr = service
+            .lock()
+            .create_characteristic(uuid, NimbleProperties::READ | NimbleProperties::NOTIFY);
+        chr.lock()
+            .create_descriptor(USER_DESCRIPTION_DESC, DescriptorProperties::READ)
+            .lock()
+            .set_value(description.as_bytes());
+        chr
+    };
+
+    let motor_temp = characteristic(TEMPERATURE_CHAR, "Motor");
+    let chip_temp = characteristic(TEMPERATURE_CHAR, "Chip");
+    let motor_level = characteristic(MOTOR_LEVEL_CHAR, "Motor level");
+
+    let spawned = std::thread::Builder::new()
+        .name("ble-telemetry".into())
+        .stack_size(4096)
+        .spawn(move || loop {
+            std::thread::sleep(TELEMETRY_INTERVAL);
+
+            let snapshot = state.snapshot();
+            // ESS temperature is the same sint16 in 0.01°C as ours, but has no "unknown" value
+            if let Some(temp) = snapshot.device.motor_temp_c {
+                motor_temp
+                    .lock()
+                    .set_value(&wand::encode_temperature(Some(temp)))
+                    .notify();
+            }
+            if let Some(temp) = snapshot.device.chip_temp_c {
+                chip_temp
+                    .lock()
+                    .set_value(&wand::encode_temperature(Some(temp)))
+                    .notify();
+            }
+            motor_level
+                .lock()
+                .set_value(&wand::encode_intensity(snapshot.motor.actual))
+                .notify();
+        });
+
+    if let Err(e) = spawned {
+        log::error!("failed to start BLE telemetry: {e}");
     }
 }
@@ -1,22 +1,68 @@
-use std::sync::Arc;
+use std::{
+    fs::File,
+    io::{Read as _, Seek as _, Write as _},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use embedded_svc::http::Headers;
+use embedded_svc::{http::Headers, ws::FrameType};
 use esp_idf_hal::io::Write;
 use esp_idf_svc::{
     http::{
-        server::{EspHttpConnection, EspHttpServer, Handler, Request},
+        server::{
+            ws::{EspHttpWsConnection, EspHttpWsDetachedSender},
+            EspHttpConnection, EspHttpServer, Handler, Request,
+        },
         Method,
     },
-    ota::{EspFirmwareInfoLoader, EspOta, FirmwareInfo},
+    ota::EspOta,
 };
 use log::Level;
+use ota_sign::Verifier;
 use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::Value;
+use thingbuf::mpsc::blocking::StaticSender;
+
+use crate::{
+    arbiter::Source,
+    conf::{self, Config},
+    event_queue::Event,
+    failsafe::{supervisor::LinkWatchHandle, Link},
+    motor::Motor,
+    pattern::PatternCommand,
+    sleep::supervisor::SleepHandle,
+    state::StateHandle,
+};
+
+use super::{
+    api::{
+        self, Accepted, ApiError, AssetSaved, ConfigSaved, IntensityRequest, PatternRequest,
+        StateResponse, MAX_BODY_LEN, MAX_CONFIG_LEN,
+    },
+    ota::{check_signature, check_size, get_firmware_info},
+    ws::{self, Keepalive, WsCommand, WsError},
+    www::{self, ETag},
+};
 
-use crate::EspResult;
+const ASSET_CHUNK_LEN: usize = 2048;
 
-pub fn run_http(port: u16, ota: Arc<Mutex<EspOta>>) -> anyhow::Result<EspHttpServer<'static>> {
+/// How often the state is checked for changes to push to WebSockets.
+const WS_PUSH_INTERVAL: Duration = Duration::from_millis(50);
+const WS_KEEPALIVE_INTERVAL: Duration = Duration::from_millis(ws::KEEPALIVE_INTERVAL_MS);
+
+pub fn run_http(
+    port: u16,
+    ota: Arc<Mutex<EspOta>>,
+    state: StateHandle,
+    events: StaticSender<Event>,
+    link: LinkWatchHandle,
+    live: LiveConfig,
+) -> anyhow::Result<EspHttpServer<'static>> {
     let config = esp_idf_svc::http::server::Configuration {
         http_port: port,
+        // for the JSON 404 under /api, and the web panel
+        uri_match_wildcard: true,
         ..Default::default()
     };
 
@@ -29,12 +75,492 @@ pub fn run_http(port: u16, ota: Arc<Mutex<EspOta>>) -> anyhow::Result<EspHttpSer
 
     server.handler("/ota/upload", Method::Post, FirmwareUpdateHandler { ota })?;
 
+    register_ws(&mut server, state.clone(), events.clone(), link)?;
+    register_api(&mut server, state, events)?;
+    register_config(&mut server, live)?;
+    register_www(&mut server)?;
+
     Ok(server)
 }
 
+/// The JSON control API - see [`super::api`] for the schema.
+fn register_api(
+    server: &mut EspHttpServer<'static>,
+    state: StateHandle,
+    events: StaticSender<Event>,
+) -> anyhow::Result<()> {
+    server.fn_handler::<anyhow::Error, _>("/api/state", Method::Get, move |req| {
+        respond_json(req, 200, &StateResponse::from(&state.snapshot()))
+    })?;
+
+    let intensity_events = events.clone();
+    server.fn_handler::<anyhow::Error, _>("/api/intensity", Method::Put, move |mut req| {
+        let res = read_body(&mut req, MAX_BODY_LEN)
+            .and_then(|body| IntensityRequest::decode(&body))
+            .and_then(|level| {
+                send_command(&intensity_events, Event::SetIntensity(Source::Http, level))
+            });
+        respond_command(req, res)
+    })?;
+
+    let pattern_events = events.clone();
+    server.fn_handler::<anyhow::Error, _>("/api/pattern", Method::Post, move |mut req| {
+        let res = read_body(&mut req, MAX_BODY_LEN)
+            .and_then(|body| PatternRequest::decode(&body))
+            .and_then(|cmd| send_command(&pattern_events, Event::Pattern(Source::Http, cmd)));
+        respond_command(req, res)
+    })?;
+
+    server.fn_handler::<anyhow::Error, _>("/api/stop", Method::Post, move |req| {
+        let res = send_command(&events, Event::Pattern(Source::Http, PatternCommand::Stop));
+        respond_command(req, res)
+    })?;
+
+    // registered last, so it only catches what nothing else did
+    for method in [Method::Get, Method::Put, Method::Post] {
+        server.fn_handler::<anyhow::Error, _>("/api/*", method, |req| {
+            let error = ApiError::not_found(req.uri());
+            respond_json(req, error.status, &error)
+        })?;
+    }
+
+    Ok(())
+}
+
+/// What a config change can reach without a restart - see [`conf::LIVE_SECTIONS`].
+#[derive(Clone)]
+pub struct LiveConfig {
+    pub motor: Arc<Mutex<Motor>>,
+    pub sleep: SleepHandle,
+    pub events: StaticSender<Event>,
+}
+
+impl LiveConfig {
+    fn apply(&self, config: &Config, changed: &[String]) {
+        let touched = |section: &str| changed.iter().any(|p| p.split('.').next() == Some(section));
+
+        if touched("motor") {
+            if let Err(e) = self.motor.lock().reconfigure(&config.motor) {
+                log::error!("failed to apply the motor config: {e}");
+            }
+        }
+        if touched("sleep") {
+            self.sleep.set_idle_timeout(config.sleep.idle_timeout());
+        }
+        if touched("control") {
+            // blocking, the config is already saved so this mustn't get lost
+            let _ = self
+                .events
+                .send(Event::RemoteCap(config.control.remote_max_intensity));
+        }
+    }
+}
+
+/// Reading and editing the config file.
+fn register_config(server: &mut EspHttpServer<'static>, live: LiveConfig) -> anyhow::Result<()> {
+    server.fn_handler::<anyhow::Error, _>("/config", Method::Get, |req| {
+        let res = Config::load()
+            .and_then(|config| config.to_redacted().map_err(Into::into))
+            .map_err(|e| ApiError::internal(format!("failed to read the config: {e}")));
+        respond(req, 200, res)
+    })?;
+
+    server.fn_handler::<anyhow::Error, _>("/config", Method::Patch, move |mut req| {
+        let res = check_admin(&req)
+            .and_then(|()| read_body(&mut req, MAX_CONFIG_LEN))
+            .and_then(|body| patch_config(&live, &body));
+        respond(req, 200, res)
+    })?;
+
+    Ok(())
+}
+
+/// Writes to the config and the web panel need `http.admin_token`, read fresh so a new one
+/// applies straight away.
+fn check_admin(req: &Request<&mut EspHttpConnection>) -> Result<(), ApiError> {
+    let config = Config::load()
+        .map_err(|e| ApiError::internal(format!("failed to read the config: {e}")))?;
+    api::authorize(req.header("Authorization"), &config.http.admin_token)
+}
+
+fn patch_config(live: &LiveConfig, body: &[u8]) -> Result<ConfigSaved, ApiError> {
+    let patch: Value = serde_json::from_slice(body)?;
+    let (config, changed) = Config::edit(|current| current.patched(patch))
+        .map_err(|e| ApiError::internal(format!("failed to update the config: {e}")))?
+        .map_err(|e| ApiError::invalid(e.to_string()))?;
+    if changed.is_empty() {
+        return Ok(ConfigSaved::default());
+    }
+
+    let (applied, restart_required): (Vec<_>, Vec<_>) = changed
+        .into_iter()
+        .partition(|path| conf::applies_live(path));
+    live.apply(&config, &applied);
+
+    log::info!(
+        "config changed over HTTP: {applied:?} applied, {restart_required:?} need a restart"
+    );
+    Ok(ConfigSaved {
+        applied,
+        restart_required,
+    })
+}
+
+/// The web panel - see [`super::www`].
+fn register_www(server: &mut EspHttpServer<'static>) -> anyhow::Result<()> {
+    server.fn_handler::<anyhow::Error, _>("/www/*", Method::Put, |mut req| {
+        let res = check_admin(&req).and_then(|()| upload_asset(&mut req));
+        respond(req, 201, res)
+    })?;
+
+    server.fn_handler::<anyhow::Error, _>("/www/*", Method::Delete, |req| {
+        let res = check_admin(&req)
+            .and_then(|()| asset_path(req.uri()))
+            .and_then(|path| {
+                std::fs::remove_file(format!("{}/{path}", www::ROOT))
+                    .map_err(|_| ApiError::new(404, "not_found", format!("no such file: {path}")))
+            });
+
+        match res {
+            Ok(()) => {
+                req.into_status_response(204)?;
+                Ok(())
+            }
+            Err(e) => respond_error(req, e),
+        }
+    })?;
+
+    // registered last of all, so it only gets what nothing else wanted
+    server.fn_handler::<anyhow::Error, _>("/*", Method::Get, serve_asset)?;
+
+    Ok(())
+}
+
+/// The path under [`www::ROOT`] for an upload to `/www/<path>`.
+fn asset_path(uri: &str) -> Result<String, ApiError> {
+    uri.strip_prefix("/www/")
+        .and_then(www::resolve)
+        .ok_or_else(|| ApiError::bad_request(format!("invalid asset path: {uri}")))
+}
+
+fn upload_asset(req: &mut Request<&mut EspHttpConnection>) -> Result<AssetSaved, ApiError> {
+    let path = asset_path(req.uri())?;
+    let len = req.content_len().ok_or_else(ApiError::length_required)? as usize;
+    if len > www::MAX_ASSET_LEN {
+        return Err(ApiError::too_large(www::MAX_ASSET_LEN));
+    }
+
+    let full = format!("{}/{path}", www::ROOT);
+    // a dotfile, so it's never served half-written
+    let tmp = format!("{}/.upload", www::ROOT);
+    let io = |e: std::io::Error| ApiError::internal(format!("failed to write {path}: {e}"));
+
+    if let Some((dir, _)) = full.rsplit_once('/') {
+        std::fs::create_dir_all(dir).map_err(io)?;
+    }
+
+    let mut file = File::create(&tmp).map_err(io)?;
+    let mut buf = vec![0; ASSET_CHUNK_LEN];
+    let mut written = 0;
+    while written < len {
+        let n = match req.read(&mut buf) {
+            Ok(0) => Err(ApiError::bad_request("request body ended early")),
+            Ok(n) => Ok(n.min(len - written)),
+            Err(e) => Err(ApiError::internal(format!(
+                "failed to read the body: {e:?}"
+            ))),
+        };
+        let res = n.and_then(|n| file.write_all(&buf[..n]).map(|()| n).map_err(io));
+
+        match res {
+            Ok(n) => written += n,
+            Err(e) => {
+                drop(file);
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e);
+            }
+        }
+    }
+
+    file.sync_all().map_err(io)?;
+    drop(file);
+    std::fs::rename(&tmp, &full).map_err(io)?;
+
+    log::info!("web panel asset {path} updated ({written} bytes)");
+    Ok(AssetSaved {
+        path,
+        size: written,
+    })
+}
+
+fn serve_asset(req: Request<&mut EspHttpConnection>) -> anyhow::Result<()> {
+    let Some(path) = www::resolve(req.uri()) else {
+        let error = ApiError::bad_request(format!("invalid path: {}", req.uri()));
+        return respond_error(req, error);
+    };
+
+    let plain = format!("{}/{path}", www::ROOT);
+    let gzipped = format!("{plain}.gz");
+    let gzip =
+        www::accepts_gzip(req.header("Accept-Encoding")) && std::fs::metadata(&gzipped).is_ok();
+
+    let mut file = match File::open(if gzip { &gzipped } else { &plain }) {
+        Ok(file) => file,
+        Err(_) => {
+            let error = ApiError::new(404, "not_found", format!("no such file: {path}"));
+            return respond_error(req, error);
+        }
+    };
+
+    // the files are small, so reading them twice beats keeping a hash around
+    let mut buf = vec![0; ASSET_CHUNK_LEN];
+    let mut etag = ETag::new();
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        etag.update(&buf[..n]);
+    }
+    let etag = etag.finish();
+
+    let mut headers = vec![
+        ("Content-Type", www::content_type(&path)),
+        ("Cache-Control", www::CACHE_CONTROL),
+        ("ETag", etag.as_str()),
+        ("Vary", "Accept-Encoding"),
+    ];
+    if www::etag_matches(req.header("If-None-Match"), &etag) {
+        req.into_response(304, None, &headers)?;
+        return Ok(());
+    }
+
+    if gzip {
+        headers.push(("Content-Encoding", "gzip"));
+    }
+
+    let mut res = req.into_response(200, None, &headers)?;
+    file.rewind()?;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        res.write_all(&buf[..n])?;
+    }
+
+    Ok(())
+}
+
+struct WsClient {
+    session: i32,
+    sender: EspHttpWsDetachedSender,
+}
+
+/// The WebSocket control stream - see [`super::ws`] for the schema.
+fn register_ws(
+    server: &mut EspHttpServer<'static>,
+    state: StateHandle,
+    events: StaticSender<Event>,
+    link: LinkWatchHandle,
+) -> anyhow::Result<()> {
+    let clients: Arc<Mutex<Vec<WsClient>>> = Arc::default();
+    let keepalive: Arc<Mutex<Keepalive>> = Arc::default();
+    let epoch = Instant::now();
+
+    let handler_clients = Arc::clone(&clients);
+    let handler_keepalive = Arc::clone(&keepalive);
+    let handler_state = state.clone();
+    let handler_link = link.clone();
+    server.ws_handler(
+        "/ws",
+        move |conn: &mut EspHttpWsConnection| -> anyhow::Result<()> {
+            let session = conn.session();
+            let now_ms = epoch.elapsed().as_millis() as u64;
+
+            if conn.is_new() {
+                log::info!("websocket {session} connected");
+                handler_keepalive.lock().seen(session, now_ms);
+                handler_clients.lock().push(WsClient {
+                    session,
+                    sender: conn.create_detached_sender()?,
+                });
+                let state = ws::encode_state(&handler_state.snapshot());
+                conn.send(FrameType::Binary(false), &state)?;
+                return Ok(());
+            }
+
+            if conn.is_closed() {
+                log::info!("websocket {session} closed");
+                handler_clients.lock().retain(|c| c.session != session);
+                handler_keepalive.lock().close(session);
+                handler_link.disconnected(Link::WebSocket(session));
+                return Ok(());
+            }
+
+            // peek at the length first, anything too long gets the socket closed by httpd
+            let (_, len) = conn.recv(&mut [])?;
+            if len > ws::MAX_MESSAGE_LEN {
+                return Err(anyhow::anyhow!(
+                    "websocket {session} sent a {len} byte message, closing it"
+                ));
+            }
+
+            let mut buf = [0; ws::MAX_MESSAGE_LEN];
+            let (frame, len) = conn.recv(&mut buf[..len])?;
+
+            handler_keepalive.lock().seen(session, now_ms);
+            handler_link.seen(Link::WebSocket(session));
+
+            let res = match frame {
+                FrameType::Binary(false) => ws::decode(&buf[..len]),
+                // pings and closes are answered by httpd
+                FrameType::Ping | FrameType::Pong | FrameType::Close | FrameType::SocketClose => {
+                    return Ok(())
+                }
+                _ => Err(WsError::NotBinary),
+            };
+
+            let cmd = match res {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    conn.send(FrameType::Binary(false), &ws::encode_error(&e))?;
+                    return Ok(());
+                }
+            };
+
+            let event = match cmd {
+                WsCommand::Ping => {
+                    conn.send(FrameType::Binary(false), &ws::PONG)?;
+                    return Ok(());
+                }
+                WsCommand::SetIntensity(level) => Event::SetIntensity(Source::Http, level),
+                WsCommand::Pattern(cmd) => Event::Pattern(Source::Http, cmd),
+                WsCommand::Stop => Event::Pattern(Source::Http, PatternCommand::Stop),
+            };
+
+            if cmd.is_control() {
+                handler_link.command(Link::WebSocket(session));
+            }
+
+            if events.try_send(event).is_err() {
+                conn.send(FrameType::Binary(false), &ws::encode_error(&WsError::Busy))?;
+            }
+
+            Ok(())
+        },
+    )?;
+
+    let spawned = std::thread::Builder::new()
+        .name("ws-push".into())
+        .stack_size(4096)
+        .spawn(move || {
+            let mut last = None;
+            let mut last_sent = Instant::now();
+            loop {
+                std::thread::sleep(WS_PUSH_INTERVAL);
+
+                let expired = keepalive.lock().expired(epoch.elapsed().as_millis() as u64);
+                let frame = ws::encode_state(&state.snapshot());
+                let due = last != Some(frame) || last_sent.elapsed() >= WS_KEEPALIVE_INTERVAL;
+                if !due && expired.is_empty() {
+                    continue;
+                }
+
+                if due {
+                    last = Some(frame);
+                    last_sent = Instant::now();
+                }
+
+                // sending waits on the httpd task, which might be waiting for this lock
+                let mut sending = std::mem::take(&mut *clients.lock());
+                sending.retain_mut(|client| {
+                    let alive = if expired.contains(&client.session) {
+                        log::info!("websocket {} went silent, closing it", client.session);
+                        let _ = client.sender.send(FrameType::Close, &[]);
+                        false
+                    } else {
+                        !due || client.sender.send(FrameType::Binary(false), &frame).is_ok()
+                    };
+
+                    if !alive {
+                        link.disconnected(Link::WebSocket(client.session));
+                    }
+                    alive
+                });
+                clients.lock().extend(sending);
+            }
+        });
+
+    if let Err(e) = spawned {
+        log::error!("failed to start websocket updates: {e}");
+    }
+
+    Ok(())
+}
+
+fn send_command(events: &StaticSender<Event>, event: Event) -> Result<(), ApiError> {
+    events.try_send(event).map_err(|_| ApiError::busy())
+}
+
+fn read_body(req: &mut Request<&mut EspHttpConnection>, limit: usize) -> Result<Vec<u8>, ApiError> {
+    let len = req.content_len().ok_or_else(ApiError::length_required)? as usize;
+    if len > limit {
+        return Err(ApiError::too_large(limit));
+    }
+
+    let mut body = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        match req.read(&mut body[read..]) {
+            Ok(0) => return Err(ApiError::bad_request("request body ended early")),
+            Ok(n) => read += n,
+            Err(e) => {
+                return Err(ApiError::internal(format!(
+                    "failed to read the body: {e:?}"
+                )))
+            }
+        }
+    }
+
+    Ok(body)
+}
+
+fn respond_command(
+    req: Request<&mut EspHttpConnection>,
+    res: Result<(), ApiError>,
+) -> anyhow::Result<()> {
+    respond(req, 202, res.map(|()| Accepted::YES))
+}
+
+fn respond(
+    req: Request<&mut EspHttpConnection>,
+    status: u16,
+    res: Result<impl Serialize, ApiError>,
+) -> anyhow::Result<()> {
+    match res {
+        Ok(body) => respond_json(req, status, &body),
+        Err(e) => respond_error(req, e),
+    }
+}
+
+fn respond_error(req: Request<&mut EspHttpConnection>, error: ApiError) -> anyhow::Result<()> {
+    log::info!("rejected request to {}: {error}", req.uri());
+    respond_json(req, error.status, &error)
+}
+
+fn respond_json(
+    req: Request<&mut EspHttpConnection>,
+    status: u16,
+    body: &impl Serialize,
+) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(body)?;
+    let mut res = req.into_response(status, None, &[("Content-Type", "application/json")])?;
+    res.write_all(&body)?;
+    Ok(())
+}
+
 const FIRMWARE_DOWNLOAD_CHUNK_SIZE: usize = 1024 * 8; // 8kb
-const FIRMWARE_MAX_SIZE: usize = 1024 * 1024 * 3; // 3MB
-const FIRMWARE_MIN_SIZE: usize = size_of::<FirmwareInfo>() + 1024;
 
 pub struct FirmwareUpdateHandler {
     ota: Arc<Mutex<EspOta>>,
@@ -46,24 +572,19 @@ impl Handler<EspHttpConnection<'_>> for FirmwareUpdateHandler {
     fn handle(&self, connection: &mut EspHttpConnection) -> Result<(), Self::Error> {
         let mut req = Request::wrap(connection);
 
-        let file_size = req.content_len().unwrap_or(0) as usize;
-        if file_size < FIRMWARE_MIN_SIZE {
+        let Some(file_size) = req.content_len() else {
             respond_and_log(
                 req,
                 Level::Info,
-                400,
-                format!("File size {file_size} too small - not proceeding!"),
+                411,
+                "No Content-Length - not proceeding!".to_string(),
             )?;
             return Ok(());
-        }
+        };
 
-        if file_size > FIRMWARE_MAX_SIZE {
-            respond_and_log(
-                req,
-                Level::Info,
-                400,
-                format!("File size {file_size} too big - not proceeding!"),
-            )?;
+        let file_size = file_size as usize;
+        if let Err(msg) = check_size(file_size) {
+            respond_and_log(req, Level::Info, 400, msg)?;
             return Ok(());
         }
 
@@ -80,10 +601,20 @@ impl Handler<EspHttpConnection<'_>> for FirmwareUpdateHandler {
             return Ok(());
         }
 
-        let mut ota = self.ota.lock();
+        // a BLE update holds on to it until it finishes or goes idle
+        let Some(mut ota) = self.ota.try_lock() else {
+            respond_and_log(
+                req,
+                Level::Info,
+                409,
+                "Another update is in progress - not proceeding!".to_string(),
+            )?;
+            return Ok(());
+        };
 
         let mut work = ota.initiate_update()?;
         let mut buffer = vec![0; FIRMWARE_DOWNLOAD_CHUNK_SIZE];
+        let mut verifier = Verifier::new();
         let mut missing_firmware_info = true;
         let mut total_bytes_read = 0;
 
@@ -98,8 +629,14 @@ impl Handler<EspHttpConnection<'_>> for FirmwareUpdateHandler {
             );
 
             total_bytes_read += bytes_read;
-            if missing_firmware_info {
-                let Ok(_info) = get_firmware_info(&buffer[..bytes_read]) else {
+            // the signature header stays out of flash
+            let image = match verifier.update(&buffer[..bytes_read]) {
+                Ok(image) => image,
+                Err(e) => break Err((400, format!("Rejecting the image: {e}"))),
+            };
+
+            if missing_firmware_info && !image.is_empty() {
+                let Ok(_info) = get_firmware_info(image) else {
                     break Err((
                         400,
                         "Failed to get firmware info from sent bytes".to_string(),
@@ -110,8 +647,8 @@ impl Handler<EspHttpConnection<'_>> for FirmwareUpdateHandler {
                 missing_firmware_info = false;
             }
 
-            if bytes_read > 0 {
-                if let Err(e) = work.write(&buffer[..bytes_read]) {
+            if !image.is_empty() {
+                if let Err(e) = work.write(image) {
                     break Err((500, format!("Failed to write to the OTA: {e}")));
                 }
             }
@@ -133,6 +670,12 @@ impl Handler<EspHttpConnection<'_>> for FirmwareUpdateHandler {
             return Ok(());
         }
 
+        if let Err(e) = check_signature(verifier) {
+            work.abort().unwrap();
+            respond_and_log(req, Level::Warn, 403, format!("Rejecting the image: {e}"))?;
+            return Ok(());
+        }
+
         work.complete()?;
 
         respond_and_log(req, Level::Info, 200, "OTA update completed!".to_owned())?;
@@ -152,10 +695,3 @@ fn respond_and_log(
     res.write_all(msg.as_bytes())?;
     Ok(())
 }
-
-fn get_firmware_info(buff: &[u8]) -> EspResult<()> {
-    let mut loader = EspFirmwareInfoLoader::new();
-    loader.load(buff)?;
-    Ok(())
-    // loader.get_info()
-}
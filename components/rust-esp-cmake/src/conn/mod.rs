@@ -1,4 +1,16 @@
+pub use hitachi_core::api;
+pub use hitachi_core::beacon;
 pub mod ble;
+pub use hitachi_core::ble_ota;
+pub use hitachi_core::emulation;
+pub use hitachi_core::hid;
 pub mod http;
+pub use hitachi_core::lovense;
+pub use hitachi_core::nus;
+pub mod ota;
 pub mod remote_log;
 pub mod serial;
+pub mod session;
+pub use hitachi_core::wand;
+pub use hitachi_core::ws;
+pub mod www;
@@ -0,0 +1,231 @@
+//! Firmware update checks and the BLE update worker. The HTTP upload handler lives in
+//! [`super::http`], the BLE wire format in [`super::ble_ota`].
+//!
+//! Both take signed images (see [`ota_sign`]) and only complete an update once the signature
+//! checks out against [`OTA_PUBLIC_KEY`].
+
+use std::{
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    time::Duration,
+};
+
+use esp_idf_svc::ota::{EspFirmwareInfoLoader, EspOta, FirmwareInfo};
+use ota_sign::{ed25519_dalek::PUBLIC_KEY_LENGTH, Verifier, HEADER_LEN};
+use parking_lot::Mutex;
+
+use crate::EspResult;
+
+use super::ble_ota::{OtaStatus, Transfer};
+
+pub const FIRMWARE_MAX_SIZE: usize = 1024 * 1024 * 3; // 3MB
+pub const FIRMWARE_MIN_SIZE: usize = HEADER_LEN + size_of::<FirmwareInfo>() + 1024;
+
+/// Embedded by the build script, from `ota-key.pub` in the project root, or a locally made
+/// dev key with `HITACHI_OTA_DEV_KEY=1`.
+pub const OTA_PUBLIC_KEY: [u8; PUBLIC_KEY_LENGTH] =
+    include!(concat!(env!("OUT_DIR"), "/ota_public_key.rs"));
+
+/// A BLE update that hears nothing for this long is aborted, which frees the [`EspOta`] for
+/// `/ota/upload` again. Until then a client that lost the connection can pick it back up where
+/// it left off.
+const BLE_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Progress is notified every this many bytes, rather than on every chunk.
+const BLE_PROGRESS_STEP: u32 = 4096;
+
+pub fn check_size(file_size: usize) -> Result<(), String> {
+    if file_size < FIRMWARE_MIN_SIZE {
+        return Err(format!("File size {file_size} too small - not proceeding!"));
+    }
+
+    if file_size > FIRMWARE_MAX_SIZE {
+        return Err(format!("File size {file_size} too big - not proceeding!"));
+    }
+
+    Ok(())
+}
+
+pub fn check_signature(verifier: Verifier) -> Result<(), ota_sign::Error> {
+    verifier.verify(&OTA_PUBLIC_KEY)
+}
+
+pub fn get_firmware_info(buff: &[u8]) -> EspResult<()> {
+    let mut loader = EspFirmwareInfoLoader::new();
+    loader.load(buff)?;
+    Ok(())
+    // loader.get_info()
+}
+
+/// What the BLE OTA service's write callbacks hand to [`run_ble_worker`].
+pub enum OtaRequest {
+    Begin { size: u32 },
+    Chunk { offset: u32, data: Vec<u8> },
+    Status,
+    Finish,
+    Abort,
+}
+
+/// Applies BLE update requests to `ota`, calling `report(status, offset, size)` with progress.
+/// Updates only start while `motor_running` returns false.
+pub fn run_ble_worker(
+    ota: Arc<Mutex<EspOta>>,
+    requests: Receiver<OtaRequest>,
+    motor_running: impl Fn() -> bool,
+    mut report: impl FnMut(OtaStatus, u32, u32),
+) {
+    while let Ok(request) = requests.recv() {
+        match request {
+            OtaRequest::Begin { size } => {
+                if motor_running() {
+                    log::warn!("refusing BLE OTA while the motor is running");
+                    report(OtaStatus::MotorRunning, 0, size);
+                    continue;
+                }
+
+                if let Err(e) = check_size(size as usize) {
+                    log::info!("{e}");
+                    report(OtaStatus::BadSize, 0, size);
+                    continue;
+                }
+
+                run_ble_transfer(&ota, size, &requests, &mut report);
+            }
+            OtaRequest::Status => report(OtaStatus::Idle, 0, 0),
+            OtaRequest::Chunk { .. } | OtaRequest::Finish => report(OtaStatus::NotStarted, 0, 0),
+            OtaRequest::Abort => {}
+        }
+    }
+}
+
+fn run_ble_transfer(
+    ota: &Mutex<EspOta>,
+    size: u32,
+    requests: &Receiver<OtaRequest>,
+    report: &mut impl FnMut(OtaStatus, u32, u32),
+) {
+    let Some(mut ota) = ota.try_lock() else {
+        log::warn!("refusing BLE OTA while another update is running");
+        report(OtaStatus::Busy, 0, size);
+        return;
+    };
+
+    let mut work = match ota.initiate_update() {
+        Ok(work) => work,
+        Err(e) => {
+            log::error!("failed to start the OTA: {e}");
+            report(OtaStatus::WriteFailed, 0, size);
+            return;
+        }
+    };
+
+    log::info!("BLE OTA started, expecting {size} bytes");
+    let mut transfer = Transfer::new(size);
+    let mut verifier = Verifier::new();
+    // the image header can span several chunks
+    let mut info = Some(EspFirmwareInfoLoader::new());
+    report(OtaStatus::Receiving, 0, size);
+
+    let result = loop {
+        let request = match requests.recv_timeout(BLE_IDLE_TIMEOUT) {
+            Ok(request) => request,
+            Err(RecvTimeoutError::Timeout) => {
+                log::warn!("BLE OTA timed out at {}/{size} bytes", transfer.offset());
+                break Err(OtaStatus::Idle);
+            }
+            Err(RecvTimeoutError::Disconnected) => break Err(OtaStatus::Idle),
+        };
+
+        match request {
+            // a client coming back for the same image resumes from the reported offset
+            OtaRequest::Begin { size: new_size } if transfer.resumes(new_size) => {
+                report(OtaStatus::Receiving, transfer.offset(), size);
+            }
+            OtaRequest::Begin { .. } => {
+                log::warn!("BLE OTA restarted with a different image, aborting the old one");
+                break Err(OtaStatus::Idle);
+            }
+            OtaRequest::Status => report(OtaStatus::Receiving, transfer.offset(), size),
+            OtaRequest::Chunk { offset, data } => {
+                let before = transfer.offset();
+                if let Err(status) = transfer.accept(offset, data.len()) {
+                    report(status, transfer.offset(), size);
+                    continue;
+                }
+
+                // the signature header stays out of flash
+                let image = match verifier.update(&data) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        log::info!("rejecting BLE OTA: {e}");
+                        break Err(OtaStatus::BadSignature);
+                    }
+                };
+
+                if let Some(loader) = info.as_mut().filter(|_| !image.is_empty()) {
+                    match loader.load(image) {
+                        Ok(true) => info = None,
+                        Ok(false) => {}
+                        Err(e) => {
+                            log::info!("Failed to get firmware info from sent bytes: {e}");
+                            break Err(OtaStatus::BadFirmware);
+                        }
+                    }
+                }
+
+                if let Err(e) = work.write(image) {
+                    log::error!("Failed to write to the OTA: {e}");
+                    break Err(OtaStatus::WriteFailed);
+                }
+
+                if before / BLE_PROGRESS_STEP != transfer.offset() / BLE_PROGRESS_STEP
+                    || transfer.is_complete()
+                {
+                    log::info!(
+                        "firmware DL: {:.2}%",
+                        (transfer.offset() as f64 / size as f64) * 100.0
+                    );
+                    report(OtaStatus::Receiving, transfer.offset(), size);
+                }
+            }
+            OtaRequest::Finish if !transfer.is_complete() => {
+                report(OtaStatus::Incomplete, transfer.offset(), size);
+            }
+            OtaRequest::Finish if info.is_some() => {
+                log::info!("Failed to get firmware info from sent bytes");
+                break Err(OtaStatus::BadFirmware);
+            }
+            OtaRequest::Finish => match check_signature(verifier) {
+                Ok(()) => break Ok(()),
+                Err(e) => {
+                    log::warn!("rejecting BLE OTA: {e}");
+                    break Err(OtaStatus::BadSignature);
+                }
+            },
+            OtaRequest::Abort => {
+                log::info!("BLE OTA aborted by the client");
+                break Err(OtaStatus::Idle);
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => match work.complete() {
+            Ok(()) => {
+                log::info!("OTA update completed!");
+                report(OtaStatus::Complete, size, size);
+            }
+            Err(e) => {
+                log::error!("failed to complete the OTA: {e}");
+                report(OtaStatus::WriteFailed, transfer.offset(), size);
+            }
+        },
+        Err(status) => {
+            if let Err(e) = work.abort() {
+                log::error!("failed to abort the OTA: {e}");
+            }
+            report(status, transfer.offset(), size);
+        }
+    }
+}
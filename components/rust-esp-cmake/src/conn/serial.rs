@@ -1,47 +1,84 @@
-use esp_idf_hal::{gpio::ADCPin, temp_sensor::TempSensorDriver};
+use esp_idf_hal::gpio::ADCPin;
 use parking_lot::Mutex;
 use thingbuf::{
-    mpsc::blocking::{Receiver, Sender},
+    mpsc::blocking::{Receiver, Sender, StaticSender},
     recycling::WithCapacity,
 };
 
+use esp32_nimble::BLEDevice;
 use esp_idf_sys::{esp_get_free_heap_size, esp_get_minimum_free_heap_size};
 use getargs::{Opt, Options};
 use humansize::DECIMAL;
-use std::{
-    fs::File,
-    io::{Seek, Write},
-    ops::DerefMut,
-    sync::Arc,
+use std::{io::Write, sync::Arc, time::Duration};
+
+use crate::{
+    arbiter::Source,
+    conf::Config,
+    event_queue::Event,
+    idf_libs::ntc::Thermistor,
+    intensity::MAX_INTENSITY,
+    motor::Motor,
+    pattern::{Pattern, PatternCommand, PatternKind},
+    sleep::{self, supervisor::SleepHandle},
+    state::StateHandle,
 };
-use thingbuf::mpsc::blocking::SendRef;
 
-use crate::{conf::Config, idf_libs::ntc::Thermistor};
+use super::session::{SessionMsg, Sessions};
 
 static HELP: &str = "USAGE: 
 wifi --field [FIELD] get|set [VALUE] | set wifi config options
 restart | self-explanatory
 dump-config
 sys mem|temp|[bweh]
+pattern [--period MS] [--min INTENSITY] [--max INTENSITY] pulse|wave|ramp|stairs|random | start a pattern
+pattern next|prev|stop|list
+timer [DURATION|off] | show or set the sleep timer, e.g. timer 20m
+status
+bond list|delete ADDRESS|clear | manage BLE bonds
+http token [set TOKEN|clear] | the token HTTP needs to change the config or the web panel
 help
 ";
 static WIFI_HELP: &str = "USAGE:
 wifi --field [FIELD] get
 wifi --field [FIELD] set [VALUE]
 ";
+static HTTP_HELP: &str = "USAGE:
+http token
+http token set TOKEN
+http token clear
+";
+static PATTERN_HELP: &str = "USAGE:
+pattern [--period MS] [--min INTENSITY] [--max INTENSITY] pulse|wave|ramp|stairs|random
+pattern next|prev|stop|list
+";
 
 pub struct SerialHandler<P: ADCPin> {
     ntc: Arc<Mutex<Thermistor<P>>>,
-    temp_sensor: TempSensorDriver<'static>,
+    events: StaticSender<Event>,
+    motor: Arc<Mutex<Motor>>,
+    sleep: SleepHandle,
+    sessions: Sessions,
+    state: StateHandle,
     // timer_service: EspTimerService<Task>,
     // timer: Option<EspTimer<'static>>
 }
 
 impl<P: ADCPin> SerialHandler<P> {
-    pub fn new(ntc: Thermistor<P>, temp_sensor: TempSensorDriver<'static>) -> Self {
+    pub fn new(
+        ntc: Arc<Mutex<Thermistor<P>>>,
+        events: StaticSender<Event>,
+        motor: Arc<Mutex<Motor>>,
+        sleep: SleepHandle,
+        sessions: Sessions,
+        state: StateHandle,
+    ) -> Self {
         Self {
-            ntc: Arc::new(Mutex::new(ntc)),
-            temp_sensor,
+            ntc,
+            events,
+            motor,
+            sleep,
+            sessions,
+            state,
             // timer_service: EspTimerService::new().unwrap(),
             // timer: None
         }
@@ -49,8 +86,9 @@ impl<P: ADCPin> SerialHandler<P> {
 
     pub fn handle_cmd(
         &mut self,
+        session: u16,
         recv: &str,
-        output: &mut SendRef<'_, Vec<u8>>,
+        output: &mut Vec<u8>,
     ) -> anyhow::Result<()> {
         let args = shlex::split(recv).ok_or_else(|| anyhow::anyhow!("invalid string"))?;
         let mut parser = Options::new(args.iter().map(String::as_str));
@@ -63,22 +101,21 @@ impl<P: ADCPin> SerialHandler<P> {
             }
         }
 
-        let mut config_f = File::options()
-            .write(true)
-            .read(true)
-            .open("/littlefs/config.json")?;
-        let mut config: Config = serde_json::from_reader(&mut config_f)?;
-
         let res = match parser.next_positional() {
-            Some("wifi") => self.handle_wifi(&mut parser, &mut config, output),
+            Some("wifi") => self.handle_wifi(&mut parser, output),
             Some("restart") => esp_idf_hal::reset::restart(),
             Some("dump-config") => {
                 write!(output, "Current configuration: ")?;
-                serde_json::to_writer_pretty(output.deref_mut(), &config)?;
-                writeln!(output.deref_mut())?;
+                serde_json::to_writer_pretty(&mut *output, &Config::load()?)?;
+                writeln!(output)?;
                 Ok(())
             }
-            Some("sys") => self.handle_sys(&mut parser, &mut config, output),
+            Some("sys") => self.handle_sys(&mut parser, output),
+            Some("pattern") => self.handle_pattern(&mut parser, output),
+            Some("timer") => self.handle_timer(&mut parser, output),
+            Some("status") => self.handle_status(session, output),
+            Some("bond") => self.handle_bond(&mut parser, output),
+            Some("http") => self.handle_http(&mut parser, output),
             // Some("monitor") => {
             //     self.handle_monitor(&mut parser, &mut config, output)
             // }
@@ -94,21 +131,15 @@ impl<P: ADCPin> SerialHandler<P> {
 
         if let Err(e) = res {
             writeln!(output, "Error!: {e}");
-            return Ok(());
         }
 
-        config_f.rewind()?;
-        config_f.set_len(0)?;
-        serde_json::to_writer(config_f, &config)?;
-
         Ok(())
     }
 
     pub fn handle_sys<'args, I: Iterator<Item = &'args str>>(
         &mut self,
         parser: &mut Options<&'args str, I>,
-        config: &mut Config,
-        output: &mut SendRef<'_, Vec<u8>>,
+        output: &mut Vec<u8>,
     ) -> anyhow::Result<()> {
         while let Some(opt) = parser.next_opt().ok().flatten() {}
 
@@ -136,11 +167,231 @@ impl<P: ADCPin> SerialHandler<P> {
         Ok(())
     }
 
+    pub fn handle_timer<'args, I: Iterator<Item = &'args str>>(
+        &mut self,
+        parser: &mut Options<&'args str, I>,
+        output: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        while let Some(_opt) = parser.next_opt().ok().flatten() {}
+
+        match parser.next_positional() {
+            None => match self.sleep.remaining() {
+                Some(remaining) => writeln!(
+                    output,
+                    "Sleep timer: {} left",
+                    sleep::format_remaining(remaining.as_millis() as u64)
+                )?,
+                None => writeln!(output, "Sleep timer is off")?,
+            },
+            Some("off") => {
+                self.sleep.cancel_timer();
+                writeln!(output, "Sleep timer is off")?;
+            }
+            Some(duration) => {
+                let Some(ms) = sleep::parse_duration(duration) else {
+                    return Err(anyhow::anyhow!(
+                        "Invalid duration {duration}. Usage: timer 20m|90s|1h|off"
+                    ));
+                };
+
+                self.sleep.set_timer(Duration::from_millis(ms));
+                writeln!(
+                    output,
+                    "Turning off in {}",
+                    sleep::format_remaining(ms)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_status(&mut self, session: u16, output: &mut Vec<u8>) -> anyhow::Result<()> {
+        let level = self.motor.lock().get();
+        writeln!(
+            output,
+            "Motor: {}/{MAX_INTENSITY} (actual {})",
+            level.target, level.actual
+        )?;
+
+        match self.sleep.remaining() {
+            Some(remaining) => writeln!(
+                output,
+                "Sleep timer: {} left",
+                sleep::format_remaining(remaining.as_millis() as u64)
+            )?,
+            None => writeln!(output, "Sleep timer: off")?,
+        }
+
+        let control = self.state.snapshot().device.control;
+        match control.owner() {
+            Some(owner) => writeln!(output, "Control: {owner}")?,
+            None => writeln!(output, "Control: none")?,
+        }
+        writeln!(
+            output,
+            "Remote control: {} (max {}/{MAX_INTENSITY})",
+            if control.paused() { "paused" } else { "allowed" },
+            control.remote_cap()
+        )?;
+
+        writeln!(
+            output,
+            "Session: {session}, {} connected",
+            self.sessions.count()
+        )?;
+
+        Ok(())
+    }
+
+    pub fn handle_bond<'args, I: Iterator<Item = &'args str>>(
+        &mut self,
+        parser: &mut Options<&'args str, I>,
+        output: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let device = BLEDevice::take();
+        let bonds = device
+            .bonded_addresses()
+            .map_err(|e| anyhow::anyhow!("failed to read bonds: {e:?}"))?;
+
+        match parser.next_positional() {
+            Some("list") | None => {
+                if bonds.is_empty() {
+                    writeln!(output, "No bonded devices")?;
+                }
+                for addr in bonds {
+                    writeln!(output, "{addr}")?;
+                }
+            }
+            Some("delete") => {
+                let Some(wanted) = parser.next_positional() else {
+                    return Err(anyhow::anyhow!("Missing address - Usage: bond delete ADDRESS"));
+                };
+                let Some(addr) = bonds
+                    .iter()
+                    .find(|addr| addr.to_string().eq_ignore_ascii_case(wanted))
+                else {
+                    return Err(anyhow::anyhow!("No bond with {wanted}"));
+                };
+
+                device
+                    .delete_bond(addr)
+                    .map_err(|e| anyhow::anyhow!("failed to delete bond: {e:?}"))?;
+                writeln!(output, "Deleted bond with {addr}")?;
+            }
+            Some("clear") => {
+                device
+                    .delete_all_bonds()
+                    .map_err(|e| anyhow::anyhow!("failed to delete bonds: {e:?}"))?;
+                writeln!(output, "Deleted {} bond(s)", bonds.len())?;
+            }
+            Some(cmd) => return Err(anyhow::anyhow!("Invalid subcommand {cmd}")),
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_http<'args, I: Iterator<Item = &'args str>>(
+        &mut self,
+        parser: &mut Options<&'args str, I>,
+        output: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        if parser.next_positional() != Some("token") {
+            return Err(anyhow::anyhow!("Invalid subcommand. usage: {HTTP_HELP}"));
+        }
+
+        let token = match parser.next_positional() {
+            None => {
+                if Config::load()?.http.admin_token.is_empty() {
+                    writeln!(
+                        output,
+                        "No admin token - HTTP can't change the config or the web panel"
+                    )?;
+                } else {
+                    writeln!(output, "Admin token is set")?;
+                }
+                return Ok(());
+            }
+            Some("set") => parser
+                .next_positional()
+                .ok_or_else(|| anyhow::anyhow!("missing token. usage: {HTTP_HELP}"))?,
+            Some("clear") => "",
+            Some(cmd) => return Err(anyhow::anyhow!("Invalid subcommand {cmd}")),
+        };
+
+        Config::edit(|mut config| {
+            config.http.admin_token = token.into();
+            config.http.validate()?;
+            anyhow::Ok((config, ()))
+        })??;
+
+        if token.is_empty() {
+            writeln!(output, "Cleared the admin token")?;
+        } else {
+            writeln!(output, "Updated the admin token")?;
+        }
+        Ok(())
+    }
+
+    pub fn handle_pattern<'args, I: Iterator<Item = &'args str>>(
+        &mut self,
+        parser: &mut Options<&'args str, I>,
+        output: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let mut period = Pattern::DEFAULT_PERIOD_MS;
+        let mut low = 0;
+        let mut high = MAX_INTENSITY;
+
+        while let Some(opt) = parser.next_opt().ok().flatten() {
+            let target = match opt {
+                Opt::Short('p') | Opt::Long("period") => &mut period,
+                Opt::Short('m') | Opt::Long("min") => &mut low,
+                Opt::Short('M') | Opt::Long("max") => &mut high,
+                _ => continue,
+            };
+
+            let value = parser
+                .value()
+                .map_err(|_| anyhow::anyhow!("couldn't parse option value"))?;
+            *target = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{value} is not a valid number"))?;
+        }
+
+        if low > MAX_INTENSITY || high > MAX_INTENSITY {
+            return Err(anyhow::anyhow!("intensities must be between 0 and {MAX_INTENSITY}"));
+        }
+
+        let cmd = match parser.next_positional() {
+            Some("list") => {
+                for kind in PatternKind::ALL {
+                    writeln!(output, "{kind}")?;
+                }
+                return Ok(());
+            }
+            Some("stop") => PatternCommand::Stop,
+            Some("next") => PatternCommand::Next,
+            Some("prev") => PatternCommand::Previous,
+            Some(name) => PatternCommand::Start(
+                Pattern::new(name.parse()?, low, high).with_period(period),
+            ),
+            None => return Err(anyhow::anyhow!("missing pattern. usage: {PATTERN_HELP}")),
+        };
+
+        self.events
+            .try_send(Event::Pattern(Source::Console, cmd))
+            .map_err(|_| anyhow::anyhow!("event queue is full, try again"))?;
+
+        writeln!(output, "OK!")?;
+
+        Ok(())
+    }
+
     // pub fn handle_monitor<'args, I: Iterator<Item = &'args str>>(
     //     &mut self,
     //     parser: &mut Options<&'args str, I>,
     //     config: &mut Config,
-    //     output: &mut SendRef<'_, Vec<u8>>,
+    //     output: &mut Vec<u8>,
     // ) -> anyhow::Result<()> {
     //     while let Some(opt) = parser.next_opt().ok().flatten() {};
 
@@ -170,8 +421,7 @@ impl<P: ADCPin> SerialHandler<P> {
     pub fn handle_wifi<'args, I: Iterator<Item = &'args str>>(
         &mut self,
         parser: &mut Options<&'args str, I>,
-        config: &mut Config,
-        output: &mut SendRef<'_, Vec<u8>>,
+        output: &mut Vec<u8>,
     ) -> anyhow::Result<()> {
         let mut field = None;
 
@@ -200,7 +450,10 @@ impl<P: ADCPin> SerialHandler<P> {
                     ));
                 };
 
-                config.wifi.update(field, value)?;
+                Config::edit(|mut config| {
+                    config.wifi.update(field, value)?;
+                    anyhow::Ok((config, ()))
+                })??;
 
                 writeln!(output, "Updated {field} to {value}!")?;
             }
@@ -212,7 +465,7 @@ impl<P: ADCPin> SerialHandler<P> {
                 writeln!(
                     output,
                     "Field {field} is set to {}",
-                    config.wifi.get(field)?
+                    Config::load()?.wifi.get(field)?
                 )?;
             }
             _ => return Err(anyhow::anyhow!("Invalid subcommand")),
@@ -223,15 +476,22 @@ impl<P: ADCPin> SerialHandler<P> {
 
     pub fn handle_serial(
         mut self,
-        uart_rx: Receiver<String, WithCapacity>,
-        uart_tx: Sender<Vec<u8>, WithCapacity>,
+        uart_rx: Receiver<SessionMsg<String>, WithCapacity>,
+        uart_tx: Sender<SessionMsg<Vec<u8>>, WithCapacity>,
     ) {
         while let Some(req_slot) = uart_rx.recv_ref() {
-            log::info!("Received on ble/UART: {req_slot}");
+            log::info!(
+                "Received on ble/UART from {}: {}",
+                req_slot.session,
+                req_slot.body
+            );
+            self.sleep.touch();
             let mut send_slot = uart_tx.send_ref().unwrap();
+            send_slot.session = req_slot.session;
+            let output = &mut send_slot.body;
 
-            if let Err(e) = self.handle_cmd(req_slot.as_str(), &mut send_slot) {
-                let _ = writeln!(send_slot, "Error while handling: {e}");
+            if let Err(e) = self.handle_cmd(req_slot.session, req_slot.body.as_str(), output) {
+                let _ = writeln!(output, "Error while handling: {e}");
             }
         }
     }
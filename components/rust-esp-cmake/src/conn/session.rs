@@ -0,0 +1,70 @@
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::Mutex;
+use thingbuf::{recycling::WithCapacity, Recycle};
+
+use super::{lovense::LovenseDecoder, nus};
+
+/// Per-connection state for a BLE central.
+pub struct Session {
+    /// NUS console input, split into lines
+    pub input: nus::LineBuffer,
+    pub lovense: LovenseDecoder,
+    /// last ATT MTU we saw, to log when it changes
+    pub mtu: u16,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            input: nus::LineBuffer::default(),
+            lovense: LovenseDecoder::default(),
+            mtu: nus::DEFAULT_MTU,
+        }
+    }
+}
+
+/// Sessions keyed by connection handle. Created on connect, dropped on disconnect.
+#[derive(Clone, Default)]
+pub struct Sessions(Arc<Mutex<HashMap<u16, Session>>>);
+
+impl Sessions {
+    pub fn open(&self, conn_handle: u16) {
+        self.0.lock().insert(conn_handle, Session::default());
+    }
+
+    pub fn close(&self, conn_handle: u16) {
+        self.0.lock().remove(&conn_handle);
+    }
+
+    /// Runs `f` on the session for `conn_handle`, if it's still connected.
+    pub fn with<R>(&self, conn_handle: u16, f: impl FnOnce(&mut Session) -> R) -> Option<R> {
+        self.0.lock().get_mut(&conn_handle).map(f)
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.lock().len()
+    }
+}
+
+/// A console line or response, tagged with the connection it came from / goes to.
+pub struct SessionMsg<T> {
+    pub session: u16,
+    pub body: T,
+}
+
+impl<T> Recycle<SessionMsg<T>> for WithCapacity
+where
+    WithCapacity: Recycle<T>,
+{
+    fn new_element(&self) -> SessionMsg<T> {
+        SessionMsg {
+            session: 0,
+            body: self.new_element(),
+        }
+    }
+
+    fn recycle(&self, element: &mut SessionMsg<T>) {
+        self.recycle(&mut element.body);
+    }
+}
@@ -0,0 +1,114 @@
+//! Static files for the web control panel, served from [`ROOT`].
+//!
+//! Assets are meant to be uploaded gzipped (`app.js.gz`); a request for `app.js` gets the `.gz`
+//! with `Content-Encoding: gzip` if the client takes it, and the plain file otherwise. Every
+//! response carries an ETag over the file's contents and `Cache-Control: no-cache`, so browsers
+//! keep their copy but check it's still current - which is cheap, and means freshly uploaded
+//! assets show up straight away.
+
+pub const ROOT: &str = "/littlefs/www";
+
+/// The storage partition is only 100K, shared with the config.
+pub const MAX_ASSET_LEN: usize = 64 * 1024;
+
+pub const CACHE_CONTROL: &str = "no-cache";
+
+/// Turns a request path into a path relative to [`ROOT`], or `None` if it tries to leave it.
+/// Directories get their `index.html`.
+pub fn resolve(uri: &str) -> Option<String> {
+    let path = uri.split(['?', '#']).next().unwrap_or_default();
+    let path = path.trim_start_matches('/');
+
+    let mut resolved = String::with_capacity(path.len() + 10);
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        let allowed = segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+        // no "..", and no dotfiles either (our own temp files are dotfiles)
+        if !allowed || segment.starts_with('.') {
+            return None;
+        }
+
+        if !resolved.is_empty() {
+            resolved.push('/');
+        }
+        resolved.push_str(segment);
+    }
+
+    if resolved.is_empty() || path.ends_with('/') {
+        if !resolved.is_empty() {
+            resolved.push('/');
+        }
+        resolved.push_str("index.html");
+    }
+
+    Some(resolved)
+}
+
+pub fn content_type(path: &str) -> &'static str {
+    let path = path.strip_suffix(".gz").unwrap_or(path);
+    let extension = path.rsplit_once('.').map_or("", |(_, ext)| ext);
+
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "ico" => "image/x-icon",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+pub fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    accept_encoding.is_some_and(|header| {
+        header.split(',').any(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            // "gzip;q=0" means "anything but gzip"
+            let refused = parts.any(|p| matches!(p.trim(), "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+            name.eq_ignore_ascii_case("gzip") && !refused
+        })
+    })
+}
+
+/// FNV-1a over a file's contents, fed in chunks.
+pub struct ETag(u64);
+
+impl ETag {
+    pub fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> String {
+        format!("\"{:016x}\"", self.0)
+    }
+}
+
+impl Default for ETag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether an `If-None-Match` header covers `etag`, i.e. the client's copy is current.
+pub fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    if_none_match.is_some_and(|header| {
+        header.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag
+        })
+    })
+}
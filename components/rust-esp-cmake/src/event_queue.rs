@@ -1,14 +1,25 @@
 use thingbuf::mpsc::blocking::{StaticChannel, StaticReceiver, StaticSender};
 
-use crate::{ble::LovenseMessage, idf_libs::button::ButtonEvent};
+use crate::{
+    arbiter::Source, ble::LovenseMessage, failsafe::LinkLoss, idf_libs::button::ButtonEvent,
+    pairing::PairingCheck, pattern::PatternCommand, sleep::SleepCheck, thermal::ThermalState,
+};
 
 static EVENT_QUEUE: StaticChannel<Event, 64> = StaticChannel::new();
 
 #[derive(Clone, Copy, Default, Debug)]
 pub enum Event {
     Lovense(LovenseMessage),
-    SetPwm(u8),
+    /// target intensity from a source that isn't Lovense
+    SetIntensity(Source, u32),
+    Pattern(Source, PatternCommand),
+    Thermal(ThermalState),
+    Sleep(SleepCheck),
+    LinkLost(LinkLoss),
+    Pairing(PairingCheck),
     Button(ButtonEvent, i32),
+    /// `control.remote_max_intensity` was changed
+    RemoteCap(u32),
     #[default]
     Null,
 }
@@ -0,0 +1,12 @@
+//! Link-loss failsafe for remote controllers.
+//!
+//! Whichever remote link (a BLE connection or a WebSocket) last sent a motor command is the
+//! controller. When it disconnects, or sends nothing for the heartbeat timeout, the supervisor
+//! raises [`Event::LinkLost`](crate::event_queue::Event::LinkLost) once. The main loop applies
+//! `ble.failsafe.link_loss` to BLE links and always stops for WebSockets. Pressing a button
+//! releases the controller, so a phone wandering off after that doesn't stop anything. The
+//! state machine is [`hitachi_core::failsafe`]; [`supervisor::LinkWatchHandle`] clocks it.
+
+pub use hitachi_core::failsafe::*;
+
+pub mod supervisor;
@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use thingbuf::mpsc::blocking::StaticSender;
+
+use crate::{clock::Clocked, event_queue::Event};
+
+use super::{Link, LinkWatch};
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Shared handle to the [`LinkWatch`].
+#[derive(Clone)]
+pub struct LinkWatchHandle {
+    watch: Clocked<LinkWatch>,
+}
+
+impl LinkWatchHandle {
+    pub fn new(heartbeat: Option<Duration>) -> Self {
+        Self {
+            watch: Clocked::new(LinkWatch::new(heartbeat.map(|d| d.as_millis() as u64))),
+        }
+    }
+
+    /// Call when a connection sends something that drives the motor.
+    pub fn command(&self, link: Link) {
+        self.watch.with(|watch, now_ms| watch.command(link, now_ms));
+    }
+
+    /// Call on any write from a connection.
+    pub fn seen(&self, link: Link) {
+        self.watch.with(|watch, now_ms| watch.seen(link, now_ms));
+    }
+
+    pub fn release(&self) {
+        self.watch.with(|watch, _| watch.release());
+    }
+
+    /// Safe to call from the NimBLE host task - the loss is only reported by the supervisor.
+    pub fn disconnected(&self, link: Link) {
+        self.watch.with(|watch, _| watch.disconnected(link));
+    }
+
+    /// Spawns the thread that raises [`Event::LinkLost`].
+    pub fn spawn_supervisor(
+        &self,
+        events: StaticSender<Event>,
+    ) -> std::io::Result<std::thread::JoinHandle<()>> {
+        self.watch
+            .spawn_ticker("failsafe", CHECK_INTERVAL, move |watch| {
+                let loss = watch.with(|watch, now_ms| watch.check(now_ms));
+                if let Some(loss) = loss {
+                    log::warn!("lost the controlling link: {loss:?}");
+                    // like the sleep timer, this must not get dropped on a full queue
+                    let _ = events.send(Event::LinkLost(loss));
+                }
+            })
+    }
+}
@@ -175,6 +175,13 @@ impl ButtonManager {
             data_ptr as *mut c_void,
         )?;
 
+        button.register_callback(
+            ButtonEvent::LongPressStart,
+            Some(btn_callback),
+            ptr::null_mut(),
+            data_ptr as *mut c_void,
+        )?;
+
         self.button_handlers.push(button);
 
         Ok(())
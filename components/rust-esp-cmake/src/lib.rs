@@ -4,12 +4,24 @@ use std::{
     ffi::{CStr, CString},
     fs::File,
     sync::Arc,
+    time::Duration,
 };
 
+use arbiter::{Arbiter, Source, Verdict};
 use ble::LovenseMessage;
-use conf::{Config, MotorConfig, RemoteLogConfig, WifiConfig};
-use conn::{ble, http::run_http, remote_log::remote_log_server, serial::SerialHandler};
+use conf::{
+    BleConfig, BleSecurityConfig, Config, LinkLossPolicy, MotorConfig, RemoteLogConfig,
+    SleepConfig, ThermalConfig, WifiConfig, CONFIG_PATH,
+};
+use conn::{
+    ble,
+    http::{run_http, LiveConfig},
+    remote_log::remote_log_server,
+    serial::SerialHandler,
+    session::Sessions,
+};
 use esp_idf_hal::{
+    gpio::Pin,
     ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver},
     prelude::Peripherals,
     temp_sensor::{TempSensorConfig, TempSensorDriver},
@@ -25,25 +37,47 @@ use esp_idf_sys::{
     esp_app_get_description, esp_nofail, esp_vfs_littlefs_conf_t, esp_vfs_littlefs_register,
     EspError,
 };
+use failsafe::{supervisor::LinkWatchHandle, Link};
 use idf_libs::{
     button::{ButtonConfig, ButtonEvent, ButtonManager},
     log_redirection::{log_crate_shenanigans::EspChannelLogger, redirect_logs},
     ntc::{Thermistor, ThermistorConfig},
 };
 use lights::Lights;
+use intensity::MAX_INTENSITY;
 use motor::Motor;
 use parking_lot::Mutex;
+use pairing::{
+    supervisor::{PairingHandle, CHECK_INTERVAL},
+    PairingCheck,
+};
+use pattern::{player::PatternPlayer, Pattern, PatternCommand, PatternKind};
+use sleep::{supervisor::SleepHandle, SleepCheck};
+use state::StateHandle;
+use thermal::{supervisor::ThermalSupervisor, ThermalState};
 use thingbuf::recycling::WithCapacity;
+pub use hitachi_core::arbiter;
+pub mod clock;
 pub mod conf;
 pub mod conn;
 pub mod event_queue;
+pub mod failsafe;
 pub mod idf_libs;
+pub use hitachi_core::intensity;
 pub mod lights;
 pub mod motor;
+pub mod pairing;
+pub mod pattern;
+pub mod sleep;
+pub mod state;
+pub mod thermal;
 pub mod wifi;
 
 pub type EspResult<T> = Result<T, EspError>;
 
+const SLEEP_TIMER_SLOT: Duration = Duration::from_secs(15 * 60);
+const PAIRING_LONG_PRESS: Duration = Duration::from_secs(3);
+
 #[no_mangle]
 extern "C" fn rust_primary() -> i32 {
     // It is necessary to call this function once. Otherwise some patches to the runtime
@@ -54,6 +88,8 @@ extern "C" fn rust_primary() -> i32 {
     // esp_idf_svc::log::EspLogger::initialize_default();
 
     log::info!("Hello, world!");
+    #[cfg(ota_dev_key)]
+    log::warn!("built with the dev OTA key - anyone can sign updates for this wand");
 
     if let Err(e) = real_main() {
         log::error!("Main errored out: {e}");
@@ -102,9 +138,9 @@ fn real_main() -> anyhow::Result<()> {
         esp_nofail!(esp_vfs_littlefs_register(&conf));
     }
 
-    if !std::fs::exists("/littlefs/config.json")? {
+    if !std::fs::exists(CONFIG_PATH)? {
         serde_json::to_writer(
-            File::create("/littlefs/config.json")?,
+            File::create(CONFIG_PATH)?,
             &Config {
                 wifi: WifiConfig {
                     enable: true,
@@ -114,20 +150,36 @@ fn real_main() -> anyhow::Result<()> {
                     identity: String::new(),
                     username: String::new(),
                 },
-                motor: MotorConfig {
-                    max_power: 100,
-                    min_power: 50,
-                },
+                motor: MotorConfig::default(),
                 remote_log: RemoteLogConfig {
                     enable: true,
                     port: 8070,
                 },
+                thermal: ThermalConfig::default(),
+                sleep: SleepConfig::default(),
+                ble: BleConfig::default(),
+                control: conf::ControlConfig::default(),
             },
         )?;
     }
 
-    let wifi_cfg: WifiConfig =
-        serde_json::from_reader::<_, Config>(File::open("/littlefs/config.json")?)?.wifi;
+    let mut config = Config::load()?;
+    if let Err(e) = config.motor.validate() {
+        log::error!("invalid motor config, falling back to defaults: {e}");
+        config.motor = MotorConfig::default();
+    }
+    if let Err(e) = config.thermal.validate() {
+        log::error!("invalid thermal config, falling back to defaults: {e}");
+        config.thermal = ThermalConfig::default();
+    }
+    if let Err(e) = config.ble.security.validate() {
+        log::error!("invalid BLE security config, falling back to defaults: {e}");
+        config.ble.security = BleSecurityConfig::default();
+    }
+    if let Err(e) = config.control.validate() {
+        log::error!("invalid control config, falling back to defaults: {e}");
+        config.control = conf::ControlConfig::default();
+    }
     let mut wifi_manager = wifi::WifiManager::new(
         EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))?,
         sys_loop,
@@ -155,9 +207,13 @@ fn real_main() -> anyhow::Result<()> {
         peripherals.pins.gpio5,
     )?;
 
-    let mut motor = Motor::new(driver);
+    let motor = Arc::new(Mutex::new(Motor::new(driver, &config.motor)?));
+    Motor::start_ramp(Arc::clone(&motor))?;
+    let player = PatternPlayer::new(Arc::clone(&motor))?;
+    let state = StateHandle::new(Arc::clone(&motor));
+    let mut arbiter = Arbiter::new(config.control.remote_max_intensity);
 
-    let thermistor = Thermistor::new(
+    let thermistor = Arc::new(Mutex::new(Thermistor::new(
         peripherals.pins.gpio2,
         ThermistorConfig {
             b_value: 3950,
@@ -166,20 +222,48 @@ fn real_main() -> anyhow::Result<()> {
             vdd_mv: 3300,
             circuit_mode: idf_libs::ntc::ThermistorCircuitMode::NtcGnd,
         },
-    )?;
+    )?));
 
     let mut temp_sensor = TempSensorDriver::new(&TempSensorConfig::new(), peripherals.temp_sensor)?;
     temp_sensor.enable()?;
-    let serial_handler = SerialHandler::new(thermistor, temp_sensor);
-
     // driver.set_duty(max_duty * 3 / 4)?;
 
     let (event_tx, event_rx) = event_queue::get_channel();
 
+    ThermalSupervisor::new(
+        Arc::clone(&thermistor),
+        temp_sensor,
+        Arc::clone(&motor),
+        event_tx.clone(),
+        state.clone(),
+        &config.thermal,
+    )
+    .spawn()?;
+
+    let sleep = SleepHandle::new(config.sleep.idle_timeout());
+    sleep.spawn_supervisor(Arc::clone(&motor), event_tx.clone())?;
+
+    let sessions = Sessions::default();
+    let serial_handler = SerialHandler::new(
+        thermistor,
+        event_tx.clone(),
+        Arc::clone(&motor),
+        sleep.clone(),
+        sessions.clone(),
+        state.clone(),
+    );
+
     let mut button_manager = ButtonManager::new(event_tx.clone());
     button_manager.add_button(peripherals.pins.gpio6, ButtonConfig::default())?;
     button_manager.add_button(peripherals.pins.gpio7, ButtonConfig::default())?;
-    button_manager.add_button(peripherals.pins.gpio8, ButtonConfig::default())?;
+    // long press opens the pairing window, so make it long enough not to happen by accident
+    button_manager.add_button(
+        peripherals.pins.gpio8,
+        ButtonConfig {
+            long_press_time: Some(PAIRING_LONG_PRESS),
+            ..ButtonConfig::default()
+        },
+    )?;
 
     let ota = Arc::new(Mutex::new(EspOta::new().unwrap()));
 
@@ -189,39 +273,279 @@ fn real_main() -> anyhow::Result<()> {
     let (uart_rx_send, uart_rx_receive) =
         thingbuf::mpsc::blocking::with_recycle(32, WithCapacity::new().with_max_capacity(128));
 
-    std::thread::spawn(|| ble::run_ble(ble_tx, uart_tx_receive, uart_rx_send));
-    let http = run_http(8080, Arc::clone(&ota))?;
+    let failsafe_config = config.ble.failsafe;
+    let link = LinkWatchHandle::new(
+        (failsafe_config.heartbeat_timeout_s > 0)
+            .then(|| Duration::from_secs(failsafe_config.heartbeat_timeout_s as u64)),
+    );
+    link.spawn_supervisor(event_tx.clone())?;
+
+    let pairing = PairingHandle::new(Duration::from_secs(
+        config.ble.security.pairing_window_s as u64,
+    ));
+    pairing.spawn_supervisor(event_tx.clone())?;
+
+    let ble_config = config.ble;
+    let ble_link = link.clone();
+    let ble_pairing = pairing.clone();
+    let ble_ota = Arc::clone(&ota);
+    let ble_state = state.clone();
+    let ble_uart_tx = uart_tx_send.clone();
+    std::thread::spawn(|| {
+        ble::run_ble(
+            ble_config,
+            ble_state,
+            ble_tx,
+            sessions,
+            ble_link,
+            ble_pairing,
+            ble_ota,
+            uart_tx_receive,
+            ble_uart_tx,
+            uart_rx_send,
+        )
+    });
+    let http = run_http(
+        8080,
+        Arc::clone(&ota),
+        state.clone(),
+        event_tx.clone(),
+        link.clone(),
+        LiveConfig {
+            motor: Arc::clone(&motor),
+            sleep: sleep.clone(),
+            events: event_tx.clone(),
+        },
+    )?;
 
     std::thread::spawn(move || serial_handler.handle_serial(uart_rx_receive, uart_tx_send));
 
     for event in &event_rx {
+        if matches!(
+            *event,
+            event_queue::Event::Button(..)
+                | event_queue::Event::Lovense(..)
+                | event_queue::Event::Pattern(..)
+                | event_queue::Event::SetIntensity(..)
+        ) {
+            sleep.touch();
+        }
+
+        // the buttons take over, so a remote dropping afterwards shouldn't stop anything
+        if matches!(*event, event_queue::Event::Button(..)) {
+            link.release();
+        }
+
+        let mut cap = MAX_INTENSITY;
+        if let Some((source, stopping)) = motor_request(&event) {
+            if stopping {
+                arbiter.stop(source);
+            } else {
+                match arbiter.request(source) {
+                    Verdict::Allow { cap: allowed } => cap = allowed,
+                    verdict => {
+                        log::debug!("ignoring {source} command: {verdict:?}");
+                        continue;
+                    }
+                }
+            }
+        }
+
         match *event {
             event_queue::Event::Button(ButtonEvent::SingleClick, pin) => {
                 let speed = match pin {
-                    6 => motor.inc(),
-                    7 => motor.dec(),
-                    8 => motor.set(0),
+                    6 => {
+                        player.stop();
+                        motor.lock().inc()
+                    }
+                    7 => {
+                        player.stop();
+                        motor.lock().dec()
+                    }
+                    8 => player.stop_motor(),
                     _ => continue,
                 };
 
                 lights.show_speed(speed)?;
             }
-            event_queue::Event::SetPwm(val) => {
-                // let val = val as u32;
+            event_queue::Event::Button(ButtonEvent::DoubleClick, 8) => {
+                // cycles the sleep timer through off -> 15m -> 30m -> 45m -> 60m -> off
+                let slots = sleep
+                    .remaining()
+                    .map_or(0, |r| r.as_secs().div_ceil(SLEEP_TIMER_SLOT.as_secs()) as u32);
+                let next = (slots + 1) % 5;
+                if next == 0 {
+                    sleep.cancel_timer();
+                } else {
+                    sleep.set_timer(SLEEP_TIMER_SLOT * next);
+                }
+
+                lights.show_timer(next)?;
+            }
+            event_queue::Event::Button(ButtonEvent::DoubleClick, pin) => {
+                let cmd = match pin {
+                    6 => PatternCommand::Next,
+                    7 => PatternCommand::Previous,
+                    _ => continue,
+                };
 
-                // let
-                // driver.set_duty(driver.get_max_duty() * val / (u8::MAX as u32))?;
+                handle_pattern_cmd(&player, &mut lights, cmd, cap)?;
+            }
+            event_queue::Event::Pattern(_, cmd) => {
+                handle_pattern_cmd(&player, &mut lights, cmd, cap)?;
+            }
+            event_queue::Event::Thermal(ThermalState::Shutdown) => {
+                lights.show_overheat()?;
+            }
+            event_queue::Event::Thermal(_) => {
+                lights.show_speed(motor.lock().get().actual)?;
+            }
+            event_queue::Event::Sleep(SleepCheck::Expired) => {
+                player.stop();
+                lights.show_speed(motor.lock().set(0))?;
+            }
+            event_queue::Event::Button(ButtonEvent::LongPressStart, 8) => {
+                pairing.open();
+                lights.show_pairing(0)?;
+            }
+            event_queue::Event::Pairing(PairingCheck::Open { remaining_ms }) => {
+                lights.show_pairing(remaining_ms / CHECK_INTERVAL.as_millis() as u64)?;
+            }
+            event_queue::Event::Pairing(PairingCheck::Closed) => {
+                lights.show_speed(motor.lock().get().target)?;
+            }
+            event_queue::Event::Sleep(SleepCheck::Warning { remaining_ms }) => {
+                // blink with the supervisor's check interval
+                if (remaining_ms / 500) % 2 == 0 {
+                    lights.show_timer(4)?;
+                } else {
+                    lights.show_speed(motor.lock().get().target)?;
+                }
+            }
+            event_queue::Event::LinkLost(loss) => match failsafe_config.link_loss {
+                // a closed tab isn't coming back the way a phone walking out of range might
+                _ if matches!(loss.link(), Link::WebSocket(_)) => {
+                    lights.show_speed(player.stop_motor())?;
+                }
+                LinkLossPolicy::Stop => {
+                    lights.show_speed(player.stop_motor())?;
+                }
+                LinkLossPolicy::RampDown { secs } => {
+                    player.stop();
+                    motor.lock().fade_out(Duration::from_secs(secs as u64));
+                    lights.show_speed(0)?;
+                }
+                LinkLossPolicy::Keep => {
+                    log::info!("keeping the motor running after {loss:?}");
+                }
+            },
+            event_queue::Event::SetIntensity(_, 0) => {
+                lights.show_speed(player.stop_motor())?;
+            }
+            event_queue::Event::SetIntensity(_, level) => {
+                player.stop();
+                lights.show_speed(motor.lock().set(level.min(cap)))?;
+            }
+            event_queue::Event::Lovense(LovenseMessage::Vibrate(0)) => {
+                lights.show_speed(player.stop_motor())?;
             }
             event_queue::Event::Lovense(LovenseMessage::Vibrate(val)) => {
-                lights.show_speed(motor.set(val as u32))?;
+                player.stop();
+                let level = intensity::from_steps(val as u32, ble::LOVENSE_STEPS);
+                lights.show_speed(motor.lock().set(level.min(cap)))?;
+            }
+            event_queue::Event::Lovense(LovenseMessage::PowerOff) => {
+                lights.show_speed(player.stop_motor())?;
+            }
+            event_queue::Event::Lovense(LovenseMessage::Light(on)) => {
+                lights.set_enabled(on)?;
+                if on {
+                    lights.show_speed(motor.lock().get().target)?;
+                }
+            }
+            event_queue::Event::Lovense(LovenseMessage::Preset(0)) => {
+                handle_pattern_cmd(&player, &mut lights, PatternCommand::Stop, cap)?;
+            }
+            event_queue::Event::Lovense(LovenseMessage::Preset(n)) => {
+                let kind = PatternKind::ALL[(n as usize - 1) % PatternKind::ALL.len()];
+                let cmd = PatternCommand::Start(Pattern::new(kind, 0, MAX_INTENSITY));
+                handle_pattern_cmd(&player, &mut lights, cmd, cap)?;
+            }
+            event_queue::Event::RemoteCap(remote_cap) => {
+                arbiter.set_remote_cap(remote_cap);
             }
             _ => continue,
         }
+
+        // whoever was driving the motor is done with it once it's off
+        if player.current().is_none() && motor.lock().get().target == 0 {
+            arbiter.release();
+        }
+
+        state.update(|s| {
+            s.pattern = player.current();
+            s.control = arbiter;
+        });
     }
 
     Ok(())
 }
 
+/// The arbiter's view of `event`: which source it's from and whether it stops the motor.
+/// `None` for events that don't drive the motor.
+fn motor_request(event: &event_queue::Event) -> Option<(Source, bool)> {
+    use event_queue::Event;
+
+    match *event {
+        Event::Button(ButtonEvent::SingleClick, 8) => Some((Source::Buttons, true)),
+        Event::Button(ButtonEvent::SingleClick | ButtonEvent::DoubleClick, 6 | 7) => {
+            Some((Source::Buttons, false))
+        }
+        Event::Lovense(LovenseMessage::Vibrate(val)) => Some((Source::Ble, val == 0)),
+        Event::Lovense(LovenseMessage::Preset(n)) => Some((Source::Ble, n == 0)),
+        Event::Lovense(LovenseMessage::PowerOff) => Some((Source::Ble, true)),
+        Event::SetIntensity(source, level) => Some((source, level == 0)),
+        Event::Pattern(source, cmd) => Some((source, cmd == PatternCommand::Stop)),
+        _ => None,
+    }
+}
+
+/// `cap` limits the intensities of whatever pattern ends up playing.
+fn handle_pattern_cmd<P: Pin>(
+    player: &PatternPlayer,
+    lights: &mut Lights<P>,
+    cmd: PatternCommand,
+    cap: u32,
+) -> anyhow::Result<()> {
+    let pattern = match cmd {
+        PatternCommand::Start(pattern) => pattern,
+        PatternCommand::Next | PatternCommand::Previous => match (player.current(), cmd) {
+            (Some(current), PatternCommand::Next) => Pattern {
+                kind: current.kind.next(),
+                ..current
+            },
+            (Some(current), _) => Pattern {
+                kind: current.kind.prev(),
+                ..current
+            },
+            (None, _) => Pattern::new(PatternKind::default(), 0, MAX_INTENSITY),
+        },
+        PatternCommand::Stop => {
+            lights.show_speed(player.stop_motor())?;
+            return Ok(());
+        }
+    };
+
+    let pattern = Pattern {
+        low: pattern.low.min(cap),
+        high: pattern.high.min(cap),
+        ..pattern
+    };
+    player.play(pattern);
+    lights.show_speed(pattern.high)?;
+
+    Ok(())
+}
 
 // rust analyzer gets angry if this is missing /shrug
 fn main() {}
@@ -34,10 +34,12 @@ use crate::idf_libs::led_strip::LedColorFormat;
 use crate::idf_libs::led_strip::LedModel::WS2812;
 use crate::idf_libs::led_strip::LedStrip;
 use crate::idf_libs::led_strip::LedStripConfig;
+use crate::intensity;
 use crate::EspResult;
 
 pub struct Lights<P: Pin> {
     pub led: LedStrip<P>,
+    enabled: bool,
 }
 
 impl<P: Pin> Lights<P> {
@@ -51,10 +53,30 @@ impl<P: Pin> Lights<P> {
             },
         )?;
 
-        Ok(Self { led: strip })
+        Ok(Self {
+            led: strip,
+            enabled: true,
+        })
+    }
+
+    /// Turns the LEDs off (or back on) without losing track of what they should be showing.
+    pub fn set_enabled(&mut self, enabled: bool) -> EspResult<()> {
+        self.enabled = enabled;
+        if !enabled {
+            for idx in 0..4 {
+                self.led.set_pixel(idx, (0, 0, 0))?;
+            }
+            self.led.write()?;
+        }
+
+        Ok(())
     }
 
     pub fn set_all(&mut self, pixels: [(u32, u32, u32); 4]) -> EspResult<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
         for (idx, color) in pixels.into_iter().enumerate() {
             self.led.set_pixel(idx as u32, color)?;
         }
@@ -64,7 +86,31 @@ impl<P: Pin> Lights<P> {
         Ok(())
     }
 
-    pub fn show_speed(&mut self, pwr: u32) -> EspResult<()> {
+    /// All red - the thermal supervisor shut the motor down.
+    pub fn show_overheat(&mut self) -> EspResult<()> {
+        self.set_all([(199, 0, 0); 4])
+    }
+
+    /// Lights `slots` LEDs in purple, for the sleep timer button gesture.
+    pub fn show_timer(&mut self, slots: u32) -> EspResult<()> {
+        let mut pixels = [(0, 0, 0); 4];
+        for pixel in pixels.iter_mut().rev().take(slots as usize) {
+            *pixel = (120, 0, 199);
+        }
+
+        self.set_all(pixels)
+    }
+
+    /// One blue LED chasing around - the pairing window is open. `frame` advances the animation.
+    pub fn show_pairing(&mut self, frame: u64) -> EspResult<()> {
+        let mut pixels = [(0, 0, 20); 4];
+        pixels[(frame % 4) as usize] = (0, 60, 199);
+
+        self.set_all(pixels)
+    }
+
+    pub fn show_speed(&mut self, intensity: u32) -> EspResult<()> {
+        let pwr = intensity::to_steps(intensity, 20);
         if pwr == 0 {
             return self.set_all([(0, 0, 0), (0, 0, 0), (0, 0, 0), (100, 100, 100)]);
         }
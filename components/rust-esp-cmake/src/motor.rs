@@ -1,51 +1,185 @@
-use std::{cmp, ops::Range};
+use std::{
+    cmp,
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
 use esp_idf_hal::ledc::LedcDriver;
+use parking_lot::Mutex;
 
-pub fn map_range(lhs: Range<i64>, rhs: Range<i64>, val: i64) -> i64 {
-    if val == 0 {
-        return 0;
-    }
+use crate::{
+    conf::MotorConfig,
+    intensity::{IntensityMap, MAX_INTENSITY},
+    state::MotorLevel,
+};
+
+/// The actual intensity is tracked in thousandths so slow ramps still move every tick.
+const SUBSTEPS: u32 = 1000;
+const RAMP_TICK: Duration = Duration::from_millis(20);
 
-    rhs.start + ((val - lhs.start) * (rhs.end - rhs.start) / (lhs.end - lhs.start))
+/// Moves `current` towards `target` (both in thousandths of intensity) by at most
+/// `rate` intensity per second, `elapsed_ms` after the last step. A rate of 0 means no limit.
+pub fn approach(current: u32, target: u32, up_rate: u32, down_rate: u32, elapsed_ms: u32) -> u32 {
+    let (rate, distance) = if target >= current {
+        (up_rate, target - current)
+    } else {
+        (down_rate, current - target)
+    };
+
+    let max_delta = rate.saturating_mul(elapsed_ms);
+    if rate == 0 || max_delta >= distance {
+        target
+    } else if target > current {
+        current + max_delta
+    } else {
+        current - max_delta
+    }
 }
 
 pub struct Motor {
     driver: LedcDriver<'static>,
-    duty: u32,
+    map: IntensityMap,
+    target: u32,
+    actual: u32,
+    /// thermal limit on the actual intensity - see [`crate::thermal`]
+    cap: u32,
+    ramp_up: u32,
+    ramp_down: u32,
+    /// one-off ramp down rate from [`Motor::fade_out`], until the next `set`
+    fade: Option<u32>,
+    hard_stop: bool,
 }
 
 impl Motor {
-    pub fn new(driver: LedcDriver<'static>) -> Self {
-        Self { driver, duty: 0 }
+    pub fn new(driver: LedcDriver<'static>, config: &MotorConfig) -> anyhow::Result<Self> {
+        let map = config.intensity_map()?;
+        // ramp rates are configured in steps per second
+        let per_step = MAX_INTENSITY / map.steps();
+
+        Ok(Self {
+            driver,
+            map,
+            target: 0,
+            actual: 0,
+            cap: MAX_INTENSITY,
+            ramp_up: config.ramp_up.saturating_mul(per_step),
+            ramp_down: config.ramp_down.saturating_mul(per_step),
+            fade: None,
+            hard_stop: config.hard_stop,
+        })
     }
 
-    pub fn set(&mut self, power: u32) -> u32 {
-        self.duty = cmp::min(power, 20);
-        let mapped = map_range(0..20, 50..100, self.duty as i64) as u32;
-        self.driver
-            .set_duty(self.driver.get_max_duty() * mapped / 100)
-            .unwrap();
-        self.duty
+    /// Spawns the background task that slews the actual duty towards the target.
+    pub fn start_ramp(motor: Arc<Mutex<Motor>>) -> std::io::Result<JoinHandle<()>> {
+        std::thread::Builder::new()
+            .name("motor-ramp".into())
+            .stack_size(3072)
+            .spawn(move || {
+                let mut last = Instant::now();
+                loop {
+                    std::thread::sleep(RAMP_TICK);
+                    let elapsed = last.elapsed();
+                    last = Instant::now();
+                    motor.lock().step(elapsed.as_millis() as u32);
+                }
+            })
     }
 
-    pub fn get(&self) -> u32 {
-        self.duty
+    /// Sets the target intensity. The duty follows at the configured ramp rate.
+    pub fn set(&mut self, intensity: u32) -> u32 {
+        self.target = cmp::min(intensity, MAX_INTENSITY);
+        self.fade = None;
+        self.step(0);
+        self.target
     }
 
-    pub fn inc(&mut self) -> u32 {
-        if self.duty < 20 {
-            self.set(self.duty + 1)
+    /// Picks up a changed config. The target stays where it is.
+    pub fn reconfigure(&mut self, config: &MotorConfig) -> anyhow::Result<()> {
+        self.map = config.intensity_map()?;
+        let per_step = MAX_INTENSITY / self.map.steps();
+        self.ramp_up = config.ramp_up.saturating_mul(per_step);
+        self.ramp_down = config.ramp_down.saturating_mul(per_step);
+        self.hard_stop = config.hard_stop;
+
+        self.write_duty();
+        Ok(())
+    }
+
+    /// Turns the motor off - skipping the ramp if `hard_stop` is configured.
+    pub fn stop(&mut self) -> u32 {
+        if self.hard_stop {
+            self.target = 0;
+            self.actual = 0;
+            self.write_duty();
+            0
         } else {
-            self.duty
+            self.set(0)
         }
     }
 
-    pub fn dec(&mut self) -> u32 {
-        if self.duty > 0 {
-            self.set(self.duty - 1)
+    /// Ramps down to 0 over `duration`, regardless of the configured ramp rate.
+    pub fn fade_out(&mut self, duration: Duration) {
+        let ms = duration.as_millis() as u32;
+        if ms == 0 {
+            self.stop();
+            return;
+        }
+
+        // intensity per second, rounded up so it's never slower than asked
+        let rate = (self.actual / SUBSTEPS * 1000).div_ceil(ms).max(1);
+        self.set(0);
+        self.fade = Some(rate);
+    }
+
+    /// Limits the actual intensity without touching the target, so it's restored once the cap
+    /// is lifted. A cap of 0 cuts the motor immediately.
+    pub fn set_cap(&mut self, cap: u32) {
+        self.cap = cap;
+        if cap == 0 {
+            self.actual = 0;
+            self.write_duty();
         } else {
-            self.duty
+            self.step(0);
         }
     }
+
+    pub fn get(&self) -> MotorLevel {
+        MotorLevel {
+            target: self.target,
+            actual: self.actual / SUBSTEPS,
+        }
+    }
+
+    /// Moves up one configured step.
+    pub fn inc(&mut self) -> u32 {
+        self.set(self.map.step_up(self.target))
+    }
+
+    /// Moves down one configured step.
+    pub fn dec(&mut self) -> u32 {
+        self.set(self.map.step_down(self.target))
+    }
+
+    fn step(&mut self, elapsed_ms: u32) {
+        let target = self.target.min(self.cap) * SUBSTEPS;
+        if self.actual == target {
+            return;
+        }
+
+        self.actual = approach(
+            self.actual,
+            target,
+            self.ramp_up,
+            self.fade.unwrap_or(self.ramp_down),
+            elapsed_ms,
+        );
+        self.write_duty();
+    }
+
+    fn write_duty(&mut self) {
+        let permille = self.map.duty_permille(self.actual / SUBSTEPS) as u64;
+        let duty = self.driver.get_max_duty() as u64 * permille / 1000;
+        self.driver.set_duty(duty as u32).unwrap();
+    }
 }
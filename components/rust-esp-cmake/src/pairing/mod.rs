@@ -0,0 +1,11 @@
+//! The pairing window, opened with a long press on the stop button.
+//!
+//! With `ble.security.bonded_only` (the default), centrals we haven't bonded with can only
+//! connect, and so bond, while the window is open. The supervisor raises
+//! [`Event::Pairing`](crate::event_queue::Event::Pairing) on every check while it's open, which
+//! the main loop turns into the blue LED chase, and once more when it closes. The window itself
+//! is [`hitachi_core::pairing`].
+
+pub use hitachi_core::pairing::*;
+
+pub mod supervisor;
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use thingbuf::mpsc::blocking::StaticSender;
+
+use crate::{clock::Clocked, event_queue::Event};
+
+use super::{PairingCheck, PairingWindow};
+
+/// Also the LED animation's frame time.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Shared handle to the [`PairingWindow`].
+#[derive(Clone)]
+pub struct PairingHandle {
+    window: Clocked<PairingWindow>,
+    duration: Duration,
+}
+
+impl PairingHandle {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            window: Clocked::new(PairingWindow::default()),
+            duration,
+        }
+    }
+
+    pub fn open(&self) {
+        log::info!("pairing window open for {:?}", self.duration);
+        let duration_ms = self.duration.as_millis() as u64;
+        self.window
+            .with(|window, now_ms| window.open(now_ms, duration_ms));
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.with(|window, now_ms| window.is_open(now_ms))
+    }
+
+    /// Spawns the thread that raises [`Event::Pairing`] every [`CHECK_INTERVAL`] while the window
+    /// is open, and once more when it closes.
+    pub fn spawn_supervisor(
+        &self,
+        events: StaticSender<Event>,
+    ) -> std::io::Result<std::thread::JoinHandle<()>> {
+        let mut was_open = false;
+        self.window
+            .spawn_ticker("pairing", CHECK_INTERVAL, move |window| {
+                let check = window.with(|window, now_ms| window.check(now_ms));
+                match check {
+                    PairingCheck::Open { .. } => {
+                        was_open = true;
+                        let _ = events.try_send(Event::Pairing(check));
+                    }
+                    PairingCheck::Closed if was_open => {
+                        was_open = false;
+                        log::info!("pairing window closed");
+                        // the lights need to go back to normal
+                        let _ = events.send(Event::Pairing(check));
+                    }
+                    PairingCheck::Closed => {}
+                }
+            })
+    }
+}
@@ -0,0 +1,6 @@
+//! Timed motor waveforms. The shapes are in [`hitachi_core::pattern`];
+//! [`player::PatternPlayer`] plays them on the motor.
+
+pub use hitachi_core::pattern::*;
+
+pub mod player;
@@ -0,0 +1,71 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::motor::Motor;
+
+use super::Pattern;
+
+const TICK: Duration = Duration::from_millis(50);
+
+/// Runs the active [`Pattern`] on its own thread, writing levels straight into the [`Motor`].
+pub struct PatternPlayer {
+    active: Arc<Mutex<Option<(Pattern, Instant)>>>,
+    motor: Arc<Mutex<Motor>>,
+}
+
+impl PatternPlayer {
+    pub fn new(motor: Arc<Mutex<Motor>>) -> anyhow::Result<Self> {
+        let active: Arc<Mutex<Option<(Pattern, Instant)>>> = Arc::new(Mutex::new(None));
+
+        let thread_active = Arc::clone(&active);
+        let thread_motor = Arc::clone(&motor);
+        std::thread::Builder::new()
+            .name("pattern".into())
+            .stack_size(4096)
+            .spawn(move || loop {
+                std::thread::sleep(TICK);
+
+                let Some((pattern, started)) = *thread_active.lock() else {
+                    continue;
+                };
+
+                let level = pattern.level_at(started.elapsed().as_millis() as u64);
+                let mut motor = thread_motor.lock();
+                // a stop that landed since the pattern was read must not be undone
+                if *thread_active.lock() != Some((pattern, started)) {
+                    continue;
+                }
+
+                if motor.get().target != level {
+                    motor.set(level);
+                }
+            })?;
+
+        Ok(Self { active, motor })
+    }
+
+    pub fn play(&self, pattern: Pattern) {
+        log::info!("starting pattern {pattern:?}");
+        *self.active.lock() = Some((pattern, Instant::now()));
+    }
+
+    /// Stops the running pattern, leaving the motor at whatever level it was at.
+    /// Returns whether a pattern was running.
+    pub fn stop(&self) -> bool {
+        self.active.lock().take().is_some()
+    }
+
+    /// Stops the running pattern and turns the motor off.
+    pub fn stop_motor(&self) -> u32 {
+        self.stop();
+        self.motor.lock().stop()
+    }
+
+    pub fn current(&self) -> Option<Pattern> {
+        self.active.lock().map(|(pattern, _)| pattern)
+    }
+}
@@ -0,0 +1,6 @@
+//! Inactivity auto-off and the user-set sleep timer. The logic is in [`hitachi_core::sleep`];
+//! [`supervisor::SleepHandle`] clocks it and raises [`crate::event_queue::Event::Sleep`].
+
+pub use hitachi_core::sleep::*;
+
+pub mod supervisor;
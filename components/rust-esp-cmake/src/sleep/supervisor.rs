@@ -0,0 +1,73 @@
+use std::{sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+use thingbuf::mpsc::blocking::StaticSender;
+
+use crate::{clock::Clocked, event_queue::Event, motor::Motor};
+
+use super::{SleepCheck, SleepTimer};
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Shared handle to the [`SleepTimer`].
+#[derive(Clone)]
+pub struct SleepHandle {
+    timer: Clocked<SleepTimer>,
+}
+
+impl SleepHandle {
+    pub fn new(idle_timeout: Option<Duration>) -> Self {
+        Self {
+            timer: Clocked::new(SleepTimer::new(idle_timeout.map(|d| d.as_millis() as u64))),
+        }
+    }
+
+    /// Call on any user input.
+    pub fn touch(&self) {
+        self.timer.with(|timer, now_ms| timer.touch(now_ms));
+    }
+
+    pub fn set_idle_timeout(&self, idle_timeout: Option<Duration>) {
+        self.timer
+            .with(|timer, _| timer.set_idle_timeout(idle_timeout.map(|d| d.as_millis() as u64)));
+    }
+
+    pub fn set_timer(&self, duration: Duration) {
+        self.timer
+            .with(|timer, now_ms| timer.set_timer(now_ms, duration.as_millis() as u64));
+    }
+
+    pub fn cancel_timer(&self) -> bool {
+        self.timer.with(|timer, _| timer.cancel_timer())
+    }
+
+    pub fn remaining(&self) -> Option<Duration> {
+        self.timer
+            .with(|timer, now_ms| timer.remaining(now_ms))
+            .map(Duration::from_millis)
+    }
+
+    /// Spawns the thread that raises [`Event::Sleep`] when the timer is about to run or has run out.
+    pub fn spawn_supervisor(
+        &self,
+        motor: Arc<Mutex<Motor>>,
+        events: StaticSender<Event>,
+    ) -> std::io::Result<std::thread::JoinHandle<()>> {
+        self.timer
+            .spawn_ticker("sleep", CHECK_INTERVAL, move |timer| {
+                let running = motor.lock().get().target > 0;
+                let check = timer.with(|timer, now_ms| timer.check(now_ms, running));
+                match check {
+                    SleepCheck::Idle => {}
+                    SleepCheck::Warning { .. } => {
+                        let _ = events.try_send(Event::Sleep(check));
+                    }
+                    SleepCheck::Expired => {
+                        log::info!("sleep timer expired, turning off");
+                        // this one must not get dropped on a full queue
+                        let _ = events.send(Event::Sleep(check));
+                    }
+                }
+            })
+    }
+}
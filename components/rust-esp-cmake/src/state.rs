@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::motor::Motor;
+
+pub use hitachi_core::state::*;
+
+/// Shared handle other threads use to publish and read [`DeviceState`].
+#[derive(Clone)]
+pub struct StateHandle {
+    state: Arc<Mutex<DeviceState>>,
+    motor: Arc<Mutex<Motor>>,
+}
+
+impl StateHandle {
+    pub fn new(motor: Arc<Mutex<Motor>>) -> Self {
+        Self {
+            state: Arc::default(),
+            motor,
+        }
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut DeviceState)) {
+        f(&mut self.state.lock());
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            motor: self.motor.lock().get(),
+            device: *self.state.lock(),
+        }
+    }
+}
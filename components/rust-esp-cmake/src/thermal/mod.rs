@@ -0,0 +1,6 @@
+//! Thermal derating. The policy is [`hitachi_core::thermal`]; [`supervisor::ThermalSupervisor`]
+//! feeds it thermistor readings and caps the motor.
+
+pub use hitachi_core::thermal::*;
+
+pub mod supervisor;
@@ -0,0 +1,113 @@
+use std::{sync::Arc, time::Duration};
+
+use esp_idf_hal::{gpio::ADCPin, temp_sensor::TempSensorDriver};
+use parking_lot::Mutex;
+use thingbuf::mpsc::blocking::StaticSender;
+
+use crate::{
+    conf::ThermalConfig, event_queue::Event, idf_libs::ntc::Thermistor, motor::Motor,
+    state::StateHandle,
+};
+
+use super::{ThermalGuard, ThermalState};
+
+/// Polls the thermistor and caps the motor according to [`ThermalGuard`]. The chip's own
+/// sensor is read alongside it for telemetry only.
+///
+/// The cap is applied to the motor directly so an overheat never waits on a full event queue;
+/// [`Event::Thermal`] is only raised so lights and remote clients can show the change.
+pub struct ThermalSupervisor<P: ADCPin> {
+    ntc: Arc<Mutex<Thermistor<P>>>,
+    chip_sensor: TempSensorDriver<'static>,
+    motor: Arc<Mutex<Motor>>,
+    events: StaticSender<Event>,
+    state: StateHandle,
+    guard: ThermalGuard,
+    poll_interval: Duration,
+}
+
+impl<P: ADCPin + Send + 'static> ThermalSupervisor<P> {
+    pub fn new(
+        ntc: Arc<Mutex<Thermistor<P>>>,
+        chip_sensor: TempSensorDriver<'static>,
+        motor: Arc<Mutex<Motor>>,
+        events: StaticSender<Event>,
+        state: StateHandle,
+        config: &ThermalConfig,
+    ) -> Self {
+        Self {
+            ntc,
+            chip_sensor,
+            motor,
+            events,
+            state,
+            guard: ThermalGuard::new(
+                config.soft_limit_c,
+                config.hard_limit_c,
+                config.hysteresis_c,
+            ),
+            poll_interval: Duration::from_millis(config.poll_interval_ms as u64),
+        }
+    }
+
+    pub fn spawn(self) -> std::io::Result<std::thread::JoinHandle<()>> {
+        std::thread::Builder::new()
+            .name("thermal".into())
+            .stack_size(4096)
+            .spawn(move || self.run())
+    }
+
+    fn run(mut self) {
+        let mut state = ThermalState::Normal;
+
+        loop {
+            std::thread::sleep(self.poll_interval);
+
+            let chip_temp = self.chip_sensor.get_celsius().ok();
+            self.state.update(|s| s.chip_temp_c = chip_temp);
+
+            let reading = self.ntc.lock().get_temp();
+            let (temp, new_state) = match reading {
+                Ok(temp) => (Some(temp), self.guard.update(temp)),
+                Err(e) => {
+                    log::error!("failed to read motor temperature: {e}");
+                    (None, self.guard.read_failed())
+                }
+            };
+
+            self.state.update(|s| {
+                s.motor_temp_c = temp;
+                s.thermal = new_state;
+            });
+            if new_state == state {
+                continue;
+            }
+
+            match (new_state, temp) {
+                (ThermalState::Shutdown, Some(temp)) => {
+                    log::error!("motor at {temp:.1}°C - shutting down!")
+                }
+                (ThermalState::Shutdown, None) => {
+                    log::error!("can't read the motor temperature - shutting down!")
+                }
+                (ThermalState::Derating { cap }, Some(temp)) => {
+                    log::warn!("motor at {temp:.1}°C - capping intensity to {cap}")
+                }
+                (ThermalState::Normal, Some(temp)) => {
+                    log::info!("motor back to {temp:.1}°C - uncapped")
+                }
+                // until it gives up, a failed read keeps the last state
+                (_, None) => {}
+            }
+
+            self.motor.lock().set_cap(new_state.cap());
+
+            // derating caps change every reading while hot, only bother the queue on transitions
+            if std::mem::discriminant(&new_state) != std::mem::discriminant(&state) {
+                let _ = self.events.try_send(Event::Thermal(new_state));
+            }
+
+            state = new_state;
+        }
+    }
+}
@@ -224,10 +224,245 @@ cfg.add_menu(
             50,
             description="Lowest power level available",
         ),
+        "steps": RangeInput(
+            "Number of button steps (1-1000)",
+            (1, 1000),
+            20,
+            description="How many presses from off to full power",
+            display_name="Steps",
+        ),
+        "ramp_up": RangeInput(
+            "Acceleration limit (levels per second, 0 = instant)",
+            (0, 1000),
+            20,
+            description="How fast the motor speeds up",
+            display_name="Ramp up",
+        ),
+        "ramp_down": RangeInput(
+            "Deceleration limit (levels per second, 0 = instant)",
+            (0, 1000),
+            40,
+            description="How fast the motor slows down",
+            display_name="Ramp down",
+        ),
+        "hard_stop": BoolInput(
+            "Stop immediately instead of ramping down?",
+            display_name="Hard stop",
+        ),
     },
     display_name="Motor"
 )
 
+cfg.add_menu(
+    "thermal",
+    "Thermal protection",
+    {
+        "soft_limit_c": RangeInput(
+            "Start limiting power above (°C)",
+            (20, 120),
+            60,
+            description="Power is capped linearly above this",
+            display_name="Soft limit",
+        ),
+        "hard_limit_c": RangeInput(
+            "Shut the motor down at (°C)",
+            (20, 120),
+            75,
+            description="Motor turns off until it cools down",
+            display_name="Hard limit",
+        ),
+        "hysteresis_c": RangeInput(
+            "Cool down by (°C) before re-enabling",
+            (1, 50),
+            10,
+            description="Hysteresis after a shutdown",
+            display_name="Hysteresis",
+        ),
+        "poll_interval_ms": StrInput("Poll interval (ms)", description="How often the thermistor is read", as_int=True),
+    },
+    display_name="Thermal"
+)
+
+cfg.add_menu(
+    "sleep",
+    "Auto-off",
+    {
+        "idle_timeout_min": RangeInput(
+            "Turn off after how many minutes without input? (0 = never)",
+            (0, 240),
+            30,
+            description="Inactivity auto-off",
+            display_name="Idle timeout",
+        ),
+    },
+    display_name="Auto-off"
+)
+
+cfg.add_menu(
+    "ble",
+    "Bluetooth identity",
+    {
+        "name": StrInput("Advertised name", description="(empty = profile default)"),
+        "broadcast_state": BoolInput(
+            "Broadcast state in the scan response?",
+            description="Intensity, temperature and faults without connecting",
+            default=False,
+        ),
+        "profile": Menu(
+            "Protocol profile",
+            {
+                "type": RadioList(
+                    "Protocol",
+                    [
+                        ("lovense", "Lovense"),
+                        ("wevibe", "WeVibe"),
+                        ("magic_motion", "Magic Motion"),
+                        ("kiiroo", "Kiiroo"),
+                    ],
+                    default="lovense",
+                ),
+                "model": StrInput("Lovense model letter", description="(lovense only, e.g. H)"),
+            },
+            display_name="Profile",
+        ),
+        "failsafe": Menu(
+            "Link-loss failsafe",
+            {
+                "link_loss": Menu(
+                    "When the controlling app disconnects",
+                    {
+                        "action": RadioList(
+                            "Action",
+                            [
+                                ("stop", "Stop the motor"),
+                                ("ramp_down", "Ramp down"),
+                                ("keep", "Keep running"),
+                            ],
+                            default="ramp_down",
+                        ),
+                        "secs": RangeInput(
+                            "Ramp down over how many seconds?",
+                            (0, 60),
+                            3,
+                            description="(ramp_down only)",
+                            display_name="Ramp down time",
+                        ),
+                    },
+                    display_name="On link loss",
+                ),
+                "heartbeat_timeout_s": RangeInput(
+                    "Treat a silent app as lost after how many seconds? (0 = never)",
+                    (0, 600),
+                    0,
+                    description="Heartbeat timeout",
+                    display_name="Heartbeat timeout",
+                ),
+            },
+            display_name="Failsafe",
+        ),
+        "security": Menu(
+            "Pairing and security",
+            {
+                "passkey": Menu(
+                    "Pairing passkey",
+                    {
+                        "type": RadioList(
+                            "Passkey",
+                            [
+                                ("static", "Fixed passkey"),
+                                ("random", "New random passkey every boot (shown in the log)"),
+                            ],
+                            default="static",
+                        ),
+                        "value": StrInput("Fixed passkey (6 digits)", description="(static only)", as_int=True),
+                    },
+                    display_name="Passkey",
+                ),
+                "io_cap": RadioList(
+                    "How pairing is confirmed",
+                    [
+                        ("no_input_no_output", "Just works (no passkey)"),
+                        ("display_only", "Passkey from the log"),
+                        ("display_yes_no", "Display with yes/no"),
+                        ("keyboard_only", "Keyboard"),
+                        ("keyboard_display", "Keyboard and display"),
+                    ],
+                    default="no_input_no_output",
+                    display_name="IO capability",
+                ),
+                "bonded_only": BoolInput("Only accept bonded devices?", default=True),
+                "pairing_window_s": RangeInput(
+                    "Keep the pairing window open for how many seconds?",
+                    (10, 600),
+                    120,
+                    description="Opened by holding the stop button",
+                    display_name="Pairing window",
+                ),
+                "nus": RadioList(
+                    "Console (NUS) requires",
+                    [
+                        ("open", "Nothing"),
+                        ("encrypted", "Pairing"),
+                        ("authenticated", "Pairing with passkey"),
+                    ],
+                    default="encrypted",
+                    display_name="Console security",
+                ),
+                "control": RadioList(
+                    "Toy control requires",
+                    [
+                        ("open", "Nothing"),
+                        ("encrypted", "Pairing"),
+                        ("authenticated", "Pairing with passkey"),
+                    ],
+                    default="open",
+                    display_name="Control security",
+                ),
+            },
+            display_name="Security",
+        ),
+        "hid_remote": Menu(
+            "Media remote / presentation clicker",
+            {
+                "enable": BoolInput("Connect to a BLE remote?", default=False),
+                "address": StrInput(
+                    "Remote address",
+                    description="(aa:bb:cc:dd:ee:ff, empty = pair while the pairing window is open)",
+                ),
+            },
+            display_name="HID remote",
+        ),
+    },
+    display_name="Bluetooth"
+)
+
+cfg.add_menu(
+    "control",
+    "Remote control",
+    {
+        "remote_max_intensity": RangeInput(
+            "Highest intensity remote apps may set (0-1000)",
+            (0, 1000),
+            1000,
+            description="The buttons can always go to full power",
+            display_name="Remote max",
+        ),
+    },
+    display_name="Remote control"
+)
+
+cfg.add_menu(
+    "http",
+    "HTTP",
+    {
+        "admin_token": StrInput(
+            "Admin token",
+            description="Needed to change the config or the web panel over HTTP (8+ characters, empty = not allowed)",
+        ),
+    },
+    display_name="HTTP"
+)
+
 cfg.add_menu(
     "remote_log",
     "Network Logging Options",
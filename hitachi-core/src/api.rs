@@ -0,0 +1,644 @@
+//! JSON schema for the HTTP control API.
+//!
+//! | method | path             | body                 | response               |
+//! |--------|------------------|----------------------|------------------------|
+//! | GET    | `/api/state`     |                      | [`StateResponse`]      |
+//! | PUT    | `/api/intensity` | [`IntensityRequest`] | 202 + [`Accepted`]     |
+//! | POST   | `/api/pattern`   | [`PatternRequest`]   | 202 + [`Accepted`]     |
+//! | POST   | `/api/stop`      |                      | 202 + [`Accepted`]     |
+//! | GET    | `/config`        |                      | the config, redacted   |
+//! | PATCH  | `/config`        | a JSON merge patch   | [`ConfigSaved`]        |
+//! | PUT    | `/www/<path>`    | the file             | 201 + [`AssetSaved`]   |
+//! | DELETE | `/www/<path>`    |                      | 204                    |
+//!
+//! Changing the config or the web panel takes the `http.admin_token` from the config, as
+//! `Authorization: Bearer <token>` - see [`authorize`]. Everything else is open to the LAN.
+//!
+//! Commands are queued like every other input, so a 202 only means the wand got it - whether
+//! it was obeyed (see [`crate::arbiter`]) shows up in the next `/api/state`. Every error is an
+//! [`ApiError`]: `{"error": "out_of_range", "message": "..."}`.
+//!
+//! Secrets in `GET /config` read `"********"`; sending that back unchanged keeps them. See the
+//! firmware's `Config::patched` for how patches work.
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    intensity::MAX_INTENSITY,
+    pattern::{Pattern, PatternCommand},
+    state::{Mode, Snapshot},
+    thermal::ThermalState,
+};
+
+/// Command bodies are tiny, anything bigger than this is a mistake.
+pub const MAX_BODY_LEN: usize = 512;
+pub const MAX_CONFIG_LEN: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: u16,
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: u16, error: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(400, "bad_request", message)
+    }
+
+    pub fn out_of_range(message: impl Into<String>) -> Self {
+        Self::new(422, "out_of_range", message)
+    }
+
+    /// Well-formed, but doesn't make sense.
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::new(422, "invalid", message)
+    }
+
+    pub fn not_found(path: &str) -> Self {
+        Self::new(404, "not_found", format!("no such endpoint: {path}"))
+    }
+
+    pub fn too_large(limit: usize) -> Self {
+        Self::new(
+            413,
+            "too_large",
+            format!("the request body is limited to {limit} bytes"),
+        )
+    }
+
+    /// Bodies are read up to their `Content-Length`, so one without it can't be taken.
+    pub fn length_required() -> Self {
+        Self::new(411, "length_required", "the request needs a Content-Length")
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::new(
+            401,
+            "unauthorized",
+            "this needs the admin token, as Authorization: Bearer <token>",
+        )
+    }
+
+    /// There's no admin token, so nothing that needs one is allowed.
+    pub fn no_admin_token() -> Self {
+        Self::new(
+            403,
+            "forbidden",
+            "set http.admin_token from the console to allow this over HTTP",
+        )
+    }
+
+    /// The event queue was full.
+    pub fn busy() -> Self {
+        Self::new(503, "busy", "the wand is busy, try again")
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(500, "internal", message)
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.error, self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::bad_request(format!("invalid JSON: {e}"))
+    }
+}
+
+/// Checks an `Authorization` header against the admin token. An empty token allows nothing.
+pub fn authorize(header: Option<&str>, admin_token: &str) -> Result<(), ApiError> {
+    if admin_token.is_empty() {
+        return Err(ApiError::no_admin_token());
+    }
+
+    let given = header
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or_else(ApiError::unauthorized)?;
+
+    // the same time for every wrong token of the right length
+    let same = given.len() == admin_token.len()
+        && given
+            .bytes()
+            .zip(admin_token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if !same {
+        return Err(ApiError::unauthorized());
+    }
+
+    Ok(())
+}
+
+/// Body of a successful command.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Accepted {
+    pub accepted: bool,
+}
+
+impl Accepted {
+    pub const YES: Accepted = Accepted { accepted: true };
+}
+
+/// Body of a successful config change: the dotted paths of the settings that changed, e.g.
+/// `motor.ramp_up`. Both are empty if nothing did.
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct ConfigSaved {
+    /// already in effect
+    pub applied: Vec<String>,
+    /// saved, but only take effect after a restart
+    pub restart_required: Vec<String>,
+}
+
+/// Body of a successful asset upload.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AssetSaved {
+    pub path: String,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IntensityRequest {
+    /// target intensity, 0..=1000
+    pub level: u32,
+}
+
+impl IntensityRequest {
+    pub fn decode(body: &[u8]) -> Result<u32, ApiError> {
+        let request: IntensityRequest = serde_json::from_slice(body)?;
+        check_level("level", request.level)?;
+        Ok(request.level)
+    }
+}
+
+/// `{"command": "start", "pattern": "wave", "period_ms": 2000, "low": 0, "high": 1000}`, with
+/// everything but `pattern` optional, or `{"command": "next" | "previous" | "stop"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PatternRequest {
+    Start {
+        pattern: String,
+        period_ms: Option<u32>,
+        low: Option<u32>,
+        high: Option<u32>,
+    },
+    // braces, so unknown fields are rejected for these too
+    Next {},
+    Previous {},
+    Stop {},
+}
+
+impl PatternRequest {
+    pub fn decode(body: &[u8]) -> Result<PatternCommand, ApiError> {
+        let request: PatternRequest = serde_json::from_slice(body)?;
+        request.into_command()
+    }
+
+    pub fn into_command(self) -> Result<PatternCommand, ApiError> {
+        let (pattern, period_ms, low, high) = match self {
+            PatternRequest::Next {} => return Ok(PatternCommand::Next),
+            PatternRequest::Previous {} => return Ok(PatternCommand::Previous),
+            PatternRequest::Stop {} => return Ok(PatternCommand::Stop),
+            PatternRequest::Start {
+                pattern,
+                period_ms,
+                low,
+                high,
+            } => (pattern, period_ms, low, high),
+        };
+
+        let kind = pattern
+            .parse()
+            .map_err(|e: anyhow::Error| ApiError::new(422, "unknown_pattern", e.to_string()))?;
+        let low = check_level("low", low.unwrap_or(0))?;
+        let high = check_level("high", high.unwrap_or(MAX_INTENSITY))?;
+        let period_ms = match period_ms {
+            Some(0) => return Err(ApiError::out_of_range("period_ms must be positive")),
+            Some(period_ms) => period_ms,
+            None => Pattern::DEFAULT_PERIOD_MS,
+        };
+
+        Ok(PatternCommand::Start(
+            Pattern::new(kind, low, high).with_period(period_ms),
+        ))
+    }
+}
+
+fn check_level(field: &str, level: u32) -> Result<u32, ApiError> {
+    if level > MAX_INTENSITY {
+        return Err(ApiError::out_of_range(format!(
+            "{field} must be between 0 and {MAX_INTENSITY}, got {level}"
+        )));
+    }
+
+    Ok(level)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StateResponse {
+    /// `off`, `manual` or `pattern`
+    pub mode: &'static str,
+    pub intensity: IntensityState,
+    pub pattern: Option<PatternState>,
+    pub temperature: TemperatureState,
+    pub thermal: ThermalResponse,
+    /// any of `derating`, `overheat`, `no_temperature`
+    pub faults: Vec<&'static str>,
+    pub control: ControlState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct IntensityState {
+    pub target: u32,
+    pub actual: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PatternState {
+    pub pattern: &'static str,
+    pub period_ms: u32,
+    pub low: u32,
+    pub high: u32,
+}
+
+/// Degrees Celsius, `null` while unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TemperatureState {
+    pub motor_c: Option<f32>,
+    pub chip_c: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ThermalResponse {
+    /// `normal`, `derating` or `shutdown`
+    pub state: &'static str,
+    /// highest intensity the motor may currently run at
+    pub cap: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ControlState {
+    /// `buttons`, `ble`, `http`, `console`, or `null` if nobody is driving the motor
+    pub owner: Option<&'static str>,
+    /// remote control was paused from the buttons
+    pub paused: bool,
+    pub remote_cap: u32,
+}
+
+impl From<&Snapshot> for StateResponse {
+    fn from(snapshot: &Snapshot) -> Self {
+        let device = &snapshot.device;
+
+        let mut faults = Vec::new();
+        let thermal = match device.thermal {
+            ThermalState::Normal => "normal",
+            ThermalState::Derating { .. } => {
+                faults.push("derating");
+                "derating"
+            }
+            ThermalState::Shutdown => {
+                faults.push("overheat");
+                "shutdown"
+            }
+        };
+        if device.motor_temp_c.is_none() {
+            faults.push("no_temperature");
+        }
+
+        StateResponse {
+            mode: match snapshot.mode() {
+                Mode::Off => "off",
+                Mode::Manual => "manual",
+                Mode::Pattern => "pattern",
+            },
+            intensity: IntensityState {
+                target: snapshot.motor.target,
+                actual: snapshot.motor.actual,
+            },
+            pattern: device.pattern.map(|p| PatternState {
+                pattern: p.kind.name(),
+                period_ms: p.period_ms,
+                low: p.low,
+                high: p.high,
+            }),
+            temperature: TemperatureState {
+                motor_c: device.motor_temp_c,
+                chip_c: device.chip_temp_c,
+            },
+            thermal: ThermalResponse {
+                state: thermal,
+                cap: device.thermal.cap(),
+            },
+            faults,
+            control: ControlState {
+                owner: device.control.owner().map(|o| o.name()),
+                paused: device.control.paused(),
+                remote_cap: device.control.remote_cap(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        arbiter::{Arbiter, Source},
+        pattern::PatternKind,
+        state::{DeviceState, MotorLevel},
+    };
+
+    fn to_json(value: &impl Serialize) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    fn error_kind(result: Result<impl std::fmt::Debug, ApiError>) -> (u16, &'static str) {
+        let e = result.unwrap_err();
+        (e.status, e.error)
+    }
+
+    #[test]
+    fn intensity() {
+        assert_eq!(IntensityRequest::decode(br#"{"level": 420}"#), Ok(420));
+        assert_eq!(IntensityRequest::decode(br#"{"level": 1000}"#), Ok(1000));
+
+        assert_eq!(
+            error_kind(IntensityRequest::decode(br#"{"level": 1001}"#)),
+            (422, "out_of_range")
+        );
+        assert_eq!(
+            error_kind(IntensityRequest::decode(br#"{"level": -1}"#)),
+            (400, "bad_request")
+        );
+        assert_eq!(
+            error_kind(IntensityRequest::decode(br#"{"level": 1, "speed": 2}"#)),
+            (400, "bad_request")
+        );
+        assert_eq!(
+            error_kind(IntensityRequest::decode(b"{")),
+            (400, "bad_request")
+        );
+    }
+
+    #[test]
+    fn intensity_round_trip() {
+        let request = IntensityRequest { level: 250 };
+        let body = serde_json::to_vec(&request).unwrap();
+
+        assert_eq!(to_json(&request), json!({"level": 250}));
+        assert_eq!(
+            serde_json::from_slice::<IntensityRequest>(&body).unwrap(),
+            request
+        );
+        assert_eq!(IntensityRequest::decode(&body), Ok(250));
+    }
+
+    #[test]
+    fn pattern_start() {
+        assert_eq!(
+            PatternRequest::decode(br#"{"command": "start", "pattern": "wave"}"#),
+            Ok(PatternCommand::Start(Pattern::new(
+                PatternKind::Wave,
+                0,
+                MAX_INTENSITY
+            )))
+        );
+        assert_eq!(
+            PatternRequest::decode(
+                br#"{"command": "start", "pattern": "Stairs", "period_ms": 500, "low": 100, "high": 800}"#
+            ),
+            Ok(PatternCommand::Start(
+                Pattern::new(PatternKind::Stairs, 100, 800).with_period(500)
+            ))
+        );
+    }
+
+    #[test]
+    fn pattern_commands() {
+        assert_eq!(
+            PatternRequest::decode(br#"{"command": "next"}"#),
+            Ok(PatternCommand::Next)
+        );
+        assert_eq!(
+            PatternRequest::decode(br#"{"command": "previous"}"#),
+            Ok(PatternCommand::Previous)
+        );
+        assert_eq!(
+            PatternRequest::decode(br#"{"command": "stop"}"#),
+            Ok(PatternCommand::Stop)
+        );
+    }
+
+    #[test]
+    fn pattern_errors() {
+        let decode = |body: &str| error_kind(PatternRequest::decode(body.as_bytes()));
+
+        assert_eq!(
+            decode(r#"{"command": "start", "pattern": "zigzag"}"#),
+            (422, "unknown_pattern")
+        );
+        assert_eq!(
+            decode(r#"{"command": "start", "pattern": "wave", "high": 1001}"#),
+            (422, "out_of_range")
+        );
+        assert_eq!(
+            decode(r#"{"command": "start", "pattern": "wave", "period_ms": 0}"#),
+            (422, "out_of_range")
+        );
+        assert_eq!(decode(r#"{"command": "start"}"#), (400, "bad_request"));
+        assert_eq!(decode(r#"{"command": "faster"}"#), (400, "bad_request"));
+        assert_eq!(
+            decode(r#"{"command": "stop", "pattern": "wave"}"#),
+            (400, "bad_request")
+        );
+    }
+
+    #[test]
+    fn pattern_round_trip() {
+        let requests = [
+            PatternRequest::Start {
+                pattern: "ramp".into(),
+                period_ms: Some(1500),
+                low: Some(200),
+                high: None,
+            },
+            PatternRequest::Start {
+                pattern: "pulse".into(),
+                period_ms: None,
+                low: None,
+                high: None,
+            },
+            PatternRequest::Next {},
+            PatternRequest::Previous {},
+            PatternRequest::Stop {},
+        ];
+
+        for request in requests {
+            let body = serde_json::to_vec(&request).unwrap();
+            assert_eq!(
+                serde_json::from_slice::<PatternRequest>(&body).unwrap(),
+                request
+            );
+        }
+
+        assert_eq!(
+            to_json(&PatternRequest::Next {}),
+            json!({"command": "next"})
+        );
+    }
+
+    #[test]
+    fn errors_leave_the_status_out() {
+        assert_eq!(
+            to_json(&ApiError::not_found("/api/nope")),
+            json!({"error": "not_found", "message": "no such endpoint: /api/nope"})
+        );
+        assert_eq!(ApiError::too_large(MAX_BODY_LEN).status, 413);
+        assert_eq!(ApiError::length_required().status, 411);
+        assert_eq!(ApiError::busy().status, 503);
+    }
+
+    #[test]
+    fn bodies() {
+        assert_eq!(to_json(&Accepted::YES), json!({"accepted": true}));
+        assert_eq!(
+            to_json(&ConfigSaved {
+                applied: vec!["motor.ramp_up".into()],
+                restart_required: vec![],
+            }),
+            json!({"applied": ["motor.ramp_up"], "restart_required": []})
+        );
+        assert_eq!(
+            to_json(&AssetSaved {
+                path: "app.js".into(),
+                size: 1234,
+            }),
+            json!({"path": "app.js", "size": 1234})
+        );
+    }
+
+    #[test]
+    fn idle_state() {
+        let snapshot = Snapshot {
+            motor: MotorLevel {
+                target: 0,
+                actual: 0,
+            },
+            device: DeviceState::default(),
+        };
+
+        assert_eq!(
+            to_json(&StateResponse::from(&snapshot)),
+            json!({
+                "mode": "off",
+                "intensity": {"target": 0, "actual": 0},
+                "pattern": null,
+                "temperature": {"motor_c": null, "chip_c": null},
+                "thermal": {"state": "normal", "cap": 1000},
+                "faults": ["no_temperature"],
+                "control": {"owner": null, "paused": false, "remote_cap": 1000},
+            })
+        );
+    }
+
+    #[test]
+    fn running_state() {
+        let mut control = Arbiter::new(700);
+        control.request(Source::Http);
+        let snapshot = Snapshot {
+            motor: MotorLevel {
+                target: 600,
+                actual: 450,
+            },
+            device: DeviceState {
+                motor_temp_c: Some(65.5),
+                chip_temp_c: Some(40.0),
+                thermal: ThermalState::Derating { cap: 700 },
+                pattern: Some(Pattern::new(PatternKind::Wave, 100, 900).with_period(3000)),
+                control,
+            },
+        };
+
+        assert_eq!(
+            to_json(&StateResponse::from(&snapshot)),
+            json!({
+                "mode": "pattern",
+                "intensity": {"target": 600, "actual": 450},
+                "pattern": {"pattern": "wave", "period_ms": 3000, "low": 100, "high": 900},
+                "temperature": {"motor_c": 65.5, "chip_c": 40.0},
+                "thermal": {"state": "derating", "cap": 700},
+                "faults": ["derating"],
+                "control": {"owner": "http", "paused": false, "remote_cap": 700},
+            })
+        );
+
+        let snapshot = Snapshot {
+            device: DeviceState {
+                thermal: ThermalState::Shutdown,
+                pattern: None,
+                ..snapshot.device
+            },
+            ..snapshot
+        };
+        let state = StateResponse::from(&snapshot);
+        assert_eq!(state.mode, "manual");
+        assert_eq!(state.thermal.state, "shutdown");
+        assert_eq!(state.faults, ["overheat"]);
+    }
+
+    #[test]
+    fn admin_token() {
+        assert_eq!(authorize(Some("Bearer s3cret"), "s3cret"), Ok(()));
+        assert_eq!(authorize(Some("Bearer  s3cret "), "s3cret"), Ok(()));
+
+        for header in [
+            None,
+            Some(""),
+            Some("s3cret"),
+            Some("Bearer "),
+            Some("Bearer s3cre"),
+            Some("Bearer s3cret2"),
+            Some("Bearer S3CRET"),
+            Some("Basic s3cret"),
+        ] {
+            assert_eq!(
+                authorize(header, "s3cret").map_err(|e| e.status),
+                Err(401),
+                "{header:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn no_admin_token_allows_nothing() {
+        for header in [None, Some(""), Some("Bearer "), Some("Bearer anything")] {
+            assert_eq!(
+                authorize(header, "").map_err(|e| e.status),
+                Err(403),
+                "{header:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Decides which input source gets to drive the motor.
+//!
+//! The physical buttons always win: touching them while a remote is in control takes over until
+//! the buttons stop the motor, and stopping from the buttons pauses remote control altogether
+//! until the stop button is pressed again. Remote sources (BLE, HTTP, the console) are treated
+//! as equals - the last one to send a command owns the motor - and are capped to a configurable
+//! maximum intensity. Stopping is always allowed, from anywhere, but a remote stopping the motor
+//! doesn't hand it back from the buttons.
+
+use std::fmt::Display;
+
+use crate::intensity::MAX_INTENSITY;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Buttons,
+    Ble,
+    Http,
+    Console,
+}
+
+impl Source {
+    pub fn is_remote(self) -> bool {
+        self != Source::Buttons
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Buttons => "buttons",
+            Source::Ble => "ble",
+            Source::Http => "http",
+            Source::Console => "console",
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// go ahead, with intensities limited to `cap`
+    Allow { cap: u32 },
+    /// the buttons have taken over
+    Denied { owner: Source },
+    /// remote control was paused from the buttons
+    Paused,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arbiter {
+    remote_cap: u32,
+    owner: Option<Source>,
+    paused: bool,
+}
+
+impl Arbiter {
+    pub fn new(remote_cap: u32) -> Self {
+        Self {
+            remote_cap: remote_cap.min(MAX_INTENSITY),
+            owner: None,
+            paused: false,
+        }
+    }
+
+    /// A command from `source` that would start or change the motor.
+    pub fn request(&mut self, source: Source) -> Verdict {
+        if !source.is_remote() {
+            self.owner = Some(source);
+            return Verdict::Allow { cap: MAX_INTENSITY };
+        }
+
+        if self.paused {
+            return Verdict::Paused;
+        }
+
+        match self.owner {
+            Some(owner) if !owner.is_remote() => Verdict::Denied { owner },
+            _ => {
+                self.owner = Some(source);
+                Verdict::Allow {
+                    cap: self.remote_cap,
+                }
+            }
+        }
+    }
+
+    /// A stop from `source`. Always honoured. From the buttons, it pauses remote control if a
+    /// remote was driving the motor, and resumes it if it was already paused. From a remote, it
+    /// leaves the buttons in control if they have it.
+    pub fn stop(&mut self, source: Source) {
+        if source.is_remote() {
+            if self.owner.is_some_and(Source::is_remote) {
+                self.owner = None;
+            }
+            return;
+        }
+
+        if self.paused {
+            self.paused = false;
+        } else if self.owner.is_some_and(Source::is_remote) {
+            self.paused = true;
+        }
+        self.owner = None;
+    }
+
+    /// The motor was stopped by something other than an input source (sleep timer, failsafe).
+    pub fn release(&mut self) {
+        self.owner = None;
+    }
+
+    pub fn owner(&self) -> Option<Source> {
+        self.owner
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn remote_cap(&self) -> u32 {
+        self.remote_cap
+    }
+
+    /// Only limits later commands - a remote already above the new cap keeps its level.
+    pub fn set_remote_cap(&mut self, remote_cap: u32) {
+        self.remote_cap = remote_cap.min(MAX_INTENSITY);
+    }
+}
+
+impl Default for Arbiter {
+    fn default() -> Self {
+        Self::new(MAX_INTENSITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_REMOTES: [Source; 3] = [Source::Ble, Source::Http, Source::Console];
+
+    #[test]
+    fn remotes_take_turns_under_the_cap() {
+        let mut arbiter = Arbiter::new(600);
+
+        assert_eq!(arbiter.request(Source::Ble), Verdict::Allow { cap: 600 });
+        assert_eq!(arbiter.owner(), Some(Source::Ble));
+        assert_eq!(arbiter.request(Source::Http), Verdict::Allow { cap: 600 });
+        assert_eq!(arbiter.owner(), Some(Source::Http));
+
+        arbiter.stop(Source::Console);
+        assert_eq!(arbiter.owner(), None);
+        assert!(!arbiter.paused());
+    }
+
+    #[test]
+    fn buttons_take_over_until_stopped() {
+        let mut arbiter = Arbiter::new(600);
+        arbiter.request(Source::Ble);
+
+        assert_eq!(
+            arbiter.request(Source::Buttons),
+            Verdict::Allow { cap: MAX_INTENSITY }
+        );
+        for source in ALL_REMOTES {
+            assert_eq!(
+                arbiter.request(source),
+                Verdict::Denied {
+                    owner: Source::Buttons
+                }
+            );
+        }
+
+        // a remote can still stop the motor, but the buttons keep control
+        arbiter.stop(Source::Http);
+        assert_eq!(arbiter.owner(), Some(Source::Buttons));
+        assert!(!arbiter.paused());
+        assert_eq!(
+            arbiter.request(Source::Ble),
+            Verdict::Denied {
+                owner: Source::Buttons
+            }
+        );
+
+        // until the buttons stop it themselves
+        arbiter.stop(Source::Buttons);
+        assert_eq!(arbiter.owner(), None);
+        assert!(!arbiter.paused());
+        assert_eq!(arbiter.request(Source::Ble), Verdict::Allow { cap: 600 });
+    }
+
+    #[test]
+    fn lovense_stop_then_vibrate_does_not_win_back_control() {
+        let mut arbiter = Arbiter::default();
+        arbiter.request(Source::Ble);
+        arbiter.request(Source::Buttons);
+
+        // `Vibrate:0;Vibrate:10;`
+        arbiter.stop(Source::Ble);
+        assert_eq!(
+            arbiter.request(Source::Ble),
+            Verdict::Denied {
+                owner: Source::Buttons
+            }
+        );
+    }
+
+    #[test]
+    fn stopping_from_the_buttons_pauses_and_resumes_remotes() {
+        let mut arbiter = Arbiter::default();
+        arbiter.request(Source::Ble);
+
+        arbiter.stop(Source::Buttons);
+        assert!(arbiter.paused());
+        for source in ALL_REMOTES {
+            assert_eq!(arbiter.request(source), Verdict::Paused);
+        }
+        // stops are still honoured while paused, and don't resume anything
+        arbiter.stop(Source::Ble);
+        assert!(arbiter.paused());
+
+        // the buttons themselves aren't paused
+        assert_eq!(
+            arbiter.request(Source::Buttons),
+            Verdict::Allow { cap: MAX_INTENSITY }
+        );
+        arbiter.stop(Source::Buttons);
+        assert!(!arbiter.paused());
+        assert_eq!(
+            arbiter.request(Source::Console),
+            Verdict::Allow { cap: MAX_INTENSITY }
+        );
+    }
+
+    #[test]
+    fn pressing_stop_while_idle_does_not_pause() {
+        let mut arbiter = Arbiter::default();
+
+        arbiter.stop(Source::Buttons);
+        assert!(!arbiter.paused());
+
+        arbiter.request(Source::Buttons);
+        arbiter.stop(Source::Buttons);
+        assert!(!arbiter.paused());
+        assert_eq!(arbiter.owner(), None);
+    }
+
+    #[test]
+    fn release_keeps_the_pause() {
+        let mut arbiter = Arbiter::default();
+        arbiter.request(Source::Ble);
+        arbiter.stop(Source::Buttons);
+        arbiter.request(Source::Buttons);
+
+        arbiter.release();
+        assert_eq!(arbiter.owner(), None);
+        assert!(arbiter.paused());
+        assert_eq!(arbiter.request(Source::Http), Verdict::Paused);
+    }
+
+    #[test]
+    fn cap_is_clamped_and_applies_to_later_requests() {
+        let mut arbiter = Arbiter::new(MAX_INTENSITY + 500);
+        assert_eq!(arbiter.remote_cap(), MAX_INTENSITY);
+
+        arbiter.set_remote_cap(250);
+        assert_eq!(arbiter.request(Source::Ble), Verdict::Allow { cap: 250 });
+        arbiter.set_remote_cap(u32::MAX);
+        assert_eq!(
+            arbiter.request(Source::Ble),
+            Verdict::Allow { cap: MAX_INTENSITY }
+        );
+    }
+}
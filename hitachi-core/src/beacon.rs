@@ -0,0 +1,147 @@
+//! Device state broadcast in the scan response's manufacturer-specific data, so a room full of
+//! wands can be watched without connecting to each one.
+//!
+//! All integers are little-endian.
+//!
+//! | offset | size | field                                                          |
+//! |--------|------|----------------------------------------------------------------|
+//! | 0      | 2    | company id, `0xFFFF` (reserved for testing)                    |
+//! | 2      | 1    | layout version, [`VERSION`]                                    |
+//! | 3      | 1    | status flags, [`STATUS_POWERED`]                               |
+//! | 4      | 1    | [`Mode`](crate::state::Mode)                                   |
+//! | 5      | 1    | fault flags, same as the wand service's [`wand::FAULT_OVERHEAT`] etc. |
+//! | 6      | 2    | `u16` actual intensity, 0..=1000                               |
+//! | 8      | 2    | `i16` motor temperature in 0.01°C, `i16::MIN` if unknown       |
+
+use crate::{state::Snapshot, wand};
+
+pub const COMPANY_ID: u16 = 0xFFFF;
+pub const VERSION: u8 = 1;
+pub const LEN: usize = 10;
+
+/// Running off mains - there's no battery to report. Always set.
+pub const STATUS_POWERED: u8 = 1 << 0;
+
+pub fn encode(snapshot: &Snapshot) -> [u8; LEN] {
+    let company = COMPANY_ID.to_le_bytes();
+    // mode and fault flags come out of the state characteristic's encoding
+    let [mode, faults, _, _] = wand::encode_state(snapshot);
+    let actual = wand::encode_intensity(snapshot.motor.actual);
+    let temp = wand::encode_temperature(snapshot.device.motor_temp_c);
+
+    [
+        company[0],
+        company[1],
+        VERSION,
+        STATUS_POWERED,
+        mode,
+        faults,
+        actual[0],
+        actual[1],
+        temp[0],
+        temp[1],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pattern::{Pattern, PatternKind},
+        state::{DeviceState, MotorLevel},
+        thermal::ThermalState,
+    };
+
+    fn snapshot(target: u32, actual: u32, device: DeviceState) -> Snapshot {
+        Snapshot {
+            motor: MotorLevel { target, actual },
+            device,
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let data = encode(&snapshot(
+            600,
+            420,
+            DeviceState {
+                motor_temp_c: Some(36.25),
+                ..Default::default()
+            },
+        ));
+
+        assert_eq!(
+            data,
+            [
+                0xFF,
+                0xFF,
+                VERSION,
+                STATUS_POWERED,
+                1,
+                0,
+                0xA4,
+                0x01,
+                0x29,
+                0x0E
+            ]
+        );
+    }
+
+    #[test]
+    fn off_and_unknown_temperature() {
+        let data = encode(&snapshot(0, 0, DeviceState::default()));
+
+        assert_eq!(data[4], 0);
+        assert_eq!(data[5], wand::FAULT_NO_TEMP);
+        assert_eq!(data[6..8], [0, 0]);
+        assert_eq!(i16::from_le_bytes([data[8], data[9]]), i16::MIN);
+    }
+
+    #[test]
+    fn pattern_and_faults() {
+        let device = DeviceState {
+            motor_temp_c: Some(-5.0),
+            thermal: ThermalState::Derating { cap: 500 },
+            pattern: Some(Pattern::new(PatternKind::Wave, 0, 1000)),
+            ..Default::default()
+        };
+        let data = encode(&snapshot(0, 0, device));
+        assert_eq!(data[4], 2);
+        assert_eq!(data[5], wand::FAULT_DERATING);
+        assert_eq!(i16::from_le_bytes([data[8], data[9]]), -500);
+
+        let device = DeviceState {
+            thermal: ThermalState::Shutdown,
+            ..Default::default()
+        };
+        let data = encode(&snapshot(800, 0, device));
+        assert_eq!(data[4], 1);
+        assert_eq!(data[5], wand::FAULT_OVERHEAT | wand::FAULT_NO_TEMP);
+    }
+
+    #[test]
+    fn values_are_clamped() {
+        let data = encode(&snapshot(
+            5000,
+            5000,
+            DeviceState {
+                motor_temp_c: Some(1000.0),
+                ..Default::default()
+            },
+        ));
+
+        assert_eq!(u16::from_le_bytes([data[6], data[7]]), 1000);
+        assert_eq!(i16::from_le_bytes([data[8], data[9]]), i16::MAX);
+
+        let data = encode(&snapshot(
+            0,
+            0,
+            DeviceState {
+                motor_temp_c: Some(-1000.0),
+                ..Default::default()
+            },
+        ));
+        // i16::MIN is kept for "unknown"
+        assert_eq!(i16::from_le_bytes([data[8], data[9]]), i16::MIN + 1);
+    }
+}
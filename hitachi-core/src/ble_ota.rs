@@ -0,0 +1,293 @@
+//! Binary schema for the BLE firmware update service.
+//!
+//! All integers are little-endian. A client:
+//!
+//! 1. subscribes to `control` and writes `begin` with the image size
+//! 2. writes the image to `data` in chunks, each prefixed with its offset. The image is the
+//!    signed one (see `tools/ota-sign`), header included
+//! 3. writes `finish` once the notified offset reaches the size
+//!
+//! The wand only accepts the chunk at the offset it expects next, and answers anything else
+//! with `offset mismatch` and that offset. That's also how an interrupted update is resumed:
+//! reconnect, write `begin` with the same size, and carry on from the notified offset.
+//!
+//! | characteristic | props          | layout                                              |
+//! |----------------|----------------|-----------------------------------------------------|
+//! | control        | write, notify  | write: `u8` command (+ args), notify: progress      |
+//! | data           | write (no rsp) | `u32` offset, image bytes                           |
+//!
+//! Commands: `0x01` begin + `u32` size, `0x02` status, `0x03` finish, `0x04` abort.
+//! Progress: `u8` [`OtaStatus`], `u32` offset, `u32` size.
+
+use std::fmt::Display;
+
+pub const SERVICE: &str = "7a6e0010-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+pub const CONTROL_CHAR: &str = "7a6e0011-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+pub const DATA_CHAR: &str = "7a6e0012-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+
+const CMD_BEGIN: u8 = 0x01;
+const CMD_STATUS: u8 = 0x02;
+const CMD_FINISH: u8 = 0x03;
+const CMD_ABORT: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtaCommand {
+    Begin { size: u32 },
+    Status,
+    Finish,
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OtaStatus {
+    Idle = 0x00,
+    Receiving = 0x01,
+    /// written and verified, takes effect on the next restart
+    Complete = 0x02,
+    MotorRunning = 0x80,
+    BadSize = 0x81,
+    BadFirmware = 0x82,
+    WriteFailed = 0x83,
+    /// the chunk wasn't at the notified offset
+    OffsetMismatch = 0x84,
+    NotStarted = 0x85,
+    /// `finish` before the whole image arrived
+    Incomplete = 0x86,
+    /// the request was dropped or another update is running, ask for the status and retry
+    Busy = 0x87,
+    /// the image isn't signed, or not with the key this firmware was built with
+    BadSignature = 0x88,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OtaError {
+    Length,
+    UnknownCommand,
+}
+
+impl Display for OtaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OtaError::Length => write!(f, "wrong payload length"),
+            OtaError::UnknownCommand => write!(f, "unknown command"),
+        }
+    }
+}
+
+impl std::error::Error for OtaError {}
+
+pub fn decode_command(data: &[u8]) -> Result<OtaCommand, OtaError> {
+    match data {
+        [CMD_BEGIN, size @ ..] => {
+            let size = size.try_into().map_err(|_| OtaError::Length)?;
+            Ok(OtaCommand::Begin {
+                size: u32::from_le_bytes(size),
+            })
+        }
+        [CMD_STATUS] => Ok(OtaCommand::Status),
+        [CMD_FINISH] => Ok(OtaCommand::Finish),
+        [CMD_ABORT] => Ok(OtaCommand::Abort),
+        [CMD_STATUS | CMD_FINISH | CMD_ABORT, ..] | [] => Err(OtaError::Length),
+        _ => Err(OtaError::UnknownCommand),
+    }
+}
+
+/// Splits a data write into its offset and the image bytes.
+pub fn decode_chunk(data: &[u8]) -> Result<(u32, &[u8]), OtaError> {
+    match data {
+        [a, b, c, d, rest @ ..] if !rest.is_empty() => {
+            Ok((u32::from_le_bytes([*a, *b, *c, *d]), rest))
+        }
+        _ => Err(OtaError::Length),
+    }
+}
+
+pub fn encode_progress(status: OtaStatus, offset: u32, size: u32) -> [u8; 9] {
+    let mut out = [0; 9];
+    out[0] = status as u8;
+    out[1..5].copy_from_slice(&offset.to_le_bytes());
+    out[5..9].copy_from_slice(&size.to_le_bytes());
+    out
+}
+
+/// Tracks how much of an image has arrived, in order.
+pub struct Transfer {
+    size: u32,
+    offset: u32,
+}
+
+impl Transfer {
+    pub fn new(size: u32) -> Self {
+        Self { size, offset: 0 }
+    }
+
+    /// Accepts `len` bytes at `offset` if that's the next chunk and it fits.
+    pub fn accept(&mut self, offset: u32, len: usize) -> Result<(), OtaStatus> {
+        if offset != self.offset {
+            return Err(OtaStatus::OffsetMismatch);
+        }
+
+        let end = u32::try_from(len)
+            .ok()
+            .and_then(|len| offset.checked_add(len))
+            .filter(|end| *end <= self.size)
+            .ok_or(OtaStatus::BadSize)?;
+        self.offset = end;
+
+        Ok(())
+    }
+
+    /// Whether a `begin` for `size` picks this transfer back up where it left off. Any other
+    /// size is a different image.
+    pub fn resumes(&self, size: u32) -> bool {
+        size == self.size
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            decode_command(&[0x01, 0x00, 0x00, 0x10, 0x00]),
+            Ok(OtaCommand::Begin { size: 0x10_0000 })
+        );
+        assert_eq!(decode_command(&[0x02]), Ok(OtaCommand::Status));
+        assert_eq!(decode_command(&[0x03]), Ok(OtaCommand::Finish));
+        assert_eq!(decode_command(&[0x04]), Ok(OtaCommand::Abort));
+    }
+
+    #[test]
+    fn command_lengths() {
+        for data in [
+            &[][..],
+            &[0x01],
+            &[0x01, 0, 0, 0],
+            &[0x01, 0, 0, 0, 0, 0],
+            &[0x02, 0],
+            &[0x03, 0],
+            &[0x04, 0, 0],
+        ] {
+            assert_eq!(decode_command(data), Err(OtaError::Length), "{data:?}");
+        }
+    }
+
+    #[test]
+    fn unknown_commands() {
+        for data in [&[0x00][..], &[0x05], &[0xFF, 1, 2, 3, 4]] {
+            assert_eq!(
+                decode_command(data),
+                Err(OtaError::UnknownCommand),
+                "{data:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn chunks() {
+        assert_eq!(
+            decode_chunk(&[0x00, 0x02, 0x00, 0x00, 0xAA, 0xBB]),
+            Ok((512, &[0xAA, 0xBB][..]))
+        );
+        // an offset with no data in it is no chunk
+        assert_eq!(decode_chunk(&[0, 0, 0, 0]), Err(OtaError::Length));
+        assert_eq!(decode_chunk(&[0, 0, 0]), Err(OtaError::Length));
+        assert_eq!(decode_chunk(&[]), Err(OtaError::Length));
+    }
+
+    #[test]
+    fn progress_layout() {
+        assert_eq!(
+            encode_progress(OtaStatus::OffsetMismatch, 0x0102_0304, 0x0A0B_0C0D),
+            [0x84, 0x04, 0x03, 0x02, 0x01, 0x0D, 0x0C, 0x0B, 0x0A]
+        );
+        assert_eq!(encode_progress(OtaStatus::Idle, 0, 0), [0; 9]);
+    }
+
+    #[test]
+    fn chunks_in_order() {
+        let mut transfer = Transfer::new(1000);
+
+        assert_eq!(transfer.accept(0, 400), Ok(()));
+        assert_eq!(transfer.accept(400, 400), Ok(()));
+        assert!(!transfer.is_complete());
+        assert_eq!(transfer.accept(800, 200), Ok(()));
+        assert_eq!(transfer.offset(), 1000);
+        assert!(transfer.is_complete());
+    }
+
+    #[test]
+    fn only_the_next_offset_is_accepted() {
+        let mut transfer = Transfer::new(1000);
+        transfer.accept(0, 400).unwrap();
+
+        // a resend of what already arrived, and a chunk from too far ahead
+        assert_eq!(transfer.accept(0, 400), Err(OtaStatus::OffsetMismatch));
+        assert_eq!(transfer.accept(500, 100), Err(OtaStatus::OffsetMismatch));
+        assert_eq!(transfer.offset(), 400);
+
+        assert_eq!(transfer.accept(400, 100), Ok(()));
+    }
+
+    #[test]
+    fn writes_past_the_end() {
+        let mut transfer = Transfer::new(1000);
+        transfer.accept(0, 900).unwrap();
+
+        assert_eq!(transfer.accept(900, 101), Err(OtaStatus::BadSize));
+        assert_eq!(transfer.offset(), 900);
+        assert_eq!(transfer.accept(900, 100), Ok(()));
+        assert_eq!(transfer.accept(1000, 1), Err(OtaStatus::BadSize));
+        assert!(transfer.is_complete());
+    }
+
+    #[test]
+    fn lengths_that_overflow() {
+        let mut transfer = Transfer::new(u32::MAX);
+        transfer.accept(0, u32::MAX as usize - 10).unwrap();
+
+        assert_eq!(transfer.accept(u32::MAX - 10, 20), Err(OtaStatus::BadSize));
+        assert_eq!(
+            transfer.accept(u32::MAX - 10, usize::MAX),
+            Err(OtaStatus::BadSize)
+        );
+        assert_eq!(transfer.offset(), u32::MAX - 10);
+    }
+
+    #[test]
+    fn resume_after_a_disconnect() {
+        let mut transfer = Transfer::new(1000);
+        transfer.accept(0, 244).unwrap();
+        transfer.accept(244, 244).unwrap();
+
+        // the client reconnects, begins again with the same size, and is told to carry on
+        // from 488. a chunk it sent before dropping is refused
+        assert!(transfer.resumes(1000));
+        assert_eq!(transfer.accept(244, 244), Err(OtaStatus::OffsetMismatch));
+        assert_eq!(transfer.accept(488, 512), Ok(()));
+        assert!(transfer.is_complete());
+    }
+
+    #[test]
+    fn another_image_does_not_resume() {
+        let transfer = Transfer::new(1000);
+
+        assert!(!transfer.resumes(999));
+        assert!(!transfer.resumes(0));
+    }
+}
@@ -0,0 +1,179 @@
+//! Other vendors' vibrator protocols, for apps that don't speak Lovense.
+//!
+//! Layouts and byte formats follow the Buttplug device configuration for each protocol. Only
+//! the single-motor vibrate commands are decoded; everything else is ignored.
+
+use crate::intensity::{self, MAX_INTENSITY};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emulation {
+    WeVibe,
+    MagicMotion,
+    Kiiroo,
+}
+
+/// GATT layout of an emulated toy. UUIDs are 128-bit strings.
+pub struct GattLayout {
+    pub service: &'static str,
+    /// written by the app
+    pub rx: &'static str,
+    /// read/notify, if the protocol has one
+    pub tx: Option<&'static str>,
+}
+
+impl Emulation {
+    /// Advertised name when the config doesn't set one. Apps match on these.
+    pub fn default_name(&self) -> &'static str {
+        match self {
+            Emulation::WeVibe => "Wish",
+            Emulation::MagicMotion => "Smart Mini Vibe",
+            Emulation::Kiiroo => "Cliona",
+        }
+    }
+
+    pub fn layout(&self) -> GattLayout {
+        match self {
+            Emulation::WeVibe => GattLayout {
+                service: "f000bb03-0451-4000-b000-000000000000",
+                rx: "f000c000-0451-4000-b000-000000000000",
+                tx: Some("f000b000-0451-4000-b000-000000000000"),
+            },
+            Emulation::MagicMotion => GattLayout {
+                service: "78667579-7b48-43db-b8c5-7928a6b0a335",
+                rx: "78667579-a914-49a4-8333-aa3c0cd8fedc",
+                tx: None,
+            },
+            Emulation::Kiiroo => GattLayout {
+                service: "00001900-0000-1000-8000-00805f9b34fb",
+                rx: "00001902-0000-1000-8000-00805f9b34fb",
+                tx: Some("00001903-0000-1000-8000-00805f9b34fb"),
+            },
+        }
+    }
+
+    /// Turns a write into a target intensity, or `None` if it isn't a vibrate command.
+    pub fn decode(&self, data: &[u8]) -> Option<u32> {
+        match self {
+            // [0x0f, 0x03, 0x00, ext | int << 4, 0x00, 0x03, 0x00, 0x00], or all zeroes past
+            // the first byte to stop. Speeds are nibbles, 0..=15
+            Emulation::WeVibe => match data {
+                [0x0f, 0x00, ..] => Some(0),
+                [0x0f, _, _, speeds, ..] => {
+                    let speed = (speeds >> 4).max(speeds & 0x0f);
+                    Some(intensity::from_steps(speed as u32, 15))
+                }
+                _ => None,
+            },
+            // 12 byte command with the 0..=100 speed at index 9
+            Emulation::MagicMotion => match data {
+                [0x0b, _, _, _, _, _, _, _, _, speed, ..] => {
+                    Some(intensity::from_steps(*speed as u32, 100))
+                }
+                _ => None,
+            },
+            // [0x01, 0..=100]
+            Emulation::Kiiroo => match data {
+                [0x01, speed, ..] => Some(intensity::from_steps(*speed as u32, 100)),
+                _ => None,
+            },
+        }
+        .map(|v| v.min(MAX_INTENSITY))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Emulation; 3] = [Emulation::WeVibe, Emulation::MagicMotion, Emulation::Kiiroo];
+
+    fn wevibe(speeds: u8) -> [u8; 8] {
+        [0x0f, 0x03, 0x00, speeds, 0x00, 0x03, 0x00, 0x00]
+    }
+
+    fn magic_motion(speed: u8) -> [u8; 12] {
+        [
+            0x0b, 0xff, 0x04, 0x0a, 0x32, 0x32, 0x00, 0x04, 0x08, speed, 0x64, 0x00,
+        ]
+    }
+
+    #[test]
+    fn wevibe_packets() {
+        let e = Emulation::WeVibe;
+
+        assert_eq!(e.decode(&wevibe(0xff)), Some(MAX_INTENSITY));
+        assert_eq!(e.decode(&wevibe(0x33)), Some(200));
+        // one motor: the faster of the two nibbles wins
+        assert_eq!(e.decode(&wevibe(0x30)), Some(200));
+        assert_eq!(e.decode(&wevibe(0x06)), Some(400));
+        assert_eq!(e.decode(&wevibe(0x00)), Some(0));
+        assert_eq!(e.decode(&[0x0f, 0, 0, 0, 0, 0, 0, 0]), Some(0));
+    }
+
+    #[test]
+    fn magic_motion_packets() {
+        let e = Emulation::MagicMotion;
+
+        assert_eq!(e.decode(&magic_motion(100)), Some(MAX_INTENSITY));
+        assert_eq!(e.decode(&magic_motion(42)), Some(420));
+        assert_eq!(e.decode(&magic_motion(0)), Some(0));
+    }
+
+    #[test]
+    fn kiiroo_packets() {
+        let e = Emulation::Kiiroo;
+
+        assert_eq!(e.decode(&[0x01, 100]), Some(MAX_INTENSITY));
+        assert_eq!(e.decode(&[0x01, 35]), Some(350));
+        assert_eq!(e.decode(&[0x01, 0]), Some(0));
+    }
+
+    #[test]
+    fn out_of_range_speeds_are_capped() {
+        assert_eq!(
+            Emulation::MagicMotion.decode(&magic_motion(0xff)),
+            Some(MAX_INTENSITY)
+        );
+        assert_eq!(Emulation::Kiiroo.decode(&[0x01, 101]), Some(MAX_INTENSITY));
+        assert_eq!(Emulation::Kiiroo.decode(&[0x01, 0xff]), Some(MAX_INTENSITY));
+    }
+
+    #[test]
+    fn short_writes_are_ignored() {
+        assert_eq!(Emulation::WeVibe.decode(&wevibe(0xff)[..3]), None);
+        assert_eq!(Emulation::WeVibe.decode(&[0x0f]), None);
+        assert_eq!(Emulation::MagicMotion.decode(&magic_motion(50)[..9]), None);
+        assert_eq!(Emulation::Kiiroo.decode(&[0x01]), None);
+
+        for e in ALL {
+            assert_eq!(e.decode(&[]), None, "{e:?}");
+        }
+    }
+
+    #[test]
+    fn other_commands_are_ignored() {
+        let mut wevibe = wevibe(0xff);
+        wevibe[0] = 0x0e;
+        assert_eq!(Emulation::WeVibe.decode(&wevibe), None);
+
+        let mut magic_motion = magic_motion(50);
+        magic_motion[0] = 0x10;
+        assert_eq!(Emulation::MagicMotion.decode(&magic_motion), None);
+
+        assert_eq!(Emulation::Kiiroo.decode(&[0x02, 50]), None);
+    }
+
+    #[test]
+    fn every_write_decodes_in_range() {
+        for e in ALL {
+            for first in 0..=255u8 {
+                for byte in [0x00, 0x01, 0x0f, 0x64, 0x65, 0xf0, 0xff] {
+                    let data = [first, byte, byte, byte, byte, byte, byte, byte, byte, byte];
+                    if let Some(level) = e.decode(&data) {
+                        assert!(level <= MAX_INTENSITY, "{e:?} {data:?}");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Link-loss failsafe for remote controllers.
+//!
+//! Whichever remote link (a BLE connection or a WebSocket) last sent a motor command is the
+//! controller. [`LinkWatch`] reports it lost once, when it disconnects or sends nothing for the
+//! heartbeat timeout. Local input releases the controller, so a phone wandering off after that
+//! doesn't count. The firmware's `LinkWatchHandle` clocks it and decides what a loss does.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Ble(u16),
+    /// by httpd session
+    WebSocket(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkLoss {
+    /// the controlling connection disconnected
+    Disconnected { link: Link },
+    /// the controlling connection stayed connected but stopped writing
+    Silent { link: Link },
+}
+
+impl LinkLoss {
+    pub fn link(&self) -> Link {
+        match *self {
+            LinkLoss::Disconnected { link } | LinkLoss::Silent { link } => link,
+        }
+    }
+}
+
+pub struct LinkWatch {
+    heartbeat_ms: Option<u64>,
+    controller: Option<Link>,
+    last_seen_ms: u64,
+    pending: Option<LinkLoss>,
+}
+
+impl LinkWatch {
+    /// `heartbeat_ms` of `None` disables the silence check.
+    pub fn new(heartbeat_ms: Option<u64>) -> Self {
+        Self {
+            heartbeat_ms,
+            controller: None,
+            last_seen_ms: 0,
+            pending: None,
+        }
+    }
+
+    /// Records a motor command from `link`, making it the controller.
+    pub fn command(&mut self, link: Link, now_ms: u64) {
+        self.controller = Some(link);
+        self.last_seen_ms = now_ms;
+        self.pending = None;
+    }
+
+    /// Records any write from `link`. Only keeps the controller alive.
+    pub fn seen(&mut self, link: Link, now_ms: u64) {
+        if self.controller == Some(link) {
+            self.last_seen_ms = now_ms;
+        }
+    }
+
+    /// Local input took over, so losing the remote doesn't matter anymore.
+    pub fn release(&mut self) {
+        self.controller = None;
+        self.pending = None;
+    }
+
+    pub fn disconnected(&mut self, link: Link) {
+        if self.controller == Some(link) {
+            self.controller = None;
+            self.pending = Some(LinkLoss::Disconnected { link });
+        }
+    }
+
+    /// Returns a link loss once, either a disconnect since the last check or the heartbeat
+    /// running out.
+    pub fn check(&mut self, now_ms: u64) -> Option<LinkLoss> {
+        if let Some(loss) = self.pending.take() {
+            return Some(loss);
+        }
+
+        let link = self.controller?;
+        let heartbeat_ms = self.heartbeat_ms?;
+        if now_ms.saturating_sub(self.last_seen_ms) >= heartbeat_ms {
+            self.controller = None;
+            return Some(LinkLoss::Silent { link });
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHONE: Link = Link::Ble(1);
+    const OTHER: Link = Link::Ble(2);
+
+    #[test]
+    fn disconnect_is_reported_once_on_the_next_check() {
+        let mut watch = LinkWatch::new(None);
+        watch.command(PHONE, 1_000);
+
+        watch.disconnected(PHONE);
+        assert_eq!(
+            watch.check(1_100),
+            Some(LinkLoss::Disconnected { link: PHONE })
+        );
+        assert_eq!(watch.check(1_200), None);
+        assert_eq!(watch.check(100_000), None);
+    }
+
+    #[test]
+    fn silence_is_reported_after_the_heartbeat() {
+        let mut watch = LinkWatch::new(Some(5_000));
+        watch.command(PHONE, 1_000);
+
+        assert_eq!(watch.check(5_999), None);
+        assert_eq!(watch.check(6_000), Some(LinkLoss::Silent { link: PHONE }));
+        assert_eq!(watch.check(20_000), None);
+
+        // and a disconnect after that isn't a second loss
+        watch.disconnected(PHONE);
+        assert_eq!(watch.check(20_100), None);
+    }
+
+    #[test]
+    fn writes_from_the_controller_keep_it_alive() {
+        let mut watch = LinkWatch::new(Some(5_000));
+        watch.command(PHONE, 0);
+
+        watch.seen(PHONE, 4_000);
+        watch.seen(OTHER, 8_000);
+        assert_eq!(watch.check(8_999), None);
+        assert_eq!(watch.check(9_000), Some(LinkLoss::Silent { link: PHONE }));
+    }
+
+    #[test]
+    fn no_heartbeat_means_silence_is_fine() {
+        let mut watch = LinkWatch::new(None);
+        watch.command(PHONE, 0);
+
+        assert_eq!(watch.check(u64::MAX), None);
+    }
+
+    #[test]
+    fn reconnect_cancels_a_pending_disconnect() {
+        let mut watch = LinkWatch::new(None);
+        watch.command(PHONE, 0);
+
+        watch.disconnected(PHONE);
+        let reconnected = Link::Ble(3);
+        watch.command(reconnected, 50);
+        assert_eq!(watch.check(100), None);
+
+        watch.disconnected(reconnected);
+        assert_eq!(
+            watch.check(200),
+            Some(LinkLoss::Disconnected { link: reconnected })
+        );
+    }
+
+    #[test]
+    fn only_the_controller_counts() {
+        let mut watch = LinkWatch::new(Some(1_000));
+        assert_eq!(watch.check(10_000), None);
+
+        watch.command(PHONE, 0);
+        watch.command(OTHER, 0);
+        watch.disconnected(PHONE);
+        assert_eq!(watch.check(500), None);
+
+        watch.disconnected(OTHER);
+        assert_eq!(
+            watch.check(600),
+            Some(LinkLoss::Disconnected { link: OTHER })
+        );
+    }
+
+    #[test]
+    fn release_cancels_everything() {
+        let mut watch = LinkWatch::new(Some(1_000));
+        watch.command(PHONE, 0);
+        watch.disconnected(PHONE);
+        watch.release();
+        assert_eq!(watch.check(100), None);
+
+        watch.command(PHONE, 0);
+        watch.release();
+        assert_eq!(watch.check(5_000), None);
+        watch.disconnected(PHONE);
+        assert_eq!(watch.check(5_100), None);
+    }
+
+    #[test]
+    fn websockets_and_ble_are_different_links() {
+        let mut watch = LinkWatch::new(None);
+        watch.command(Link::WebSocket(1), 0);
+
+        watch.disconnected(Link::Ble(1));
+        assert_eq!(watch.check(100), None);
+        watch.disconnected(Link::WebSocket(1));
+        assert_eq!(
+            watch.check(200).map(|loss| loss.link()),
+            Some(Link::WebSocket(1))
+        );
+    }
+}
@@ -0,0 +1,560 @@
+//! HID report descriptor and input report parsing for BLE remotes (media remotes, presentation
+//! clickers).
+//!
+//! Only as much of HID as those need: input reports with array or variable fields, on any
+//! usage page. Output and feature reports are skipped, and so are long items. Keys are turned
+//! into [`RemoteAction`]s on press, not on release or while held.
+
+use std::fmt::Display;
+
+pub const PAGE_KEYBOARD: u16 = 0x07;
+pub const PAGE_CONSUMER: u16 = 0x0C;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub page: u16,
+    pub id: u16,
+}
+
+/// What a remote's key does - the same things the physical buttons do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteAction {
+    Increase,
+    Decrease,
+    Stop,
+    NextPattern,
+    PreviousPattern,
+}
+
+pub fn action_for(usage: Usage) -> Option<RemoteAction> {
+    let action = match (usage.page, usage.id) {
+        // up / down arrows, volume
+        (PAGE_KEYBOARD, 0x52) | (PAGE_CONSUMER, 0xE9) => RemoteAction::Increase,
+        (PAGE_KEYBOARD, 0x51) | (PAGE_CONSUMER, 0xEA) => RemoteAction::Decrease,
+        // enter, escape / play-pause, stop, mute
+        (PAGE_KEYBOARD, 0x28 | 0x29) | (PAGE_CONSUMER, 0xCD | 0xB7 | 0xE2) => RemoteAction::Stop,
+        // right arrow, page down (what clickers send) / next track
+        (PAGE_KEYBOARD, 0x4F | 0x4E) | (PAGE_CONSUMER, 0xB5) => RemoteAction::NextPattern,
+        // left arrow, page up / previous track
+        (PAGE_KEYBOARD, 0x50 | 0x4B) | (PAGE_CONSUMER, 0xB6) => RemoteAction::PreviousPattern,
+        _ => return None,
+    };
+
+    Some(action)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HidError {
+    /// an item's data runs past the end of the descriptor
+    Truncated,
+    /// more pushes than pops, or the other way round
+    Stack,
+}
+
+impl Display for HidError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HidError::Truncated => write!(f, "report descriptor is truncated"),
+            HidError::Stack => write!(f, "unbalanced push/pop in report descriptor"),
+        }
+    }
+}
+
+impl std::error::Error for HidError {}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Globals {
+    page: u16,
+    logical_min: i32,
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+#[derive(Debug, Default)]
+struct Locals {
+    usages: Vec<Usage>,
+    usage_min: Option<Usage>,
+    usage_max: Option<Usage>,
+}
+
+impl Locals {
+    /// The usage the `index`th element (variable) or value (array) stands for.
+    fn usage(&self, index: u32) -> Option<Usage> {
+        if let (Some(min), Some(max)) = (self.usage_min, self.usage_max) {
+            let id = min.id as u32 + index;
+            return (id <= max.id as u32).then_some(Usage {
+                page: min.page,
+                id: id as u16,
+            });
+        }
+
+        // a short usage list repeats its last entry
+        self.usages
+            .get(index as usize)
+            .or(self.usages.last())
+            .copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    report_id: u8,
+    bit_offset: u32,
+    size: u32,
+    count: u32,
+    logical_min: i32,
+    variable: bool,
+    /// usage per element (variable) or per value above `logical_min` (array)
+    usages: Vec<Usage>,
+}
+
+/// The input fields of a parsed report descriptor ("report map" in HID over GATT).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReportMap {
+    fields: Vec<Field>,
+}
+
+const ITEM_MAIN: u8 = 0;
+const ITEM_GLOBAL: u8 = 1;
+const ITEM_LOCAL: u8 = 2;
+
+const MAIN_INPUT: u8 = 0x8;
+
+const GLOBAL_USAGE_PAGE: u8 = 0x0;
+const GLOBAL_LOGICAL_MIN: u8 = 0x1;
+const GLOBAL_REPORT_SIZE: u8 = 0x7;
+const GLOBAL_REPORT_ID: u8 = 0x8;
+const GLOBAL_REPORT_COUNT: u8 = 0x9;
+const GLOBAL_PUSH: u8 = 0xA;
+const GLOBAL_POP: u8 = 0xB;
+
+const LOCAL_USAGE: u8 = 0x0;
+const LOCAL_USAGE_MIN: u8 = 0x1;
+const LOCAL_USAGE_MAX: u8 = 0x2;
+
+const INPUT_CONSTANT: u32 = 1 << 0;
+const INPUT_VARIABLE: u32 = 1 << 1;
+
+/// Usages are limited to this many per field, so a silly descriptor can't eat the heap.
+const MAX_FIELD_USAGES: u32 = 256;
+
+impl ReportMap {
+    pub fn parse(descriptor: &[u8]) -> Result<Self, HidError> {
+        let mut map = ReportMap::default();
+        let mut globals = Globals::default();
+        let mut stack = Vec::new();
+        let mut locals = Locals::default();
+        // next free bit in each input report
+        let mut offsets: Vec<(u8, u32)> = Vec::new();
+
+        let mut rest = descriptor;
+        while let [prefix, tail @ ..] = rest {
+            // long item: size, tag, data
+            if *prefix == 0xFE {
+                let [size, _, tail @ ..] = tail else {
+                    return Err(HidError::Truncated);
+                };
+                rest = tail.get(*size as usize..).ok_or(HidError::Truncated)?;
+                continue;
+            }
+
+            let len = match prefix & 0b11 {
+                3 => 4,
+                n => n as usize,
+            };
+            let data = tail.get(..len).ok_or(HidError::Truncated)?;
+            rest = &tail[len..];
+
+            let unsigned = data
+                .iter()
+                .rev()
+                .fold(0u32, |acc, byte| acc << 8 | *byte as u32);
+            let signed = match len {
+                1 => unsigned as u8 as i8 as i32,
+                2 => unsigned as u16 as i16 as i32,
+                _ => unsigned as i32,
+            };
+            // a 4 byte usage carries its own page in the high half
+            let usage = |globals: &Globals| Usage {
+                page: if len == 4 {
+                    (unsigned >> 16) as u16
+                } else {
+                    globals.page
+                },
+                id: unsigned as u16,
+            };
+
+            let tag = prefix >> 4;
+            match (prefix >> 2) & 0b11 {
+                ITEM_MAIN => {
+                    if tag == MAIN_INPUT {
+                        let offset =
+                            match offsets.iter_mut().find(|(id, _)| *id == globals.report_id) {
+                                Some((_, offset)) => offset,
+                                None => {
+                                    offsets.push((globals.report_id, 0));
+                                    &mut offsets.last_mut().unwrap().1
+                                }
+                            };
+
+                        let bits = globals.report_size * globals.report_count;
+                        if unsigned & INPUT_CONSTANT == 0 && bits > 0 {
+                            let variable = unsigned & INPUT_VARIABLE != 0;
+                            let usage_count = if variable {
+                                globals.report_count
+                            } else {
+                                // one usage per possible value
+                                match (locals.usage_min, locals.usage_max) {
+                                    (Some(min), Some(max)) => {
+                                        (max.id as u32 + 1).saturating_sub(min.id as u32)
+                                    }
+                                    _ => locals.usages.len() as u32,
+                                }
+                            };
+                            let usages = (0..usage_count.min(MAX_FIELD_USAGES))
+                                .filter_map(|index| locals.usage(index))
+                                .collect();
+
+                            map.fields.push(Field {
+                                report_id: globals.report_id,
+                                bit_offset: *offset,
+                                size: globals.report_size,
+                                count: globals.report_count,
+                                logical_min: globals.logical_min,
+                                variable,
+                                usages,
+                            });
+                        }
+                        *offset += bits;
+                    }
+
+                    // locals only last until the next main item
+                    locals = Locals::default();
+                }
+                ITEM_GLOBAL => match tag {
+                    GLOBAL_USAGE_PAGE => globals.page = unsigned as u16,
+                    GLOBAL_LOGICAL_MIN => globals.logical_min = signed,
+                    GLOBAL_REPORT_SIZE => globals.report_size = unsigned.min(32),
+                    GLOBAL_REPORT_ID => globals.report_id = unsigned as u8,
+                    GLOBAL_REPORT_COUNT => globals.report_count = unsigned.min(MAX_FIELD_USAGES),
+                    GLOBAL_PUSH => stack.push(globals),
+                    GLOBAL_POP => globals = stack.pop().ok_or(HidError::Stack)?,
+                    _ => {}
+                },
+                ITEM_LOCAL => match tag {
+                    LOCAL_USAGE => locals.usages.push(usage(&globals)),
+                    LOCAL_USAGE_MIN => locals.usage_min = Some(usage(&globals)),
+                    LOCAL_USAGE_MAX => locals.usage_max = Some(usage(&globals)),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        if !stack.is_empty() {
+            return Err(HidError::Stack);
+        }
+
+        Ok(map)
+    }
+
+    /// Whether anything in `report_id` is worth subscribing to.
+    pub fn has_report(&self, report_id: u8) -> bool {
+        self.fields.iter().any(|f| f.report_id == report_id)
+    }
+
+    /// The usages currently pressed in input report `report_id`. `data` is the report without
+    /// its id - over GATT, the id is in the report reference descriptor instead.
+    pub fn pressed(&self, report_id: u8, data: &[u8]) -> Vec<Usage> {
+        let mut pressed = Vec::new();
+        for field in self.fields.iter().filter(|f| f.report_id == report_id) {
+            for index in 0..field.count {
+                let Some(value) =
+                    read_bits(data, field.bit_offset + index * field.size, field.size)
+                else {
+                    break;
+                };
+
+                let usage = if field.variable {
+                    (value != 0)
+                        .then(|| field.usages.get(index as usize))
+                        .flatten()
+                } else {
+                    // array fields hold the index of each pressed usage
+                    (value as i64 - field.logical_min as i64)
+                        .try_into()
+                        .ok()
+                        .and_then(|i: usize| field.usages.get(i))
+                };
+
+                // usage 0 means "nothing" in arrays
+                if let Some(usage) = usage.filter(|u| u.id != 0) {
+                    if !pressed.contains(usage) {
+                        pressed.push(*usage);
+                    }
+                }
+            }
+        }
+
+        pressed
+    }
+}
+
+/// Reads `size` bits, LSB first, starting at bit `offset`.
+fn read_bits(data: &[u8], offset: u32, size: u32) -> Option<u32> {
+    if size == 0 || offset + size > data.len() as u32 * 8 {
+        return None;
+    }
+
+    let mut value = 0u32;
+    for bit in 0..size {
+        let pos = offset + bit;
+        if data[(pos / 8) as usize] & (1 << (pos % 8)) != 0 {
+            value |= 1 << bit;
+        }
+    }
+
+    Some(value)
+}
+
+/// Turns successive reports into key presses.
+#[derive(Debug, Default)]
+pub struct RemoteKeys {
+    /// what each report had pressed last time
+    held: Vec<(u8, Vec<Usage>)>,
+}
+
+impl RemoteKeys {
+    /// Returns the actions for keys in `pressed` that weren't already down in `report_id`.
+    pub fn update(&mut self, report_id: u8, pressed: Vec<Usage>) -> Vec<RemoteAction> {
+        let held = match self.held.iter_mut().find(|(id, _)| *id == report_id) {
+            Some((_, held)) => held,
+            None => {
+                self.held.push((report_id, Vec::new()));
+                &mut self.held.last_mut().unwrap().1
+            }
+        };
+
+        let actions = pressed
+            .iter()
+            .filter(|usage| !held.contains(usage))
+            .filter_map(|usage| action_for(*usage))
+            .collect();
+        *held = pressed;
+
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The boot keyboard descriptor from the HID spec: modifier bits, a reserved byte, LED
+    /// outputs and a six key array.
+    const KEYBOARD: &[u8] = &[
+        0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, // generic desktop, keyboard, application
+        0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, // modifiers
+        0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02, // input (var)
+        0x95, 0x01, 0x75, 0x08, 0x81, 0x01, // input (constant)
+        0x95, 0x05, 0x75, 0x01, 0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, // LEDs
+        0x95, 0x01, 0x75, 0x03, 0x91, 0x01, // LED padding
+        0x95, 0x06, 0x75, 0x08, 0x15, 0x00, 0x25, 0x65, // six keys
+        0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, // input (array)
+        0xC0,
+    ];
+
+    /// A media remote: consumer control as one 16 bit array value, in report 2.
+    const CONSUMER_ARRAY: &[u8] = &[
+        0x05, 0x0C, 0x09, 0x01, 0xA1, 0x01, 0x85, 0x02, // consumer control, report 2
+        0x15, 0x00, 0x26, 0xFF, 0x03, 0x19, 0x00, 0x2A, 0xFF, 0x03, // 0..=0x3FF
+        0x75, 0x10, 0x95, 0x01, 0x81, 0x00, // input (array)
+        0xC0,
+    ];
+
+    /// Consumer control as one bit per key, in report 1.
+    const CONSUMER_BITS: &[u8] = &[
+        0x05, 0x0C, 0x09, 0x01, 0xA1, 0x01, 0x85, 0x01, // consumer control, report 1
+        0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x04, // four bits
+        0x09, 0xE9, 0x09, 0xEA, 0x09, 0xCD, 0x09, 0xB5, 0x81, 0x02, // input (var)
+        0x95, 0x04, 0x81, 0x01, // padding
+        0xC0,
+    ];
+
+    fn keyboard(id: u16) -> Usage {
+        Usage {
+            page: PAGE_KEYBOARD,
+            id,
+        }
+    }
+
+    fn consumer(id: u16) -> Usage {
+        Usage {
+            page: PAGE_CONSUMER,
+            id,
+        }
+    }
+
+    #[test]
+    fn boot_keyboard() {
+        let map = ReportMap::parse(KEYBOARD).unwrap();
+        assert!(map.has_report(0));
+        assert!(!map.has_report(1));
+
+        // left shift and up arrow
+        let report = [0x01, 0x00, 0x52, 0x00, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(map.pressed(0, &report), [keyboard(0xE0), keyboard(0x52)]);
+
+        assert_eq!(map.pressed(0, &[0; 8]), []);
+        // the same key twice is one press
+        let report = [0x00, 0x00, 0x28, 0x28, 0x4F, 0x00, 0x00, 0x00];
+        assert_eq!(map.pressed(0, &report), [keyboard(0x28), keyboard(0x4F)]);
+    }
+
+    #[test]
+    fn short_reports_read_what_is_there() {
+        let map = ReportMap::parse(KEYBOARD).unwrap();
+
+        assert_eq!(
+            map.pressed(0, &[0x02, 0x00, 0x51]),
+            [keyboard(0xE1), keyboard(0x51)]
+        );
+        assert_eq!(map.pressed(0, &[]), []);
+    }
+
+    #[test]
+    fn consumer_array_with_report_id() {
+        let map = ReportMap::parse(CONSUMER_ARRAY).unwrap();
+        assert!(map.has_report(2));
+        assert!(!map.has_report(0));
+
+        assert_eq!(map.pressed(2, &[0xE9, 0x00]), [consumer(0xE9)]);
+        assert_eq!(map.pressed(2, &[0x00, 0x00]), []);
+        assert_eq!(map.pressed(1, &[0xE9, 0x00]), []);
+        // past the usages kept per field
+        assert_eq!(map.pressed(2, &[0x00, 0x02]), []);
+    }
+
+    #[test]
+    fn consumer_bits() {
+        let map = ReportMap::parse(CONSUMER_BITS).unwrap();
+
+        assert_eq!(map.pressed(1, &[0b0101]), [consumer(0xE9), consumer(0xCD)]);
+        assert_eq!(map.pressed(1, &[0b1010]), [consumer(0xEA), consumer(0xB5)]);
+        // the padding bits don't count
+        assert_eq!(map.pressed(1, &[0xF0]), []);
+    }
+
+    #[test]
+    fn several_reports() {
+        let descriptor = [CONSUMER_BITS, CONSUMER_ARRAY].concat();
+        let map = ReportMap::parse(&descriptor).unwrap();
+
+        assert_eq!(map.pressed(1, &[0b0010]), [consumer(0xEA)]);
+        assert_eq!(map.pressed(2, &[0xB6, 0x00]), [consumer(0xB6)]);
+    }
+
+    #[test]
+    fn extended_usage_carries_its_page() {
+        let descriptor = [
+            0x05, 0x07, // keyboard page
+            0x0B, 0xE9, 0x00, 0x0C, 0x00, // but a consumer usage
+            0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x01, 0x81, 0x02,
+        ];
+        let map = ReportMap::parse(&descriptor).unwrap();
+
+        assert_eq!(map.pressed(0, &[1]), [consumer(0xE9)]);
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let mut descriptor = vec![0x05, 0x0C, 0xA4, 0x05, 0x07, 0xB4];
+        descriptor.extend_from_slice(&[
+            0x09, 0xE9, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x01, 0x81, 0x02,
+        ]);
+        let map = ReportMap::parse(&descriptor).unwrap();
+        assert_eq!(map.pressed(0, &[1]), [consumer(0xE9)]);
+
+        assert_eq!(ReportMap::parse(&[0xA4]), Err(HidError::Stack));
+        assert_eq!(ReportMap::parse(&[0xB4]), Err(HidError::Stack));
+    }
+
+    #[test]
+    fn long_items_are_skipped() {
+        let descriptor = [&[0xFE, 0x02, 0x10, 0xAA, 0xBB][..], KEYBOARD].concat();
+        assert_eq!(ReportMap::parse(&descriptor), ReportMap::parse(KEYBOARD));
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(ReportMap::parse(&[0x05]), Err(HidError::Truncated));
+        assert_eq!(ReportMap::parse(&[0x26, 0xFF]), Err(HidError::Truncated));
+        assert_eq!(ReportMap::parse(&[0x27, 0, 0, 0]), Err(HidError::Truncated));
+        assert_eq!(
+            ReportMap::parse(&[0xFE, 0x03, 0x10, 0xAA]),
+            Err(HidError::Truncated)
+        );
+        assert_eq!(ReportMap::parse(&KEYBOARD[..9]), Err(HidError::Truncated));
+    }
+
+    #[test]
+    fn huge_counts_stay_bounded() {
+        // report count 0xFFFF of 32 bit values, array of 0..=0xFFFF
+        let descriptor = [
+            0x96, 0xFF, 0xFF, 0x75, 0xFF, 0x19, 0x00, 0x2A, 0xFF, 0xFF, 0x81, 0x00,
+        ];
+        let map = ReportMap::parse(&descriptor).unwrap();
+
+        assert!(map.fields[0].usages.len() <= MAX_FIELD_USAGES as usize);
+        assert_eq!(map.fields[0].count, MAX_FIELD_USAGES);
+        assert_eq!(map.fields[0].size, 32);
+        assert_eq!(map.pressed(0, &[0xFF; 8]), []);
+    }
+
+    #[test]
+    fn keys_act_on_press() {
+        let mut keys = RemoteKeys::default();
+
+        assert_eq!(
+            keys.update(0, vec![keyboard(0x52)]),
+            [RemoteAction::Increase]
+        );
+        // held
+        assert_eq!(keys.update(0, vec![keyboard(0x52)]), []);
+        assert_eq!(
+            keys.update(0, vec![keyboard(0x52), keyboard(0x29)]),
+            [RemoteAction::Stop]
+        );
+        // released and pressed again
+        assert_eq!(keys.update(0, vec![]), []);
+        assert_eq!(
+            keys.update(0, vec![keyboard(0x52)]),
+            [RemoteAction::Increase]
+        );
+
+        // reports are tracked separately
+        assert_eq!(
+            keys.update(2, vec![consumer(0xB5)]),
+            [RemoteAction::NextPattern]
+        );
+        assert_eq!(keys.update(0, vec![keyboard(0x52)]), []);
+
+        // unmapped keys do nothing
+        assert_eq!(keys.update(2, vec![keyboard(0x04)]), []);
+    }
+
+    #[test]
+    fn actions() {
+        assert_eq!(action_for(consumer(0xE9)), Some(RemoteAction::Increase));
+        assert_eq!(action_for(keyboard(0x51)), Some(RemoteAction::Decrease));
+        assert_eq!(action_for(consumer(0xCD)), Some(RemoteAction::Stop));
+        assert_eq!(action_for(keyboard(0x4E)), Some(RemoteAction::NextPattern));
+        assert_eq!(
+            action_for(keyboard(0x4B)),
+            Some(RemoteAction::PreviousPattern)
+        );
+        // volume up on the keyboard page is another key entirely
+        assert_eq!(action_for(keyboard(0xE9)), None);
+    }
+}
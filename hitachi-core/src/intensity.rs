@@ -0,0 +1,326 @@
+//! Mapping from the internal 0..=[`MAX_INTENSITY`] scale to LEDC duty.
+//!
+//! Every input source (20-step Lovense, buttons, finer-grained remote APIs) is converted to
+//! intensity first, and only [`IntensityMap`] knows about the motor's duty window and curve.
+
+use serde::{Deserialize, Serialize};
+
+pub const MAX_INTENSITY: u32 = 1000;
+
+/// Largest `|k|` an exponential curve may use. Much steeper and `e^k` stops fitting in an `f32`.
+pub const MAX_EXPONENT: f32 = 20.0;
+
+/// Shape applied to intensity before it's squeezed into the configured duty window.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// `(e^(k*x) - 1) / (e^k - 1)` - higher `k` spends more of the range at the low end.
+    /// `k` is limited to ±[`MAX_EXPONENT`]
+    Exponential { k: f32 },
+    /// `x^gamma`
+    Gamma { gamma: f32 },
+    /// piecewise-linear through evenly spaced points, each 0..=MAX_INTENSITY
+    Table { points: Vec<u16> },
+}
+
+impl Curve {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            Curve::Linear => {}
+            Curve::Exponential { k } => {
+                if !k.is_finite() || *k == 0.0 || k.abs() > MAX_EXPONENT {
+                    return Err(anyhow::anyhow!(
+                        "exponential curve needs k between -{MAX_EXPONENT} and {MAX_EXPONENT}, but not 0 (got {k})"
+                    ));
+                }
+            }
+            Curve::Gamma { gamma } => {
+                if !gamma.is_finite() || *gamma <= 0.0 {
+                    return Err(anyhow::anyhow!(
+                        "gamma curve needs a finite, positive gamma (got {gamma})"
+                    ));
+                }
+            }
+            Curve::Table { points } => {
+                if points.len() < 2 {
+                    return Err(anyhow::anyhow!("curve table needs at least 2 points"));
+                }
+
+                if points.iter().any(|p| *p as u32 > MAX_INTENSITY) {
+                    return Err(anyhow::anyhow!(
+                        "curve table points must be between 0 and {MAX_INTENSITY}"
+                    ));
+                }
+
+                if points.windows(2).any(|w| w[0] > w[1]) {
+                    return Err(anyhow::anyhow!("curve table must not decrease"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the curve to `x`, which must be in `0.0..=1.0`.
+    pub fn apply(&self, x: f32) -> f32 {
+        let y = match self {
+            Curve::Linear => x,
+            Curve::Exponential { k } => ((k * x).exp() - 1.0) / (k.exp() - 1.0),
+            Curve::Gamma { gamma } => x.powf(*gamma),
+            Curve::Table { points } => {
+                let pos = x * (points.len() - 1) as f32;
+                let idx = (pos.floor() as usize).min(points.len() - 2);
+                let frac = pos - idx as f32;
+                let (a, b) = (points[idx] as f32, points[idx + 1] as f32);
+                (a + (b - a) * frac) / MAX_INTENSITY as f32
+            }
+        };
+
+        // a curve that got past validate must not fall to min duty, which NaN would
+        if y.is_nan() {
+            x.clamp(0.0, 1.0)
+        } else {
+            y.clamp(0.0, 1.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntensityMap {
+    /// duty window, in thousandths of the max duty
+    min_duty: u32,
+    max_duty: u32,
+    steps: u32,
+    curve: Curve,
+}
+
+impl IntensityMap {
+    /// `min_duty` and `max_duty` are in thousandths of the max duty, and `min_duty` has to be the
+    /// lower one. The firmware builds this from a validated `MotorConfig`.
+    pub fn new(min_duty: u32, max_duty: u32, steps: u32, curve: Curve) -> Self {
+        Self {
+            min_duty,
+            max_duty,
+            steps: steps.max(1),
+            curve,
+        }
+    }
+
+    /// Duty for `intensity`, in thousandths of the max duty. Zero is always off.
+    pub fn duty_permille(&self, intensity: u32) -> u32 {
+        if intensity == 0 {
+            return 0;
+        }
+
+        let x = intensity.min(MAX_INTENSITY) as f32 / MAX_INTENSITY as f32;
+        let shaped = self.curve.apply(x);
+        self.min_duty + (shaped * (self.max_duty - self.min_duty) as f32).round() as u32
+    }
+
+    /// Number of discrete steps the buttons move through.
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    /// Intensity of the next step above `intensity`.
+    pub fn step_up(&self, intensity: u32) -> u32 {
+        // the highest step at or below `intensity`. steps that don't divide MAX_INTENSITY round
+        // down, so a plain `intensity * steps / MAX_INTENSITY` can land one short of it
+        let step = ((intensity.min(MAX_INTENSITY) + 1) * self.steps - 1) / MAX_INTENSITY;
+        from_steps((step + 1).min(self.steps), self.steps)
+    }
+
+    /// Intensity of the next step below `intensity`.
+    pub fn step_down(&self, intensity: u32) -> u32 {
+        let step = (intensity * self.steps).div_ceil(MAX_INTENSITY);
+        from_steps(step.saturating_sub(1), self.steps)
+    }
+}
+
+/// Converts `step` out of `steps` (e.g. a 0..=20 Lovense level) to intensity.
+pub fn from_steps(step: u32, steps: u32) -> u32 {
+    step.min(steps) * MAX_INTENSITY / steps.max(1)
+}
+
+/// Converts intensity to the nearest `step` out of `steps`, rounding up so anything non-zero
+/// shows as at least 1.
+pub fn to_steps(intensity: u32, steps: u32) -> u32 {
+    (intensity.min(MAX_INTENSITY) * steps).div_ceil(MAX_INTENSITY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [Curve; 6] = [
+        Curve::Linear,
+        Curve::Exponential { k: 3.0 },
+        Curve::Exponential { k: -3.0 },
+        Curve::Exponential { k: MAX_EXPONENT },
+        Curve::Gamma { gamma: 2.2 },
+        Curve::Gamma { gamma: 0.5 },
+    ];
+
+    fn samples() -> impl Iterator<Item = f32> {
+        (0..=100).map(|i| i as f32 / 100.0)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn curves_go_from_0_to_1_without_falling() {
+        let table = Curve::Table {
+            points: vec![0, 100, 100, 400, 1000],
+        };
+
+        for curve in CURVES.iter().chain([&table]) {
+            assert!(close(curve.apply(0.0), 0.0), "{curve:?}");
+            assert!(close(curve.apply(1.0), 1.0), "{curve:?}");
+
+            let ys: Vec<f32> = samples().map(|x| curve.apply(x)).collect();
+            assert!(ys.windows(2).all(|w| w[0] <= w[1]), "{curve:?}: {ys:?}");
+        }
+    }
+
+    #[test]
+    fn curve_shapes() {
+        assert!(close(Curve::Linear.apply(0.3), 0.3));
+        assert!(close(Curve::Gamma { gamma: 2.0 }.apply(0.5), 0.25));
+
+        // positive k stays low for longer, negative k rises early
+        assert!(Curve::Exponential { k: 3.0 }.apply(0.5) < 0.5);
+        assert!(Curve::Exponential { k: -3.0 }.apply(0.5) > 0.5);
+
+        let table = Curve::Table {
+            points: vec![0, 200, 1000],
+        };
+        assert!(close(table.apply(0.25), 0.1));
+        assert!(close(table.apply(0.5), 0.2));
+        assert!(close(table.apply(0.75), 0.6));
+    }
+
+    #[test]
+    fn steep_exponential_never_gives_nan() {
+        // what validate lets through
+        for k in [MAX_EXPONENT, -MAX_EXPONENT] {
+            for x in samples() {
+                let y = Curve::Exponential { k }.apply(x);
+                assert!((0.0..=1.0).contains(&y), "k {k}, x {x}: {y}");
+            }
+        }
+
+        // and what it doesn't, in case one slips past - linear instead of min duty
+        let curve = Curve::Exponential { k: 1000.0 };
+        assert!(close(curve.apply(0.5), 0.5));
+        assert!(close(curve.apply(1.0), 1.0));
+    }
+
+    #[test]
+    fn validate() {
+        for curve in &CURVES {
+            assert!(curve.validate().is_ok(), "{curve:?}");
+        }
+
+        let invalid = [
+            Curve::Exponential { k: 0.0 },
+            Curve::Exponential { k: 20.5 },
+            Curve::Exponential { k: -100.0 },
+            Curve::Exponential { k: f32::NAN },
+            Curve::Exponential { k: f32::INFINITY },
+            Curve::Gamma { gamma: 0.0 },
+            Curve::Gamma { gamma: -1.0 },
+            Curve::Gamma { gamma: f32::NAN },
+            Curve::Table { points: vec![0] },
+            Curve::Table {
+                points: vec![0, 1001],
+            },
+            Curve::Table {
+                points: vec![0, 500, 400],
+            },
+        ];
+        for curve in &invalid {
+            assert!(curve.validate().is_err(), "{curve:?}");
+        }
+    }
+
+    #[test]
+    fn curve_json() {
+        let parse = |json| serde_json::from_str::<Curve>(json).unwrap();
+
+        assert_eq!(parse(r#"{"type": "linear"}"#), Curve::Linear);
+        assert_eq!(
+            parse(r#"{"type": "exponential", "k": 3.0}"#),
+            Curve::Exponential { k: 3.0 }
+        );
+        assert_eq!(
+            parse(r#"{"type": "gamma", "gamma": 2.2}"#),
+            Curve::Gamma { gamma: 2.2 }
+        );
+        assert_eq!(
+            parse(r#"{"type": "table", "points": [0, 100, 1000]}"#),
+            Curve::Table {
+                points: vec![0, 100, 1000]
+            }
+        );
+    }
+
+    #[test]
+    fn duty_window() {
+        for curve in CURVES {
+            let map = IntensityMap::new(500, 1000, 20, curve.clone());
+
+            assert_eq!(map.duty_permille(0), 0, "{curve:?}");
+            assert_eq!(map.duty_permille(MAX_INTENSITY), 1000, "{curve:?}");
+            // above max is treated as max
+            assert_eq!(map.duty_permille(5000), 1000, "{curve:?}");
+
+            let duties: Vec<u32> = (1..=MAX_INTENSITY).map(|i| map.duty_permille(i)).collect();
+            assert!(duties.iter().all(|d| (500..=1000).contains(d)), "{curve:?}");
+            assert!(duties.windows(2).all(|w| w[0] <= w[1]), "{curve:?}");
+        }
+    }
+
+    #[test]
+    fn button_steps() {
+        for steps in [1, 3, 20, 7, MAX_INTENSITY] {
+            let map = IntensityMap::new(0, 1000, steps, Curve::Linear);
+
+            let mut intensity = 0;
+            for _ in 0..steps {
+                let next = map.step_up(intensity);
+                assert!(next > intensity, "{steps} steps, up from {intensity}");
+                intensity = next;
+            }
+            assert_eq!(intensity, MAX_INTENSITY, "{steps} steps");
+            assert_eq!(map.step_up(intensity), MAX_INTENSITY);
+
+            for _ in 0..steps {
+                intensity = map.step_down(intensity);
+            }
+            assert_eq!(intensity, 0, "{steps} steps");
+            assert_eq!(map.step_down(0), 0);
+        }
+
+        // from between two steps, up and down go to the neighbouring steps
+        let map = IntensityMap::new(0, 1000, 20, Curve::Linear);
+        assert_eq!(map.step_up(120), 150);
+        assert_eq!(map.step_down(120), 100);
+    }
+
+    #[test]
+    fn step_conversions() {
+        for step in 0..=20 {
+            assert_eq!(to_steps(from_steps(step, 20), 20), step);
+        }
+
+        assert_eq!(from_steps(30, 20), MAX_INTENSITY);
+        // anything non-zero shows as at least one step
+        assert_eq!(to_steps(1, 20), 1);
+        assert_eq!(to_steps(0, 20), 0);
+        assert_eq!(to_steps(2000, 20), 20);
+    }
+}
@@ -0,0 +1,22 @@
+//! The firmware's plain logic: protocol codecs, state machines and the math behind the motor.
+//!
+//! Nothing in here touches ESP-IDF, so it builds for the host and `cargo test` runs on a laptop.
+//! The firmware re-exports each module at its old path.
+
+pub mod api;
+pub mod arbiter;
+pub mod beacon;
+pub mod ble_ota;
+pub mod emulation;
+pub mod failsafe;
+pub mod hid;
+pub mod intensity;
+pub mod lovense;
+pub mod nus;
+pub mod pairing;
+pub mod pattern;
+pub mod sleep;
+pub mod state;
+pub mod thermal;
+pub mod wand;
+pub mod ws;
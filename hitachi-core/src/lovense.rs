@@ -0,0 +1,543 @@
+//! Lovense text protocol: `Command:arg;` in, `reply;` out.
+//!
+//! No BLE types in here - the firmware feeds [`LovenseDecoder`] from the RX characteristic and
+//! notifies what [`LovenseDevice`] answers. The tests replay what the common apps send.
+
+use std::fmt::{Display, Write};
+
+/// `Vibrate:n;` goes from 0 to this
+pub const LOVENSE_STEPS: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LovenseMessage {
+    /// `Vibrate:n;`, `Vibrate1:n;` and `Vibrate2:n;` - we only have the one motor
+    Vibrate(u8),
+    /// built-in pattern. 0 stops, anything else picks from [`crate::pattern::PatternKind::ALL`]
+    Preset(u8),
+    DeviceType,
+    Battery,
+    Status,
+    GetLight,
+    Light(bool),
+    PowerOff,
+    /// `Rotate:n;`, `RotateChange;` and friends - accepted so apps don't choke, but ignored
+    NoOp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LovenseError {
+    Empty,
+    UnknownCommand,
+    MissingArgument,
+    InvalidArgument,
+    InvalidUtf8,
+    /// a command grew past [`LovenseDecoder::MAX_BUFFERED`] without a `;`
+    Overflow,
+}
+
+impl Display for LovenseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LovenseError::Empty => write!(f, "empty command"),
+            LovenseError::UnknownCommand => write!(f, "unknown command"),
+            LovenseError::MissingArgument => write!(f, "missing argument"),
+            LovenseError::InvalidArgument => write!(f, "invalid argument"),
+            LovenseError::InvalidUtf8 => write!(f, "command is not valid UTF-8"),
+            LovenseError::Overflow => write!(f, "command too long"),
+        }
+    }
+}
+
+impl std::error::Error for LovenseError {}
+
+impl LovenseMessage {
+    /// Parses the first `;`-terminated command in `args`, if there is one.
+    pub fn parse(args: &str) -> Option<Result<LovenseMessage, LovenseError>> {
+        let msg_end = args.find(';')?;
+        Some(Self::parse_command(&args[..msg_end]))
+    }
+
+    /// Parses a single command, without its trailing `;`.
+    pub fn parse_command(cmd: &str) -> Result<LovenseMessage, LovenseError> {
+        let mut args = cmd.trim().split(':').map(str::trim);
+        let name = args
+            .next()
+            .filter(|n| !n.is_empty())
+            .ok_or(LovenseError::Empty)?;
+        let mut arg = || args.next().ok_or(LovenseError::MissingArgument);
+
+        Ok(match name {
+            "Vibrate" | "Vibrate1" | "Vibrate2" => LovenseMessage::Vibrate(parse_level(arg()?)?),
+            "Preset" => LovenseMessage::Preset(parse_level(arg()?)?),
+            "DeviceType" => LovenseMessage::DeviceType,
+            "Battery" | "GetBattery" => LovenseMessage::Battery,
+            "Status" => LovenseMessage::Status,
+            "GetLight" => LovenseMessage::GetLight,
+            "Light" => match arg()? {
+                "on" | "1" => LovenseMessage::Light(true),
+                "off" | "0" => LovenseMessage::Light(false),
+                _ => return Err(LovenseError::InvalidArgument),
+            },
+            "PowerOff" => LovenseMessage::PowerOff,
+            "Rotate" | "RotateChange" | "RotateTrue" | "RotateFalse" | "Air" => {
+                LovenseMessage::NoOp
+            }
+            _ => return Err(LovenseError::UnknownCommand),
+        })
+    }
+
+    /// Whether the main loop needs to see this, as opposed to it being answered from
+    /// [`LovenseDevice`] alone.
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            LovenseMessage::Vibrate(_)
+                | LovenseMessage::Preset(_)
+                | LovenseMessage::Light(_)
+                | LovenseMessage::PowerOff
+        )
+    }
+}
+
+/// What we tell apps we are, and the bits of state they can query.
+pub struct LovenseDevice {
+    /// model letter - apps pick the toy's name and feature set from this
+    pub device_type: String,
+    pub firmware: u8,
+    pub mac: [u8; 6],
+    pub light: bool,
+}
+
+impl LovenseDevice {
+    /// Calor
+    pub const DEFAULT_TYPE: &'static str = "H";
+    pub const DEFAULT_FIRMWARE: u8 = 11;
+
+    pub fn new(device_type: String, mac: [u8; 6]) -> Self {
+        Self {
+            device_type,
+            firmware: Self::DEFAULT_FIRMWARE,
+            mac,
+            light: true,
+        }
+    }
+
+    /// Applies `msg` to the queryable state and writes the notification to send back.
+    pub fn respond(&mut self, msg: LovenseMessage, out: &mut String) {
+        out.clear();
+        let _ = match msg {
+            LovenseMessage::DeviceType => {
+                let _ = write!(out, "{}:{}:", self.device_type, self.firmware);
+                for byte in self.mac {
+                    let _ = write!(out, "{byte:02X}");
+                }
+                out.write_char(';')
+            }
+            // mains powered, so always full
+            LovenseMessage::Battery => out.write_str("100;"),
+            // 2 is "normal"
+            LovenseMessage::Status => out.write_str("2;"),
+            LovenseMessage::GetLight => write!(out, "Light:{};", self.light as u8),
+            LovenseMessage::Light(on) => {
+                self.light = on;
+                out.write_str("OK;")
+            }
+            LovenseMessage::Vibrate(_)
+            | LovenseMessage::Preset(_)
+            | LovenseMessage::PowerOff
+            | LovenseMessage::NoOp => out.write_str("OK;"),
+        };
+    }
+
+    /// Writes the reply for a command that failed to parse.
+    pub fn respond_error(&self, _err: LovenseError, out: &mut String) {
+        out.clear();
+        out.push_str("ERR;");
+    }
+}
+
+fn parse_level(arg: &str) -> Result<u8, LovenseError> {
+    arg.parse::<u8>()
+        .ok()
+        .filter(|v| *v as u32 <= LOVENSE_STEPS)
+        .ok_or(LovenseError::InvalidArgument)
+}
+
+/// Per-connection stream decoder.
+///
+/// BLE writes can carry several commands (`Vibrate:5;Vibrate:6;`) or only part of one, so
+/// bytes are buffered until a `;` arrives. Feed every write with [`LovenseDecoder::feed`], then
+/// drain [`LovenseDecoder::next_message`] until it returns `None`.
+#[derive(Default)]
+pub struct LovenseDecoder {
+    buf: Vec<u8>,
+    /// where the next unparsed command starts in `buf`
+    start: usize,
+    /// dropping bytes up to the next `;` after an overflow
+    discarding: bool,
+    /// where in `buf` the dropped command was, so its error comes out in order
+    overflow_at: Option<usize>,
+}
+
+impl LovenseDecoder {
+    pub const MAX_BUFFERED: usize = 64;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.drain(..self.start);
+        if let Some(at) = &mut self.overflow_at {
+            *at -= self.start;
+        }
+        self.start = 0;
+
+        let mut data = data;
+        if self.discarding {
+            match data.iter().position(|b| *b == b';') {
+                Some(end) => {
+                    self.discarding = false;
+                    data = &data[end + 1..];
+                }
+                None => return,
+            }
+        }
+
+        self.buf.extend_from_slice(data);
+
+        // a command that can't fit is dropped whole, instead of being parsed from the middle
+        let pending_len = self
+            .buf
+            .iter()
+            .rposition(|b| *b == b';')
+            .map_or(self.buf.len(), |end| self.buf.len() - end - 1);
+        if pending_len > Self::MAX_BUFFERED {
+            self.buf.truncate(self.buf.len() - pending_len);
+            self.discarding = true;
+            self.overflow_at = Some(self.buf.len());
+        }
+    }
+
+    /// Next complete command, or `None` once only a partial command (or nothing) is left.
+    pub fn next_message(&mut self) -> Option<Result<LovenseMessage, LovenseError>> {
+        if self.overflow_at == Some(self.start) {
+            self.overflow_at = None;
+            return Some(Err(LovenseError::Overflow));
+        }
+
+        let rest = &self.buf[self.start..];
+        let end = rest.iter().position(|b| *b == b';')?;
+
+        let cmd = &rest[..end];
+        self.start += end + 1;
+
+        if cmd.len() > Self::MAX_BUFFERED {
+            return Some(Err(LovenseError::Overflow));
+        }
+
+        Some(
+            std::str::from_utf8(cmd)
+                .map_err(|_| LovenseError::InvalidUtf8)
+                .and_then(LovenseMessage::parse_command),
+        )
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::*, sample::Index};
+
+    use super::*;
+
+    const MAC: [u8; 6] = [0xC4, 0x4F, 0x33, 0x12, 0xAB, 0x0E];
+
+    /// Runs BLE writes through a decoder and device the way the firmware's RX handler does,
+    /// returning the notifications and the messages that would reach the main loop.
+    fn replay(device: &mut LovenseDevice, writes: &[&str]) -> (Vec<String>, Vec<LovenseMessage>) {
+        let mut decoder = LovenseDecoder::new();
+        let mut replies = Vec::new();
+        let mut controls = Vec::new();
+        let mut out = String::new();
+
+        for write in writes {
+            decoder.feed(write.as_bytes());
+            while let Some(res) = decoder.next_message() {
+                match res {
+                    Ok(msg) => {
+                        if msg.is_control() {
+                            controls.push(msg);
+                        }
+                        device.respond(msg, &mut out);
+                    }
+                    Err(LovenseError::Empty) => continue,
+                    Err(e) => device.respond_error(e, &mut out),
+                }
+                replies.push(out.clone());
+            }
+        }
+
+        (replies, controls)
+    }
+
+    fn device() -> LovenseDevice {
+        LovenseDevice::new(LovenseDevice::DEFAULT_TYPE.into(), MAC)
+    }
+
+    #[test]
+    fn lovense_remote_connect() {
+        // Lovense Remote, right after connecting and then moving the slider
+        let (replies, controls) = replay(
+            &mut device(),
+            &[
+                "DeviceType;",
+                "Battery;",
+                "Status:1;",
+                "GetLight;",
+                "Vibrate:4;",
+                "Vibrate:9;",
+                "Vibrate:0;",
+            ],
+        );
+
+        assert_eq!(
+            replies,
+            [
+                "H:11:C44F3312AB0E;",
+                "100;",
+                "2;",
+                "Light:1;",
+                "OK;",
+                "OK;",
+                "OK;"
+            ]
+        );
+        assert_eq!(
+            controls,
+            [
+                LovenseMessage::Vibrate(4),
+                LovenseMessage::Vibrate(9),
+                LovenseMessage::Vibrate(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn buttplug_io() {
+        // Intiface / buttplug.io, which writes several commands in one go and splits long ones
+        let (replies, controls) = replay(
+            &mut device(),
+            &[
+                "DeviceType;",
+                "Vibrate1:20;Vibrate2:20;",
+                "Vibr",
+                "ate:3;",
+                "Battery;",
+            ],
+        );
+
+        assert_eq!(replies, ["H:11:C44F3312AB0E;", "OK;", "OK;", "OK;", "100;"]);
+        assert_eq!(
+            controls,
+            [
+                LovenseMessage::Vibrate(20),
+                LovenseMessage::Vibrate(20),
+                LovenseMessage::Vibrate(3)
+            ]
+        );
+    }
+
+    #[test]
+    fn light_and_presets() {
+        let mut device = device();
+        let (replies, controls) = replay(
+            &mut device,
+            &[
+                "Light:off;",
+                "GetLight;",
+                "Preset:2;",
+                "Preset:0;",
+                "Light:1;",
+                "GetLight;",
+            ],
+        );
+
+        assert_eq!(
+            replies,
+            ["OK;", "Light:0;", "OK;", "OK;", "OK;", "Light:1;"]
+        );
+        assert_eq!(
+            controls,
+            [
+                LovenseMessage::Light(false),
+                LovenseMessage::Preset(2),
+                LovenseMessage::Preset(0),
+                LovenseMessage::Light(true)
+            ]
+        );
+        assert!(device.light);
+    }
+
+    #[test]
+    fn features_we_do_not_have_are_acknowledged() {
+        // what apps send to a Nora or a Max - answered so they carry on, but nothing happens
+        let (replies, controls) = replay(
+            &mut device(),
+            &["Rotate:5;", "RotateChange;", "Air:Level:3;", "PowerOff;"],
+        );
+
+        assert_eq!(replies, ["OK;", "OK;", "OK;", "OK;"]);
+        assert_eq!(controls, [LovenseMessage::PowerOff]);
+    }
+
+    #[test]
+    fn errors() {
+        let (replies, controls) = replay(
+            &mut device(),
+            &[
+                "Vibrate:21;",
+                "Vibrate:-1;",
+                "Vibrate;",
+                "Vibrate:ten;",
+                "Light:dim;",
+                "Explode;",
+                ";;",
+                "Vibrate:5;",
+            ],
+        );
+
+        assert_eq!(
+            replies,
+            ["ERR;", "ERR;", "ERR;", "ERR;", "ERR;", "ERR;", "OK;"]
+        );
+        assert_eq!(controls, [LovenseMessage::Vibrate(5)]);
+    }
+
+    #[test]
+    fn custom_device_type() {
+        let mut device = LovenseDevice::new("W".into(), [0; 6]);
+        device.firmware = 64;
+
+        let (replies, _) = replay(&mut device, &["DeviceType;"]);
+        assert_eq!(replies, ["W:64:000000000000;"]);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            LovenseMessage::parse("Vibrate:7;Battery;"),
+            Some(Ok(LovenseMessage::Vibrate(7)))
+        );
+        assert_eq!(LovenseMessage::parse("Vibrate:7"), None);
+        assert_eq!(
+            LovenseMessage::parse_command(" Vibrate : 7 "),
+            Ok(LovenseMessage::Vibrate(7))
+        );
+        assert_eq!(
+            LovenseMessage::parse_command("GetBattery"),
+            Ok(LovenseMessage::Battery)
+        );
+        assert_eq!(LovenseMessage::parse_command(""), Err(LovenseError::Empty));
+        assert_eq!(
+            LovenseMessage::parse_command("Light"),
+            Err(LovenseError::MissingArgument)
+        );
+    }
+
+    /// Feeds each write and drains the decoder after it, like the firmware.
+    fn decode(writes: &[&[u8]]) -> Vec<Result<LovenseMessage, LovenseError>> {
+        let mut decoder = LovenseDecoder::new();
+        let mut results = Vec::new();
+        for write in writes {
+            decoder.feed(write);
+            results.extend(std::iter::from_fn(|| decoder.next_message()));
+        }
+
+        results
+    }
+
+    /// Commands, fragments of them and junk, glued into one stream.
+    fn stream() -> impl Strategy<Value = Vec<u8>> {
+        let token = prop_oneof![
+            (0u8..=25).prop_map(|n| format!("Vibrate:{n};").into_bytes()),
+            Just(b"DeviceType;".to_vec()),
+            Just(b"Light:off;".to_vec()),
+            Just(b";".to_vec()),
+            "[A-Za-z0-9:]{0,80}".prop_map(String::into_bytes),
+            vec(any::<u8>(), 0..100),
+        ];
+        vec(token, 0..20).prop_map(|tokens| tokens.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn how_writes_are_split_does_not_matter(
+            stream in stream(),
+            cuts in vec(any::<Index>(), 0..10),
+        ) {
+            let mut cuts: Vec<usize> = cuts.iter().map(|i| i.index(stream.len() + 1)).collect();
+            cuts.push(0);
+            cuts.push(stream.len());
+            cuts.sort_unstable();
+            let writes: Vec<&[u8]> = cuts.windows(2).map(|w| &stream[w[0]..w[1]]).collect();
+
+            prop_assert_eq!(decode(&writes), decode(&[&stream]));
+        }
+
+        #[test]
+        fn one_result_per_command(commands in vec("[A-Za-z0-9:]{0,64}", 0..20)) {
+            let stream: String = commands.iter().map(|c| format!("{c};")).collect();
+            let results = decode(&[stream.as_bytes()]);
+
+            prop_assert_eq!(results.len(), commands.len());
+            for (command, res) in commands.iter().zip(results) {
+                prop_assert_eq!(res, LovenseMessage::parse_command(command));
+            }
+        }
+
+        #[test]
+        fn too_long_commands_are_dropped_whole(
+            before in 0u8..=20,
+            junk in "[A-Za-z0-9:]{65,200}",
+            after in 0u8..=20,
+            cut in any::<Index>(),
+        ) {
+            let stream = format!("Vibrate:{before};{junk};Vibrate:{after};");
+            let cut = cut.index(stream.len() + 1);
+            let expected = [
+                Ok(LovenseMessage::Vibrate(before)),
+                Err(LovenseError::Overflow),
+                Ok(LovenseMessage::Vibrate(after)),
+            ];
+
+            prop_assert_eq!(decode(&[stream.as_bytes()]), expected);
+            let (a, b) = stream.as_bytes().split_at(cut);
+            prop_assert_eq!(decode(&[a, b]), expected);
+        }
+
+        #[test]
+        fn arbitrary_bytes_stay_bounded(writes in vec(vec(any::<u8>(), 0..200), 0..20)) {
+            let mut decoder = LovenseDecoder::new();
+            for write in &writes {
+                decoder.feed(write);
+                while decoder.next_message().is_some() {}
+                prop_assert!(decoder.buf.len() - decoder.start <= LovenseDecoder::MAX_BUFFERED);
+            }
+        }
+
+        #[test]
+        fn vibrate_levels(level in 0u32..1000) {
+            let expected = if level <= LOVENSE_STEPS {
+                Ok(LovenseMessage::Vibrate(level as u8))
+            } else {
+                Err(LovenseError::InvalidArgument)
+            };
+
+            prop_assert_eq!(LovenseMessage::parse_command(&format!("Vibrate:{level}")), expected);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+//! Framing for the Nordic UART console.
+//!
+//! Console output is line-oriented: every response ends in `\n`, and gets split into
+//! notifications that each fit the connection's MTU. Chunks break after a newline whenever one
+//! fits, so most notifications carry whole lines, and never inside a UTF-8 sequence - clients
+//! rebuild output by concatenating notifications up to each `\n`.
+//!
+//! Input is buffered per connection into lines the same way, see [`LineBuffer`].
+
+use std::fmt::Display;
+
+use crate::lovense::LovenseMessage;
+
+/// ATT notification header: opcode + attribute handle.
+pub const ATT_HEADER_LEN: usize = 3;
+/// Default ATT MTU before any exchange.
+pub const DEFAULT_MTU: u16 = 23;
+
+/// Largest notification payload for `mtu`.
+pub fn payload_len(mtu: u16) -> usize {
+    (mtu.max(DEFAULT_MTU) as usize) - ATT_HEADER_LEN
+}
+
+/// Splits `data` into chunks of at most `max` bytes. See the module docs for where it breaks.
+pub struct LineChunks<'a> {
+    data: &'a [u8],
+    max: usize,
+}
+
+impl<'a> LineChunks<'a> {
+    pub fn new(data: &'a [u8], max: usize) -> Self {
+        Self {
+            data,
+            max: max.max(4),
+        }
+    }
+}
+
+impl<'a> Iterator for LineChunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let len = if self.data.len() <= self.max {
+            self.data.len()
+        } else if let Some(nl) = self.data[..self.max].iter().rposition(|b| *b == b'\n') {
+            nl + 1
+        } else {
+            // back off to the start of a UTF-8 sequence - continuation bytes are 0b10xxxxxx
+            let mut end = self.max;
+            while end > 0 && (self.data[end] & 0xC0) == 0x80 {
+                end -= 1;
+            }
+            if end == 0 {
+                self.max
+            } else {
+                end
+            }
+        };
+
+        let (chunk, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(chunk)
+    }
+}
+
+/// Longest console line we'll buffer.
+pub const MAX_LINE_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineError {
+    TooLong,
+    InvalidUtf8,
+}
+
+impl Display for LineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LineError::TooLong => write!(f, "line too long (max {MAX_LINE_LEN} bytes)"),
+            LineError::InvalidUtf8 => write!(f, "line is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for LineError {}
+
+/// Per-connection input buffer. Clients split long input into MTU-sized writes, so commands
+/// are only complete once a `\n` or `\r` arrives.
+#[derive(Default)]
+pub struct LineBuffer {
+    buf: Vec<u8>,
+    /// dropping the rest of a line that went over [`MAX_LINE_LEN`]
+    discarding: bool,
+}
+
+impl LineBuffer {
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty() && !self.discarding
+    }
+
+    /// Buffers `data`, calling `on_line` for every complete line in it. Empty lines are skipped.
+    /// An over-long line is reported as soon as it overflows, and the rest of it is dropped.
+    pub fn feed(&mut self, data: &[u8], mut on_line: impl FnMut(Result<&str, LineError>)) {
+        for &byte in data {
+            if byte == b'\n' || byte == b'\r' {
+                if !self.discarding && !self.buf.is_empty() {
+                    on_line(std::str::from_utf8(&self.buf).map_err(|_| LineError::InvalidUtf8));
+                }
+
+                self.buf.clear();
+                self.discarding = false;
+            } else if self.discarding {
+                continue;
+            } else if self.buf.len() >= MAX_LINE_LEN {
+                self.buf.clear();
+                self.discarding = true;
+                on_line(Err(LineError::TooLong));
+            } else {
+                self.buf.push(byte);
+            }
+        }
+    }
+}
+
+/// Lovense apps that find the NUS characteristic write bare `Command;` strings, without a
+/// newline. If `data` is nothing but well-formed Lovense commands, returns them.
+pub fn sniff_lovense(data: &[u8]) -> Option<Vec<LovenseMessage>> {
+    let text = std::str::from_utf8(data)
+        .ok()?
+        .trim_end_matches(['\r', '\n']);
+    let body = text.strip_suffix(';')?;
+
+    body.split(';')
+        .filter(|cmd| !cmd.trim().is_empty())
+        .map(|cmd| LovenseMessage::parse_command(cmd).ok())
+        .collect::<Option<Vec<_>>>()
+        .filter(|msgs| !msgs.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(data: &str, max: usize) -> Vec<&str> {
+        LineChunks::new(data.as_bytes(), max)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn payload_len_never_goes_below_the_default_mtu() {
+        assert_eq!(payload_len(0), 20);
+        assert_eq!(payload_len(DEFAULT_MTU), 20);
+        assert_eq!(payload_len(247), 244);
+    }
+
+    #[test]
+    fn empty_output_sends_nothing() {
+        assert_eq!(chunks("", 20), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn short_output_is_one_chunk() {
+        assert_eq!(chunks("OK\n", 20), ["OK\n"]);
+    }
+
+    #[test]
+    fn line_exactly_at_the_mtu() {
+        let line = format!("{}\n", "a".repeat(19));
+        assert_eq!(chunks(&line, 20), [line.as_str()]);
+
+        let two = format!("{line}b\n");
+        assert_eq!(chunks(&two, 20), [line.as_str(), "b\n"]);
+    }
+
+    #[test]
+    fn breaks_after_the_last_newline_that_fits() {
+        assert_eq!(
+            chunks("one\ntwo\nthree\nfour\n", 12),
+            ["one\ntwo\n", "three\nfour\n"]
+        );
+    }
+
+    #[test]
+    fn line_longer_than_the_mtu() {
+        let line = format!("{}\n", "a".repeat(45));
+        assert_eq!(
+            chunks(&line, 20),
+            [
+                "a".repeat(20),
+                "a".repeat(20),
+                format!("{}\n", "a".repeat(5))
+            ]
+        );
+    }
+
+    #[test]
+    fn never_splits_a_utf8_sequence() {
+        // 'é' is 2 bytes, starting at byte 19
+        let line = format!("{}é tail\n", "a".repeat(19));
+        assert_eq!(chunks(&line, 20), ["a".repeat(19), "é tail\n".into()]);
+
+        // 4-byte characters back to back
+        let line = "🦀".repeat(6);
+        assert_eq!(chunks(&line, 10), ["🦀🦀", "🦀🦀", "🦀🦀"]);
+    }
+
+    #[test]
+    fn concatenated_chunks_are_the_output() {
+        let output = "Session: 1, 2 connected\nstatus: ok ✓\n\nällo wörld 🦀🦀🦀\n".repeat(3);
+        for max in 4..=64 {
+            let chunks: Vec<&[u8]> = LineChunks::new(output.as_bytes(), max).collect();
+            assert!(
+                chunks.iter().all(|c| !c.is_empty() && c.len() <= max),
+                "{max}"
+            );
+            assert!(
+                chunks.iter().all(|c| std::str::from_utf8(c).is_ok()),
+                "{max}"
+            );
+            assert_eq!(chunks.concat(), output.as_bytes(), "{max}");
+        }
+    }
+
+    #[test]
+    fn tiny_mtu_still_fits_any_character() {
+        assert_eq!(chunks("🦀🦀", 1), ["🦀", "🦀"]);
+    }
+
+    fn lines(buffer: &mut LineBuffer, data: &[u8]) -> Vec<Result<String, LineError>> {
+        let mut lines = Vec::new();
+        buffer.feed(data, |line| lines.push(line.map(str::to_owned)));
+        lines
+    }
+
+    fn ok(lines: &[&str]) -> Vec<Result<String, LineError>> {
+        lines.iter().map(|line| Ok(line.to_string())).collect()
+    }
+
+    #[test]
+    fn lines_wait_for_their_newline() {
+        let mut buffer = LineBuffer::default();
+        assert!(buffer.is_empty());
+
+        assert_eq!(lines(&mut buffer, b"sta"), ok(&[]));
+        assert!(!buffer.is_empty());
+        assert_eq!(lines(&mut buffer, b"tus"), ok(&[]));
+        assert_eq!(lines(&mut buffer, b"\n"), ok(&["status"]));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn several_lines_in_one_write() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(
+            lines(&mut buffer, b"status\nwifi get ssid\nsle"),
+            ok(&["status", "wifi get ssid"])
+        );
+        assert_eq!(lines(&mut buffer, b"ep 20m\n"), ok(&["sleep 20m"]));
+    }
+
+    #[test]
+    fn crlf_and_blank_lines() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(
+            lines(&mut buffer, b"one\r\ntwo\rthree\n\n\r\n"),
+            ok(&["one", "two", "three"])
+        );
+        // the \n of a \r\n split across writes isn't another line
+        assert_eq!(lines(&mut buffer, b"four\r"), ok(&["four"]));
+        assert_eq!(lines(&mut buffer, b"\nfive\n"), ok(&["five"]));
+    }
+
+    #[test]
+    fn longest_line_fits() {
+        let mut buffer = LineBuffer::default();
+        let line = "a".repeat(MAX_LINE_LEN);
+        assert_eq!(
+            lines(&mut buffer, format!("{line}\n").as_bytes()),
+            ok(&[&line])
+        );
+    }
+
+    #[test]
+    fn overflow_is_reported_once_and_the_rest_dropped() {
+        let mut buffer = LineBuffer::default();
+
+        assert_eq!(
+            lines(&mut buffer, &[b'a'; MAX_LINE_LEN + 1]),
+            [Err(LineError::TooLong)]
+        );
+        assert!(!buffer.is_empty());
+        assert_eq!(lines(&mut buffer, &[b'a'; 1000]), []);
+        assert_eq!(lines(&mut buffer, b"aaa\nstatus\n"), ok(&["status"]));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_per_line() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(
+            lines(&mut buffer, b"\xff\xfe\nstatus\n"),
+            [Err(LineError::InvalidUtf8), Ok("status".into())]
+        );
+    }
+
+    #[test]
+    fn multibyte_characters_split_across_writes() {
+        let mut buffer = LineBuffer::default();
+        let line = "wifi set ssid Café".as_bytes();
+        let (start, end) = line.split_at(line.len() - 1);
+
+        assert_eq!(lines(&mut buffer, start), ok(&[]));
+        assert_eq!(lines(&mut buffer, end), ok(&[]));
+        assert_eq!(lines(&mut buffer, b"\n"), ok(&["wifi set ssid Café"]));
+    }
+
+    #[test]
+    fn sniffs_lovense_commands() {
+        assert_eq!(
+            sniff_lovense(b"Vibrate:10;"),
+            Some(vec![LovenseMessage::Vibrate(10)])
+        );
+        assert_eq!(
+            sniff_lovense(b"Vibrate:0;Vibrate:5;\r\n"),
+            Some(vec![LovenseMessage::Vibrate(0), LovenseMessage::Vibrate(5)])
+        );
+        assert_eq!(
+            sniff_lovense(b"DeviceType;Battery;"),
+            Some(vec![LovenseMessage::DeviceType, LovenseMessage::Battery])
+        );
+    }
+
+    #[test]
+    fn console_input_is_not_lovense() {
+        for data in [
+            &b"status\n"[..],
+            b"Vibrate:10",
+            b"Vibrate:10\n",
+            b";",
+            b";;",
+            b"",
+            b"Vibrate:10;status;",
+            b"Vibrate:99;",
+            b"\xffVibrate:1;",
+        ] {
+            assert_eq!(
+                sniff_lovense(data),
+                None,
+                "{:?}",
+                String::from_utf8_lossy(data)
+            );
+        }
+    }
+}
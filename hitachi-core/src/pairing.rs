@@ -0,0 +1,112 @@
+//! The pairing window: a stretch of time in which centrals we haven't bonded with may connect,
+//! and so bond. The firmware's `PairingHandle` opens it and clocks it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PairingCheck {
+    #[default]
+    Closed,
+    /// new bonds are accepted for another `remaining_ms`
+    Open { remaining_ms: u64 },
+}
+
+#[derive(Default)]
+pub struct PairingWindow {
+    deadline_ms: Option<u64>,
+}
+
+impl PairingWindow {
+    /// Opens the window for `duration_ms`, or extends it if it's already open.
+    pub fn open(&mut self, now_ms: u64, duration_ms: u64) {
+        self.deadline_ms = Some(now_ms.saturating_add(duration_ms));
+    }
+
+    pub fn is_open(&self, now_ms: u64) -> bool {
+        self.deadline_ms.is_some_and(|deadline| now_ms < deadline)
+    }
+
+    /// Closes the window once it has run out.
+    pub fn check(&mut self, now_ms: u64) -> PairingCheck {
+        match self.deadline_ms {
+            Some(deadline) if now_ms < deadline => PairingCheck::Open {
+                remaining_ms: deadline - now_ms,
+            },
+            _ => {
+                self.deadline_ms = None;
+                PairingCheck::Closed
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_closed() {
+        let mut window = PairingWindow::default();
+
+        assert!(!window.is_open(0));
+        assert_eq!(window.check(0), PairingCheck::Closed);
+    }
+
+    #[test]
+    fn open_until_it_expires() {
+        let mut window = PairingWindow::default();
+        window.open(1_000, 60_000);
+
+        assert!(window.is_open(1_000));
+        assert_eq!(
+            window.check(1_000),
+            PairingCheck::Open {
+                remaining_ms: 60_000
+            }
+        );
+        assert!(window.is_open(60_999));
+        assert_eq!(window.check(60_999), PairingCheck::Open { remaining_ms: 1 });
+
+        assert!(!window.is_open(61_000));
+        assert_eq!(window.check(61_000), PairingCheck::Closed);
+        assert_eq!(window.check(61_250), PairingCheck::Closed);
+    }
+
+    #[test]
+    fn expired_window_is_closed_before_the_next_check() {
+        let mut window = PairingWindow::default();
+        window.open(0, 1_000);
+
+        // a connection between the deadline and the supervisor noticing
+        assert!(!window.is_open(1_100));
+    }
+
+    #[test]
+    fn reopening_extends_it() {
+        let mut window = PairingWindow::default();
+        window.open(0, 60_000);
+        window.open(30_000, 60_000);
+
+        assert!(window.is_open(89_999));
+        assert_eq!(window.check(90_000), PairingCheck::Closed);
+    }
+
+    #[test]
+    fn reopening_after_it_closed() {
+        let mut window = PairingWindow::default();
+        window.open(0, 1_000);
+        assert_eq!(window.check(2_000), PairingCheck::Closed);
+
+        window.open(5_000, 1_000);
+        assert_eq!(
+            window.check(5_500),
+            PairingCheck::Open { remaining_ms: 500 }
+        );
+    }
+
+    #[test]
+    fn huge_duration_does_not_wrap_around() {
+        let mut window = PairingWindow::default();
+        window.open(1_000, u64::MAX);
+
+        assert!(window.is_open(2_000));
+    }
+}
@@ -0,0 +1,266 @@
+//! Timed motor waveforms, as plain math over the milliseconds since a pattern started. The
+//! firmware's `PatternPlayer` is what samples them onto the motor.
+
+use std::{fmt::Display, str::FromStr};
+
+/// Number of plateaus in a [`PatternKind::Stairs`] period.
+pub const STAIR_STEPS: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PatternKind {
+    /// on at `high` for the first half of the period, `low` for the second
+    #[default]
+    Pulse,
+    /// sine wave between `low` and `high`
+    Wave,
+    /// sawtooth: rises linearly from `low` to `high`, then drops back
+    Ramp,
+    /// like [`PatternKind::Ramp`], but in [`STAIR_STEPS`] discrete steps
+    Stairs,
+    /// jumps to a new pseudo-random level every period
+    Random,
+}
+
+impl PatternKind {
+    pub const ALL: [PatternKind; 5] = [
+        PatternKind::Pulse,
+        PatternKind::Wave,
+        PatternKind::Ramp,
+        PatternKind::Stairs,
+        PatternKind::Random,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PatternKind::Pulse => "pulse",
+            PatternKind::Wave => "wave",
+            PatternKind::Ramp => "ramp",
+            PatternKind::Stairs => "stairs",
+            PatternKind::Random => "random",
+        }
+    }
+
+    pub fn next(&self) -> PatternKind {
+        let idx = Self::ALL.iter().position(|k| k == self).unwrap_or(0);
+        Self::ALL[(idx + 1) % Self::ALL.len()]
+    }
+
+    pub fn prev(&self) -> PatternKind {
+        let idx = Self::ALL.iter().position(|k| k == self).unwrap_or(0);
+        Self::ALL[(idx + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+}
+
+impl Display for PatternKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for PatternKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PatternKind::ALL
+            .into_iter()
+            .find(|k| k.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Invalid pattern {s}. Valid patterns are pulse, wave, ramp, stairs and random"
+                )
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    /// length of one full cycle, in milliseconds
+    pub period_ms: u32,
+    pub low: u32,
+    pub high: u32,
+}
+
+impl Pattern {
+    pub const DEFAULT_PERIOD_MS: u32 = 2000;
+
+    pub fn new(kind: PatternKind, low: u32, high: u32) -> Self {
+        Self {
+            kind,
+            period_ms: Self::DEFAULT_PERIOD_MS,
+            low,
+            high,
+        }
+    }
+
+    pub fn with_period(mut self, period_ms: u32) -> Self {
+        self.period_ms = period_ms;
+        self
+    }
+
+    /// Level the motor should be at `elapsed_ms` after the pattern started.
+    pub fn level_at(&self, elapsed_ms: u64) -> u32 {
+        let (low, high) = if self.low <= self.high {
+            (self.low, self.high)
+        } else {
+            (self.high, self.low)
+        };
+        let span = (high - low) as u64;
+        let period = self.period_ms.max(1) as u64;
+        let phase = elapsed_ms % period;
+        let cycle = elapsed_ms / period;
+
+        let offset = match self.kind {
+            PatternKind::Pulse => {
+                if phase < period / 2 {
+                    span
+                } else {
+                    0
+                }
+            }
+            PatternKind::Wave => {
+                // starts at the bottom of the wave so the motor doesn't kick on at full power
+                let angle = (phase as f32 / period as f32) * std::f32::consts::TAU;
+                let amplitude = (1.0 - angle.cos()) / 2.0;
+                (amplitude * span as f32).round() as u64
+            }
+            PatternKind::Ramp => span * phase / period,
+            PatternKind::Stairs => {
+                let step = phase * STAIR_STEPS / period;
+                span * step / (STAIR_STEPS - 1)
+            }
+            PatternKind::Random => splitmix64(cycle) % (span + 1),
+        };
+
+        low + offset.min(span) as u32
+    }
+}
+
+/// Cheap stateless hash, so [`PatternKind::Random`] is reproducible for a given elapsed time.
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternCommand {
+    Start(Pattern),
+    /// switch to the next/previous built-in pattern, starting the first one if none is running
+    Next,
+    Previous,
+    Stop,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(pattern: Pattern, times: &[u64]) -> Vec<u32> {
+        times.iter().map(|t| pattern.level_at(*t)).collect()
+    }
+
+    #[test]
+    fn pulse() {
+        let pattern = Pattern::new(PatternKind::Pulse, 100, 900).with_period(1000);
+        assert_eq!(
+            levels(pattern, &[0, 499, 500, 999, 1000, 1500]),
+            [900, 900, 100, 100, 900, 100]
+        );
+    }
+
+    #[test]
+    fn wave_starts_at_the_bottom() {
+        let pattern = Pattern::new(PatternKind::Wave, 0, 1000).with_period(1000);
+        assert_eq!(
+            levels(pattern, &[0, 250, 500, 750, 1000]),
+            [0, 500, 1000, 500, 0]
+        );
+
+        // symmetric around the peak
+        for t in 0..500 {
+            assert_eq!(pattern.level_at(t), pattern.level_at(1000 - t), "at {t}ms");
+        }
+    }
+
+    #[test]
+    fn ramp() {
+        let pattern = Pattern::new(PatternKind::Ramp, 200, 600).with_period(400);
+        assert_eq!(
+            levels(pattern, &[0, 100, 200, 399, 400]),
+            [200, 300, 400, 599, 200]
+        );
+    }
+
+    #[test]
+    fn stairs() {
+        let pattern = Pattern::new(PatternKind::Stairs, 0, 1000).with_period(500);
+        assert_eq!(
+            levels(pattern, &[0, 99, 100, 200, 300, 400, 499, 500]),
+            [0, 0, 250, 500, 750, 1000, 1000, 0]
+        );
+    }
+
+    #[test]
+    fn random_holds_for_a_period_and_repeats() {
+        let pattern = Pattern::new(PatternKind::Random, 300, 700).with_period(100);
+
+        for cycle in 0..200 {
+            let level = pattern.level_at(cycle * 100);
+            assert!((300..=700).contains(&level), "cycle {cycle}: {level}");
+            assert_eq!(pattern.level_at(cycle * 100 + 99), level);
+        }
+
+        let changes = (0..200)
+            .filter(|cycle| pattern.level_at(cycle * 100) != pattern.level_at(cycle * 100 + 100))
+            .count();
+        assert!(changes > 150, "only {changes} changes in 200 periods");
+    }
+
+    #[test]
+    fn every_kind_stays_in_bounds() {
+        for kind in PatternKind::ALL {
+            // low and high the wrong way round are swapped
+            for (low, high) in [(0, 1000), (250, 750), (800, 200), (500, 500)] {
+                let pattern = Pattern::new(kind, low, high).with_period(333);
+                for t in (0..3000).step_by(7) {
+                    let level = pattern.level_at(t);
+                    assert!(
+                        (low.min(high)..=low.max(high)).contains(&level),
+                        "{kind} {low}..{high} at {t}ms: {level}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zero_period_does_not_panic() {
+        for kind in PatternKind::ALL {
+            let pattern = Pattern::new(kind, 0, 1000).with_period(0);
+            pattern.level_at(0);
+            pattern.level_at(u64::MAX);
+        }
+    }
+
+    #[test]
+    fn next_and_prev_cycle() {
+        assert_eq!(PatternKind::Pulse.prev(), PatternKind::Random);
+        assert_eq!(PatternKind::Random.next(), PatternKind::Pulse);
+
+        for kind in PatternKind::ALL {
+            assert_eq!(kind.next().prev(), kind);
+        }
+    }
+
+    #[test]
+    fn names() {
+        for kind in PatternKind::ALL {
+            assert_eq!(kind.name().parse::<PatternKind>().unwrap(), kind);
+        }
+
+        assert_eq!("WAVE".parse::<PatternKind>().unwrap(), PatternKind::Wave);
+        assert!("square".parse::<PatternKind>().is_err());
+    }
+}
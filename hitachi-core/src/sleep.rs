@@ -0,0 +1,251 @@
+//! Inactivity auto-off and the user-set sleep timer.
+//!
+//! The auto-off only counts while the motor is running, so a wand left off never "times out".
+//! The sleep timer is set from the console or the buttons, and its last [`WARNING_MS`] are
+//! reported separately so the lights can warn before the motor stops. The firmware's
+//! `SleepHandle` clocks it and turns the result into events.
+
+/// How long before the sleep timer runs out the lights start flashing.
+pub const WARNING_MS: u64 = 30_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SleepCheck {
+    #[default]
+    Idle,
+    /// the sleep timer runs out in `remaining_ms`
+    Warning { remaining_ms: u64 },
+    /// the sleep timer ran out, or nothing touched the controls for the idle timeout
+    Expired,
+}
+
+pub struct SleepTimer {
+    idle_timeout_ms: Option<u64>,
+    last_input_ms: u64,
+    deadline_ms: Option<u64>,
+}
+
+impl SleepTimer {
+    /// `idle_timeout_ms` of `None` disables the inactivity auto-off.
+    pub fn new(idle_timeout_ms: Option<u64>) -> Self {
+        Self {
+            idle_timeout_ms,
+            last_input_ms: 0,
+            deadline_ms: None,
+        }
+    }
+
+    /// Records user input, pushing back the inactivity auto-off.
+    pub fn touch(&mut self, now_ms: u64) {
+        self.last_input_ms = now_ms;
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout_ms: Option<u64>) {
+        self.idle_timeout_ms = idle_timeout_ms;
+    }
+
+    pub fn set_timer(&mut self, now_ms: u64, duration_ms: u64) {
+        self.deadline_ms = Some(now_ms.saturating_add(duration_ms));
+    }
+
+    pub fn cancel_timer(&mut self) -> bool {
+        self.deadline_ms.take().is_some()
+    }
+
+    /// Time left on the sleep timer, if one is set.
+    pub fn remaining(&self, now_ms: u64) -> Option<u64> {
+        self.deadline_ms.map(|d| d.saturating_sub(now_ms))
+    }
+
+    /// `motor_running` gates the inactivity auto-off - a motor that's already off can't be idle.
+    pub fn check(&mut self, now_ms: u64, motor_running: bool) -> SleepCheck {
+        if let Some(remaining_ms) = self.remaining(now_ms) {
+            if remaining_ms == 0 {
+                self.deadline_ms = None;
+                return SleepCheck::Expired;
+            }
+
+            if remaining_ms <= WARNING_MS {
+                return SleepCheck::Warning { remaining_ms };
+            }
+        }
+
+        if let Some(timeout) = self.idle_timeout_ms {
+            if motor_running && now_ms.saturating_sub(self.last_input_ms) >= timeout {
+                // restart the countdown so this fires once, not every check
+                self.last_input_ms = now_ms;
+                return SleepCheck::Expired;
+            }
+        }
+
+        SleepCheck::Idle
+    }
+}
+
+/// Parses durations like `20m`, `90s`, `1h` or a bare number of minutes.
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, unit_ms) = match s.char_indices().last()? {
+        (idx, 's') => (&s[..idx], 1000),
+        (idx, 'm') => (&s[..idx], 60 * 1000),
+        (idx, 'h') => (&s[..idx], 60 * 60 * 1000),
+        _ => (s, 60 * 1000),
+    };
+
+    num.parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .and_then(|n| n.checked_mul(unit_ms))
+}
+
+/// Formats milliseconds as e.g. `19m 58s`.
+pub fn format_remaining(ms: u64) -> String {
+    let secs = ms / 1000;
+    if secs >= 3600 {
+        format!("{}h {}m", secs / 3600, (secs % 3600) / 60)
+    } else {
+        format!("{}m {}s", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_timeout_only_counts_while_running() {
+        let mut timer = SleepTimer::new(Some(10_000));
+
+        assert_eq!(timer.check(60_000, false), SleepCheck::Idle);
+
+        timer.touch(100_000);
+        assert_eq!(timer.check(109_999, true), SleepCheck::Idle);
+        assert_eq!(timer.check(110_000, true), SleepCheck::Expired);
+        // fires once, then counts down again
+        assert_eq!(timer.check(110_500, true), SleepCheck::Idle);
+        assert_eq!(timer.check(120_000, true), SleepCheck::Expired);
+    }
+
+    #[test]
+    fn touch_pushes_back_the_idle_timeout() {
+        let mut timer = SleepTimer::new(Some(10_000));
+
+        timer.touch(5_000);
+        assert_eq!(timer.check(14_000, true), SleepCheck::Idle);
+        timer.touch(14_000);
+        assert_eq!(timer.check(20_000, true), SleepCheck::Idle);
+        assert_eq!(timer.check(24_000, true), SleepCheck::Expired);
+    }
+
+    #[test]
+    fn no_idle_timeout() {
+        let mut timer = SleepTimer::new(None);
+        assert_eq!(timer.check(u64::MAX, true), SleepCheck::Idle);
+
+        timer.set_idle_timeout(Some(1_000));
+        assert_eq!(timer.check(1_000, true), SleepCheck::Expired);
+    }
+
+    #[test]
+    fn sleep_timer_warns_then_expires_once() {
+        let mut timer = SleepTimer::new(None);
+        timer.set_timer(1_000, 60_000);
+
+        assert_eq!(timer.remaining(1_000), Some(60_000));
+        assert_eq!(timer.check(30_999, false), SleepCheck::Idle);
+        assert_eq!(
+            timer.check(31_000, false),
+            SleepCheck::Warning {
+                remaining_ms: WARNING_MS
+            }
+        );
+        assert_eq!(
+            timer.check(60_999, false),
+            SleepCheck::Warning { remaining_ms: 1 }
+        );
+        assert_eq!(timer.check(61_000, false), SleepCheck::Expired);
+
+        assert_eq!(timer.remaining(61_000), None);
+        assert_eq!(timer.check(62_000, false), SleepCheck::Idle);
+    }
+
+    #[test]
+    fn late_check_still_expires() {
+        let mut timer = SleepTimer::new(None);
+        timer.set_timer(0, 1_000);
+
+        assert_eq!(timer.remaining(5_000), Some(0));
+        assert_eq!(timer.check(5_000, false), SleepCheck::Expired);
+    }
+
+    #[test]
+    fn cancel_timer() {
+        let mut timer = SleepTimer::new(None);
+        assert!(!timer.cancel_timer());
+
+        timer.set_timer(0, 1_000);
+        assert!(timer.cancel_timer());
+        assert_eq!(timer.remaining(0), None);
+        assert_eq!(timer.check(2_000, false), SleepCheck::Idle);
+    }
+
+    #[test]
+    fn huge_timer_does_not_wrap_around() {
+        let mut timer = SleepTimer::new(None);
+        timer.set_timer(1_000, u64::MAX);
+
+        assert_eq!(timer.check(2_000, false), SleepCheck::Idle);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s"), Some(90_000));
+        assert_eq!(parse_duration("20m"), Some(20 * 60_000));
+        assert_eq!(parse_duration("1h"), Some(3_600_000));
+        assert_eq!(parse_duration("20"), Some(20 * 60_000));
+        assert_eq!(parse_duration(" 5m\n"), Some(5 * 60_000));
+    }
+
+    #[test]
+    fn zero_is_not_a_duration() {
+        assert_eq!(parse_duration("0"), None);
+        assert_eq!(parse_duration("0s"), None);
+        assert_eq!(parse_duration("00h"), None);
+    }
+
+    #[test]
+    fn bad_durations() {
+        for s in ["", " ", "m", "5x", "5 m", "-5m", "1.5h", "5ms", "h5", "5é"] {
+            assert_eq!(parse_duration(s), None, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn overflowing_durations() {
+        // fits in a u64 on its own, but not once it's in milliseconds
+        assert_eq!(parse_duration(&format!("{}h", u64::MAX / 1000)), None);
+        assert_eq!(parse_duration(&format!("{}s", u64::MAX)), None);
+        assert_eq!(parse_duration("99999999999999999999999"), None);
+
+        assert_eq!(
+            parse_duration(&format!("{}s", u64::MAX / 1000)),
+            Some(u64::MAX / 1000 * 1000)
+        );
+    }
+
+    #[test]
+    fn remaining_rounds_down_to_the_second() {
+        assert_eq!(format_remaining(0), "0m 0s");
+        assert_eq!(format_remaining(999), "0m 0s");
+        assert_eq!(format_remaining(59_999), "0m 59s");
+        assert_eq!(format_remaining(60_000), "1m 0s");
+        assert_eq!(format_remaining(20 * 60_000 - 1_500), "19m 58s");
+    }
+
+    #[test]
+    fn remaining_switches_to_hours() {
+        assert_eq!(format_remaining(3_599_999), "59m 59s");
+        assert_eq!(format_remaining(3_600_000), "1h 0m");
+        assert_eq!(format_remaining(5_459_000), "1h 30m");
+        assert_eq!(format_remaining(100 * 3_600_000), "100h 0m");
+    }
+}
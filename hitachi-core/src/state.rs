@@ -0,0 +1,52 @@
+//! What remote clients get to see of the device: the motor level plus the bits of state the
+//! firmware publishes from its other threads.
+
+use crate::{arbiter::Arbiter, pattern::Pattern, thermal::ThermalState};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotorLevel {
+    /// the intensity that was last requested
+    pub target: u32,
+    /// the intensity the motor is actually running at, which lags behind `target` while ramping
+    pub actual: u32,
+}
+
+/// Bits of device state that remote clients can query, beyond what the motor tracks itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DeviceState {
+    /// last thermistor reading, `None` until the first one (or if reads are failing)
+    pub motor_temp_c: Option<f32>,
+    /// the ESP's internal sensor
+    pub chip_temp_c: Option<f32>,
+    pub thermal: ThermalState,
+    pub pattern: Option<Pattern>,
+    /// who is driving the motor
+    pub control: Arbiter,
+}
+
+/// [`DeviceState`] plus the motor level at the time it was taken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    pub motor: MotorLevel,
+    pub device: DeviceState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    Off = 0,
+    Manual = 1,
+    Pattern = 2,
+}
+
+impl Snapshot {
+    pub fn mode(&self) -> Mode {
+        if self.device.pattern.is_some() {
+            Mode::Pattern
+        } else if self.motor.target > 0 {
+            Mode::Manual
+        } else {
+            Mode::Off
+        }
+    }
+}
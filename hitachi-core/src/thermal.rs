@@ -0,0 +1,176 @@
+//! Thermal derating policy: turns motor temperature readings into the highest intensity the
+//! motor may run at. The firmware's `ThermalSupervisor` takes the readings and applies the cap.
+
+use crate::intensity::MAX_INTENSITY;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ThermalState {
+    #[default]
+    Normal,
+    /// above the soft limit - motor intensity is capped at `cap`
+    Derating { cap: u32 },
+    /// went over the hard limit. stays latched until it cools down past the hysteresis
+    Shutdown,
+}
+
+impl ThermalState {
+    /// Highest intensity the motor may run at in this state.
+    pub fn cap(&self) -> u32 {
+        match self {
+            ThermalState::Normal => MAX_INTENSITY,
+            ThermalState::Derating { cap } => *cap,
+            ThermalState::Shutdown => 0,
+        }
+    }
+}
+
+/// Failed thermistor reads in a row before the motor is shut down. A disconnected or shorted
+/// thermistor would otherwise leave the motor running with no overheat protection at all.
+pub const MAX_READ_FAILURES: u32 = 3;
+
+pub struct ThermalGuard {
+    soft_limit: f32,
+    hard_limit: f32,
+    hysteresis: f32,
+    latched: bool,
+    /// failed reads since the last good one
+    failures: u32,
+    last: ThermalState,
+}
+
+impl ThermalGuard {
+    /// Limits in °C, with `soft_limit` below `hard_limit`. Once over the hard limit, the motor
+    /// stays off until it has cooled down to `hard_limit - hysteresis`.
+    pub fn new(soft_limit: f32, hard_limit: f32, hysteresis: f32) -> Self {
+        Self {
+            soft_limit,
+            hard_limit,
+            hysteresis,
+            latched: false,
+            failures: 0,
+            last: ThermalState::Normal,
+        }
+    }
+
+    pub fn update(&mut self, temp_c: f32) -> ThermalState {
+        self.failures = 0;
+        self.last = self.evaluate(temp_c);
+        self.last
+    }
+
+    /// A read that failed. Keeps the last state for a few polls, then shuts the motor down
+    /// until a reading comes through again.
+    pub fn read_failed(&mut self) -> ThermalState {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= MAX_READ_FAILURES {
+            self.last = ThermalState::Shutdown;
+        }
+
+        self.last
+    }
+
+    fn evaluate(&mut self, temp_c: f32) -> ThermalState {
+        if temp_c >= self.hard_limit {
+            self.latched = true;
+        } else if self.latched && temp_c <= self.hard_limit - self.hysteresis {
+            self.latched = false;
+        }
+
+        if self.latched {
+            return ThermalState::Shutdown;
+        }
+
+        if temp_c <= self.soft_limit {
+            return ThermalState::Normal;
+        }
+
+        let headroom = (self.hard_limit - temp_c) / (self.hard_limit - self.soft_limit);
+        ThermalState::Derating {
+            cap: (headroom.clamp(0.0, 1.0) * MAX_INTENSITY as f32) as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> ThermalGuard {
+        ThermalGuard::new(60.0, 75.0, 10.0)
+    }
+
+    #[test]
+    fn derates_linearly_between_the_limits() {
+        let mut guard = guard();
+
+        assert_eq!(guard.update(25.0), ThermalState::Normal);
+        assert_eq!(guard.update(60.0), ThermalState::Normal);
+        assert_eq!(guard.update(67.5), ThermalState::Derating { cap: 500 });
+        assert_eq!(guard.update(72.0), ThermalState::Derating { cap: 200 });
+        // and back up as it cools, without any hysteresis
+        assert_eq!(guard.update(63.0), ThermalState::Derating { cap: 800 });
+        assert_eq!(guard.update(59.9), ThermalState::Normal);
+    }
+
+    #[test]
+    fn shutdown_latches_until_cooled_past_the_hysteresis() {
+        let mut guard = guard();
+
+        assert_eq!(guard.update(75.0), ThermalState::Shutdown);
+        assert_eq!(guard.update(74.0), ThermalState::Shutdown);
+        assert_eq!(guard.update(65.1), ThermalState::Shutdown);
+        assert_eq!(guard.update(65.0), ThermalState::Derating { cap: 666 });
+        assert_eq!(guard.update(70.0), ThermalState::Derating { cap: 333 });
+
+        // latches again on the next overheat
+        assert_eq!(guard.update(80.0), ThermalState::Shutdown);
+        assert_eq!(guard.update(70.0), ThermalState::Shutdown);
+        assert_eq!(guard.update(40.0), ThermalState::Normal);
+    }
+
+    #[test]
+    fn hysteresis_past_the_soft_limit() {
+        let mut guard = ThermalGuard::new(60.0, 75.0, 30.0);
+
+        assert_eq!(guard.update(76.0), ThermalState::Shutdown);
+        assert_eq!(guard.update(50.0), ThermalState::Shutdown);
+        assert_eq!(guard.update(45.0), ThermalState::Normal);
+    }
+
+    #[test]
+    fn failed_reads_shut_down_until_readings_recover() {
+        let mut guard = guard();
+        assert_eq!(guard.update(70.0), ThermalState::Derating { cap: 333 });
+
+        for _ in 1..MAX_READ_FAILURES {
+            assert_eq!(guard.read_failed(), ThermalState::Derating { cap: 333 });
+        }
+        assert_eq!(guard.read_failed(), ThermalState::Shutdown);
+        assert_eq!(guard.read_failed(), ThermalState::Shutdown);
+
+        assert_eq!(guard.update(30.0), ThermalState::Normal);
+
+        // a good reading in between starts the count again
+        for _ in 1..MAX_READ_FAILURES {
+            assert_eq!(guard.read_failed(), ThermalState::Normal);
+        }
+        assert_eq!(guard.update(30.0), ThermalState::Normal);
+        assert_eq!(guard.read_failed(), ThermalState::Normal);
+    }
+
+    #[test]
+    fn failed_reads_keep_an_overheat_latched() {
+        let mut guard = guard();
+
+        assert_eq!(guard.update(80.0), ThermalState::Shutdown);
+        assert_eq!(guard.read_failed(), ThermalState::Shutdown);
+        assert_eq!(guard.update(70.0), ThermalState::Shutdown);
+    }
+
+    #[test]
+    fn caps() {
+        assert_eq!(ThermalState::Normal.cap(), MAX_INTENSITY);
+        assert_eq!(ThermalState::Derating { cap: 420 }.cap(), 420);
+        assert_eq!(ThermalState::Shutdown.cap(), 0);
+    }
+}
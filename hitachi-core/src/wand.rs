@@ -0,0 +1,303 @@
+//! Binary schema for our own GATT control service.
+//!
+//! All integers are little-endian. The schema characteristic holds [`SCHEMA_VERSION`]; clients
+//! should refuse to talk to a wand whose major version they don't know.
+//!
+//! | characteristic | props        | layout                                                      |
+//! |----------------|--------------|-------------------------------------------------------------|
+//! | schema         | read         | `u16` version                                               |
+//! | intensity      | read, write  | `u16` target intensity, 0..=1000                            |
+//! | pattern        | write        | `u8` id, `u32` period ms, `u16` low, `u16` high (see below) |
+//! | stop           | write        | `u8`, any non-zero value stops the motor                    |
+//! | actual         | read, notify | `u16` actual intensity                                      |
+//! | temperature    | read, notify | `i16` motor temperature in 0.01°C, `i16::MIN` if unknown    |
+//! | state          | read, notify | `u8` [`Mode`](crate::state::Mode), `u8` fault flags, `u16` thermal cap |
+//! | firmware       | read         | UTF-8 version string                                        |
+//!
+//! Pattern ids: 0 stops, 1.. are [`PatternKind::ALL`] in order, 0xFE is next and 0xFF previous.
+//! The period and levels can be left off; a period of 0 uses the default.
+
+use std::fmt::Display;
+
+use crate::{
+    intensity::MAX_INTENSITY,
+    pattern::{Pattern, PatternCommand, PatternKind},
+    state::Snapshot,
+    thermal::ThermalState,
+};
+
+pub const SCHEMA_VERSION: u16 = 1;
+
+pub const SERVICE: &str = "7a6e0001-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+pub const SCHEMA_CHAR: &str = "7a6e0002-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+pub const INTENSITY_CHAR: &str = "7a6e0003-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+pub const PATTERN_CHAR: &str = "7a6e0004-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+pub const STOP_CHAR: &str = "7a6e0005-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+pub const ACTUAL_CHAR: &str = "7a6e0006-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+pub const TEMPERATURE_CHAR: &str = "7a6e0007-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+pub const STATE_CHAR: &str = "7a6e0008-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+pub const FIRMWARE_CHAR: &str = "7a6e0009-3b1e-4a8c-9c2f-8d5e6f1a2b3c";
+
+pub const FAULT_DERATING: u8 = 1 << 0;
+pub const FAULT_OVERHEAT: u8 = 1 << 1;
+pub const FAULT_NO_TEMP: u8 = 1 << 2;
+
+const PATTERN_NEXT: u8 = 0xFE;
+const PATTERN_PREV: u8 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WandError {
+    Length,
+    OutOfRange,
+    UnknownPattern,
+}
+
+impl Display for WandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WandError::Length => write!(f, "wrong payload length"),
+            WandError::OutOfRange => write!(f, "value out of range"),
+            WandError::UnknownPattern => write!(f, "unknown pattern id"),
+        }
+    }
+}
+
+impl std::error::Error for WandError {}
+
+pub fn decode_intensity(data: &[u8]) -> Result<u32, WandError> {
+    let value = match data {
+        [lo, hi] => u16::from_le_bytes([*lo, *hi]) as u32,
+        _ => return Err(WandError::Length),
+    };
+
+    if value > MAX_INTENSITY {
+        return Err(WandError::OutOfRange);
+    }
+
+    Ok(value)
+}
+
+pub fn decode_pattern(data: &[u8]) -> Result<PatternCommand, WandError> {
+    let (&id, params) = data.split_first().ok_or(WandError::Length)?;
+
+    let kind = match id {
+        0 => return Ok(PatternCommand::Stop),
+        PATTERN_NEXT => return Ok(PatternCommand::Next),
+        PATTERN_PREV => return Ok(PatternCommand::Previous),
+        id => *PatternKind::ALL
+            .get(id as usize - 1)
+            .ok_or(WandError::UnknownPattern)?,
+    };
+
+    let (period, low, high) = match params {
+        [] => (0, 0, MAX_INTENSITY),
+        [p0, p1, p2, p3] => (u32::from_le_bytes([*p0, *p1, *p2, *p3]), 0, MAX_INTENSITY),
+        [p0, p1, p2, p3, l0, l1, h0, h1] => (
+            u32::from_le_bytes([*p0, *p1, *p2, *p3]),
+            u16::from_le_bytes([*l0, *l1]) as u32,
+            u16::from_le_bytes([*h0, *h1]) as u32,
+        ),
+        _ => return Err(WandError::Length),
+    };
+
+    if low > MAX_INTENSITY || high > MAX_INTENSITY {
+        return Err(WandError::OutOfRange);
+    }
+
+    let pattern = Pattern::new(kind, low, high);
+    Ok(PatternCommand::Start(if period == 0 {
+        pattern
+    } else {
+        pattern.with_period(period)
+    }))
+}
+
+pub fn decode_stop(data: &[u8]) -> Result<bool, WandError> {
+    match data {
+        [flag] => Ok(*flag != 0),
+        _ => Err(WandError::Length),
+    }
+}
+
+pub fn encode_intensity(intensity: u32) -> [u8; 2] {
+    (intensity.min(MAX_INTENSITY) as u16).to_le_bytes()
+}
+
+pub fn encode_temperature(temp_c: Option<f32>) -> [u8; 2] {
+    temp_c
+        .filter(|t| !t.is_nan())
+        .map(|t| {
+            (t * 100.0)
+                .round()
+                .clamp(i16::MIN as f32 + 1.0, i16::MAX as f32) as i16
+        })
+        .unwrap_or(i16::MIN)
+        .to_le_bytes()
+}
+
+pub fn encode_state(snapshot: &Snapshot) -> [u8; 4] {
+    let mut flags = 0;
+    match snapshot.device.thermal {
+        ThermalState::Normal => {}
+        ThermalState::Derating { .. } => flags |= FAULT_DERATING,
+        ThermalState::Shutdown => flags |= FAULT_OVERHEAT,
+    }
+
+    if snapshot.device.motor_temp_c.is_none() {
+        flags |= FAULT_NO_TEMP;
+    }
+
+    let cap = (snapshot.device.thermal.cap() as u16).to_le_bytes();
+    [snapshot.mode() as u8, flags, cap[0], cap[1]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{DeviceState, MotorLevel};
+
+    fn pattern(kind: PatternKind, period_ms: u32, low: u32, high: u32) -> PatternCommand {
+        PatternCommand::Start(Pattern::new(kind, low, high).with_period(period_ms))
+    }
+
+    #[test]
+    fn intensity_round_trip() {
+        for level in [0, 1, 255, 256, 999, MAX_INTENSITY] {
+            assert_eq!(decode_intensity(&encode_intensity(level)), Ok(level));
+        }
+        assert_eq!(decode_intensity(&[0xE8, 0x03]), Ok(1000));
+        assert_eq!(encode_intensity(5000), encode_intensity(MAX_INTENSITY));
+    }
+
+    #[test]
+    fn intensity_above_1000_is_rejected() {
+        assert_eq!(decode_intensity(&[0xE9, 0x03]), Err(WandError::OutOfRange));
+        assert_eq!(decode_intensity(&[0xFF, 0xFF]), Err(WandError::OutOfRange));
+    }
+
+    #[test]
+    fn intensity_length() {
+        for data in [&[][..], &[0x10], &[0x10, 0x00, 0x00]] {
+            assert_eq!(decode_intensity(data), Err(WandError::Length), "{data:?}");
+        }
+    }
+
+    #[test]
+    fn pattern_ids() {
+        assert_eq!(decode_pattern(&[0]), Ok(PatternCommand::Stop));
+        assert_eq!(decode_pattern(&[0xFE]), Ok(PatternCommand::Next));
+        assert_eq!(decode_pattern(&[0xFF]), Ok(PatternCommand::Previous));
+
+        for (i, kind) in PatternKind::ALL.into_iter().enumerate() {
+            assert_eq!(
+                decode_pattern(&[i as u8 + 1]),
+                Ok(PatternCommand::Start(Pattern::new(kind, 0, MAX_INTENSITY)))
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_pattern_ids() {
+        let first_unknown = PatternKind::ALL.len() as u8 + 1;
+        for id in [first_unknown, 0x80, 0xFD] {
+            assert_eq!(decode_pattern(&[id]), Err(WandError::UnknownPattern));
+        }
+    }
+
+    #[test]
+    fn pattern_with_period() {
+        assert_eq!(
+            decode_pattern(&[2, 0xB8, 0x0B, 0x00, 0x00]),
+            Ok(pattern(PatternKind::Wave, 3000, 0, MAX_INTENSITY))
+        );
+        // 0 keeps the default
+        assert_eq!(
+            decode_pattern(&[2, 0, 0, 0, 0]),
+            Ok(pattern(
+                PatternKind::Wave,
+                Pattern::DEFAULT_PERIOD_MS,
+                0,
+                MAX_INTENSITY
+            ))
+        );
+    }
+
+    #[test]
+    fn pattern_with_levels() {
+        assert_eq!(
+            decode_pattern(&[3, 0xE8, 0x03, 0x00, 0x00, 0xC8, 0x00, 0x20, 0x03]),
+            Ok(pattern(PatternKind::Ramp, 1000, 200, 800))
+        );
+        assert_eq!(
+            decode_pattern(&[3, 0, 0, 0, 0, 0, 0, 0xE9, 0x03]),
+            Err(WandError::OutOfRange)
+        );
+        assert_eq!(
+            decode_pattern(&[3, 0, 0, 0, 0, 0xE9, 0x03, 0, 0]),
+            Err(WandError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn pattern_length() {
+        assert_eq!(decode_pattern(&[]), Err(WandError::Length));
+        for len in [1, 2, 3, 5, 6, 7, 9] {
+            let mut data = vec![1];
+            data.resize(len + 1, 0);
+            assert_eq!(decode_pattern(&data), Err(WandError::Length), "{len}");
+        }
+    }
+
+    #[test]
+    fn stop() {
+        assert_eq!(decode_stop(&[1]), Ok(true));
+        assert_eq!(decode_stop(&[0xFF]), Ok(true));
+        assert_eq!(decode_stop(&[0]), Ok(false));
+        assert_eq!(decode_stop(&[]), Err(WandError::Length));
+        assert_eq!(decode_stop(&[1, 1]), Err(WandError::Length));
+    }
+
+    #[test]
+    fn temperature() {
+        assert_eq!(encode_temperature(Some(36.25)), 3625i16.to_le_bytes());
+        assert_eq!(encode_temperature(Some(-5.004)), (-500i16).to_le_bytes());
+        assert_eq!(encode_temperature(Some(0.0)), [0, 0]);
+        assert_eq!(encode_temperature(None), i16::MIN.to_le_bytes());
+    }
+
+    #[test]
+    fn temperature_never_reads_as_unknown_or_garbage() {
+        assert_eq!(encode_temperature(Some(1e6)), i16::MAX.to_le_bytes());
+        assert_eq!(encode_temperature(Some(-1e6)), (i16::MIN + 1).to_le_bytes());
+        assert_eq!(encode_temperature(Some(f32::NAN)), i16::MIN.to_le_bytes());
+    }
+
+    #[test]
+    fn state() {
+        let mut snapshot = Snapshot {
+            motor: MotorLevel {
+                target: 300,
+                actual: 250,
+            },
+            device: DeviceState {
+                motor_temp_c: Some(40.0),
+                ..Default::default()
+            },
+        };
+        assert_eq!(encode_state(&snapshot), [1, 0, 0xE8, 0x03]);
+
+        snapshot.device.thermal = ThermalState::Derating { cap: 600 };
+        snapshot.device.motor_temp_c = None;
+        assert_eq!(
+            encode_state(&snapshot),
+            [1, FAULT_DERATING | FAULT_NO_TEMP, 0x58, 0x02]
+        );
+
+        snapshot.device.thermal = ThermalState::Shutdown;
+        snapshot.motor.target = 0;
+        assert_eq!(
+            encode_state(&snapshot),
+            [0, FAULT_OVERHEAT | FAULT_NO_TEMP, 0, 0]
+        );
+    }
+}
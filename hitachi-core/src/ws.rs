@@ -0,0 +1,300 @@
+//! Binary message schema for the WebSocket control stream at `/ws`.
+//!
+//! Every message is one binary frame; the first byte is its type, integers are little-endian.
+//!
+//! | type   | direction      | payload                                                        |
+//! |--------|----------------|----------------------------------------------------------------|
+//! | `0x01` | client -> wand | `u16` target intensity, 0..=1000                               |
+//! | `0x02` | client -> wand | pattern, laid out like the BLE pattern characteristic (`wand`) |
+//! | `0x03` | client -> wand | none, stops the motor                                          |
+//! | `0x04` | client -> wand | none, keepalive - answered with `0x84`                         |
+//! | `0x81` | wand -> client | state, see [`encode_state`]                                    |
+//! | `0x84` | wand -> client | none, keepalive answer                                         |
+//! | `0xEE` | wand -> client | UTF-8 error message for a rejected message                     |
+//!
+//! See [`wand`] for the pattern layout. The wand sends the state right after connecting,
+//! whenever it changes, and at least every [`KEEPALIVE_INTERVAL_MS`]. A socket that sends
+//! nothing for [`KEEPALIVE_TIMEOUT_MS`] is closed.
+
+use std::fmt::Display;
+
+use crate::{
+    pattern::PatternCommand,
+    state::Snapshot,
+    wand::{self, WandError},
+};
+
+pub const KEEPALIVE_INTERVAL_MS: u64 = 5_000;
+pub const KEEPALIVE_TIMEOUT_MS: u64 = 15_000;
+
+/// Nothing the client sends is longer than a pattern.
+pub const MAX_MESSAGE_LEN: usize = 32;
+
+const MSG_INTENSITY: u8 = 0x01;
+const MSG_PATTERN: u8 = 0x02;
+const MSG_STOP: u8 = 0x03;
+const MSG_PING: u8 = 0x04;
+
+pub const MSG_STATE: u8 = 0x81;
+pub const MSG_PONG: u8 = 0x84;
+pub const MSG_ERROR: u8 = 0xEE;
+
+pub const PONG: [u8; 1] = [MSG_PONG];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsCommand {
+    SetIntensity(u32),
+    Pattern(PatternCommand),
+    Stop,
+    Ping,
+}
+
+impl WsCommand {
+    /// Whether this one starts or changes the motor, i.e. makes the socket the controlling link.
+    pub fn is_control(&self) -> bool {
+        match self {
+            WsCommand::SetIntensity(_) => true,
+            WsCommand::Pattern(cmd) => *cmd != PatternCommand::Stop,
+            WsCommand::Stop | WsCommand::Ping => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WsError {
+    Empty,
+    UnknownType(u8),
+    Payload(WandError),
+    /// text or fragmented frames
+    NotBinary,
+    /// the event queue was full
+    Busy,
+}
+
+impl Display for WsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WsError::Empty => write!(f, "empty message"),
+            WsError::UnknownType(t) => write!(f, "unknown message type {t:#04x}"),
+            WsError::Payload(e) => write!(f, "{e}"),
+            WsError::NotBinary => write!(f, "only single binary frames are understood"),
+            WsError::Busy => write!(f, "the wand is busy, try again"),
+        }
+    }
+}
+
+impl std::error::Error for WsError {}
+
+impl From<WandError> for WsError {
+    fn from(e: WandError) -> Self {
+        WsError::Payload(e)
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<WsCommand, WsError> {
+    let (&kind, payload) = data.split_first().ok_or(WsError::Empty)?;
+
+    match kind {
+        MSG_INTENSITY => Ok(WsCommand::SetIntensity(wand::decode_intensity(payload)?)),
+        MSG_PATTERN => Ok(WsCommand::Pattern(wand::decode_pattern(payload)?)),
+        MSG_STOP | MSG_PING if !payload.is_empty() => Err(WandError::Length.into()),
+        MSG_STOP => Ok(WsCommand::Stop),
+        MSG_PING => Ok(WsCommand::Ping),
+        kind => Err(WsError::UnknownType(kind)),
+    }
+}
+
+pub fn encode_error(error: &WsError) -> Vec<u8> {
+    let mut out = vec![MSG_ERROR];
+    out.extend_from_slice(error.to_string().as_bytes());
+    out
+}
+
+/// `u16` target, `u16` actual, `i16` motor temperature in 0.01°C (`i16::MIN` if unknown), then
+/// the 4 bytes of the BLE state characteristic: `u8` mode, `u8` fault flags, `u16` thermal cap.
+pub fn encode_state(snapshot: &Snapshot) -> [u8; 11] {
+    let mut out = [0; 11];
+    out[0] = MSG_STATE;
+    out[1..3].copy_from_slice(&wand::encode_intensity(snapshot.motor.target));
+    out[3..5].copy_from_slice(&wand::encode_intensity(snapshot.motor.actual));
+    out[5..7].copy_from_slice(&wand::encode_temperature(snapshot.device.motor_temp_c));
+    out[7..11].copy_from_slice(&wand::encode_state(snapshot));
+    out
+}
+
+/// When each open socket was last heard from.
+#[derive(Debug, Default)]
+pub struct Keepalive {
+    sockets: Vec<(i32, u64)>,
+}
+
+impl Keepalive {
+    pub fn seen(&mut self, session: i32, now_ms: u64) {
+        match self.sockets.iter_mut().find(|(s, _)| *s == session) {
+            Some((_, last_seen)) => *last_seen = now_ms,
+            None => self.sockets.push((session, now_ms)),
+        }
+    }
+
+    pub fn close(&mut self, session: i32) {
+        self.sockets.retain(|(s, _)| *s != session);
+    }
+
+    /// Forgets and returns the sockets that have been silent for [`KEEPALIVE_TIMEOUT_MS`].
+    pub fn expired(&mut self, now_ms: u64) -> Vec<i32> {
+        let mut expired = Vec::new();
+        self.sockets.retain(|(session, last_seen)| {
+            let alive = now_ms.saturating_sub(*last_seen) < KEEPALIVE_TIMEOUT_MS;
+            if !alive {
+                expired.push(*session);
+            }
+            alive
+        });
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        intensity::MAX_INTENSITY,
+        pattern::{Pattern, PatternKind},
+        state::{DeviceState, MotorLevel},
+    };
+
+    #[test]
+    fn decodes_commands() {
+        assert_eq!(
+            decode(&[0x01, 0xF4, 0x01]),
+            Ok(WsCommand::SetIntensity(500))
+        );
+        assert_eq!(
+            decode(&[0x02, 2]),
+            Ok(WsCommand::Pattern(PatternCommand::Start(Pattern::new(
+                PatternKind::Wave,
+                0,
+                MAX_INTENSITY
+            ))))
+        );
+        assert_eq!(
+            decode(&[0x02, 0xFE]),
+            Ok(WsCommand::Pattern(PatternCommand::Next))
+        );
+        assert_eq!(decode(&[0x03]), Ok(WsCommand::Stop));
+        assert_eq!(decode(&[0x04]), Ok(WsCommand::Ping));
+    }
+
+    #[test]
+    fn rejects_bad_messages() {
+        assert_eq!(decode(&[]), Err(WsError::Empty));
+        assert_eq!(decode(&[0x05]), Err(WsError::UnknownType(0x05)));
+        assert_eq!(decode(&[MSG_STATE]), Err(WsError::UnknownType(MSG_STATE)));
+        assert_eq!(
+            decode(&[0x01, 0xE9, 0x03]),
+            Err(WsError::Payload(WandError::OutOfRange))
+        );
+        assert_eq!(
+            decode(&[0x01, 0x10]),
+            Err(WsError::Payload(WandError::Length))
+        );
+        assert_eq!(
+            decode(&[0x02, 0x40]),
+            Err(WsError::Payload(WandError::UnknownPattern))
+        );
+        assert_eq!(
+            decode(&[0x03, 0x00]),
+            Err(WsError::Payload(WandError::Length))
+        );
+        assert_eq!(
+            decode(&[0x04, 0x00]),
+            Err(WsError::Payload(WandError::Length))
+        );
+    }
+
+    #[test]
+    fn longest_message_fits() {
+        let pattern = [0x02, 1, 0xD0, 0x07, 0, 0, 0, 0, 0xE8, 0x03];
+
+        assert!(decode(&pattern).is_ok());
+        assert!(pattern.len() <= MAX_MESSAGE_LEN);
+    }
+
+    #[test]
+    fn only_motor_changes_take_control() {
+        assert!(WsCommand::SetIntensity(0).is_control());
+        assert!(WsCommand::Pattern(PatternCommand::Next).is_control());
+        assert!(WsCommand::Pattern(PatternCommand::Previous).is_control());
+
+        assert!(!WsCommand::Pattern(PatternCommand::Stop).is_control());
+        assert!(!WsCommand::Stop.is_control());
+        assert!(!WsCommand::Ping.is_control());
+    }
+
+    #[test]
+    fn error_frames() {
+        assert_eq!(encode_error(&WsError::Empty), b"\xEEempty message");
+        assert_eq!(
+            encode_error(&WsError::UnknownType(7)),
+            b"\xEEunknown message type 0x07"
+        );
+    }
+
+    #[test]
+    fn state_frame() {
+        let snapshot = Snapshot {
+            motor: MotorLevel {
+                target: 600,
+                actual: 420,
+            },
+            device: DeviceState {
+                motor_temp_c: Some(36.25),
+                ..Default::default()
+            },
+        };
+
+        assert_eq!(
+            encode_state(&snapshot),
+            [MSG_STATE, 0x58, 0x02, 0xA4, 0x01, 0x29, 0x0E, 1, 0, 0xE8, 0x03]
+        );
+    }
+
+    #[test]
+    fn keepalive_expires_silent_sockets_once() {
+        let mut keepalive = Keepalive::default();
+        keepalive.seen(1, 0);
+        keepalive.seen(2, 10_000);
+
+        assert_eq!(
+            keepalive.expired(KEEPALIVE_TIMEOUT_MS - 1),
+            Vec::<i32>::new()
+        );
+        assert_eq!(keepalive.expired(KEEPALIVE_TIMEOUT_MS), [1]);
+        assert_eq!(
+            keepalive.expired(KEEPALIVE_TIMEOUT_MS + 1),
+            Vec::<i32>::new()
+        );
+        assert_eq!(keepalive.expired(10_000 + KEEPALIVE_TIMEOUT_MS), [2]);
+    }
+
+    #[test]
+    fn keepalive_seen_pushes_back_the_timeout() {
+        let mut keepalive = Keepalive::default();
+        keepalive.seen(1, 0);
+        keepalive.seen(1, 10_000);
+
+        assert_eq!(keepalive.expired(KEEPALIVE_TIMEOUT_MS), Vec::<i32>::new());
+        assert_eq!(keepalive.expired(10_000 + KEEPALIVE_TIMEOUT_MS), [1]);
+    }
+
+    #[test]
+    fn closed_sockets_are_forgotten() {
+        let mut keepalive = Keepalive::default();
+        keepalive.seen(1, 0);
+        keepalive.close(1);
+        keepalive.close(5);
+
+        assert_eq!(keepalive.expired(u64::MAX), Vec::<i32>::new());
+    }
+}
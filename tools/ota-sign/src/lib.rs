@@ -0,0 +1,269 @@
+//! Signed firmware images for OTA updates.
+//!
+//! A signed image is the packed compressed image from `idf.py gen_compressed_ota` with a header
+//! in front:
+//!
+//! | bytes   | content                                                  |
+//! |---------|----------------------------------------------------------|
+//! | `0..4`  | [`MAGIC`]                                                |
+//! | `4..68` | Ed25519ph signature (RFC 8032) of the image, [`CONTEXT`] |
+//! | `68..`  | the packed image                                         |
+//!
+//! The prehashed variant means the wand can check an image while it streams into flash, without
+//! ever holding all of it. The wand and this crate's tests run the same [`Verifier`].
+
+use std::fmt::Display;
+
+use ed25519_dalek::{
+    Signature, SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH, SIGNATURE_LENGTH,
+};
+use sha2::{Digest, Sha512};
+
+pub use ed25519_dalek;
+
+pub const MAGIC: [u8; 4] = *b"HSIG";
+pub const HEADER_LEN: usize = MAGIC.len() + SIGNATURE_LENGTH;
+
+/// Keeps these signatures from being valid for anything but firmware images.
+pub const CONTEXT: &[u8] = b"esp-hitachi ota v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// doesn't start with [`MAGIC`]
+    NotSigned,
+    /// ended before the end of the header
+    Truncated,
+    InvalidKey,
+    BadSignature,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NotSigned => write!(f, "the image isn't signed"),
+            Error::Truncated => write!(f, "the image is shorter than its signature header"),
+            Error::InvalidKey => write!(f, "invalid public key"),
+            Error::BadSignature => write!(f, "the signature doesn't match the image or the key"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Checks a signed image fed to it in pieces of any size.
+#[derive(Clone)]
+pub struct Verifier {
+    header: [u8; HEADER_LEN],
+    header_len: usize,
+    image: Sha512,
+}
+
+impl Verifier {
+    pub fn new() -> Self {
+        Self {
+            header: [0; HEADER_LEN],
+            header_len: 0,
+            image: Sha512::new(),
+        }
+    }
+
+    /// Takes the next piece of the signed image and returns the part of it that belongs to the
+    /// image itself, i.e. what should be written to flash. Fails as soon as the magic is wrong.
+    pub fn update<'a>(&mut self, data: &'a [u8]) -> Result<&'a [u8], Error> {
+        let take = (HEADER_LEN - self.header_len).min(data.len());
+        self.header[self.header_len..self.header_len + take].copy_from_slice(&data[..take]);
+        self.header_len += take;
+
+        if self.header_len >= MAGIC.len() && self.header[..MAGIC.len()] != MAGIC {
+            return Err(Error::NotSigned);
+        }
+
+        let image = &data[take..];
+        self.image.update(image);
+        Ok(image)
+    }
+
+    /// Checks the signature over everything passed to [`Verifier::update`].
+    pub fn verify(self, public_key: &[u8; PUBLIC_KEY_LENGTH]) -> Result<(), Error> {
+        if self.header_len < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+
+        let key = VerifyingKey::from_bytes(public_key).map_err(|_| Error::InvalidKey)?;
+        let signature =
+            Signature::from_slice(&self.header[MAGIC.len()..]).map_err(|_| Error::BadSignature)?;
+
+        key.verify_prehashed_strict(self.image, Some(CONTEXT), &signature)
+            .map_err(|_| Error::BadSignature)
+    }
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks a whole signed image at once, returning the image without its header.
+pub fn verify<'a>(
+    public_key: &[u8; PUBLIC_KEY_LENGTH],
+    signed: &'a [u8],
+) -> Result<&'a [u8], Error> {
+    let mut verifier = Verifier::new();
+    let image = verifier.update(signed)?;
+    verifier.verify(public_key)?;
+    Ok(image)
+}
+
+/// Puts the signature header in front of `image`.
+pub fn sign(key: &SigningKey, image: &[u8]) -> Vec<u8> {
+    let signature = key
+        .sign_prehashed(Sha512::new_with_prefix(image), Some(CONTEXT))
+        .expect("the context is shorter than 256 bytes");
+
+    let mut signed = Vec::with_capacity(HEADER_LEN + image.len());
+    signed.extend_from_slice(&MAGIC);
+    signed.extend_from_slice(&signature.to_bytes());
+    signed.extend_from_slice(image);
+    signed
+}
+
+/// Keys are stored as hex: 64 characters for both the secret seed and the public key.
+pub fn encode_key(key: &[u8; 32]) -> String {
+    key.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn decode_key(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut key = [0; 32];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(key)
+}
+
+/// Like [`decode_key`], but also checks it's a usable public key.
+pub fn decode_public_key(hex: &str) -> Result<[u8; PUBLIC_KEY_LENGTH], Error> {
+    let key = decode_key(hex).ok_or(Error::InvalidKey)?;
+    VerifyingKey::from_bytes(&key).map_err(|_| Error::InvalidKey)?;
+    Ok(key)
+}
+
+pub fn decode_signing_key(hex: &str) -> Option<SigningKey> {
+    let seed: [u8; SECRET_KEY_LENGTH] = decode_key(hex)?;
+    Some(SigningKey::from_bytes(&seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn public_key() -> [u8; 32] {
+        key().verifying_key().to_bytes()
+    }
+
+    fn image() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = image();
+        let signed = sign(&key(), &image);
+
+        assert_eq!(signed.len(), HEADER_LEN + image.len());
+        assert_eq!(verify(&public_key(), &signed), Ok(&image[..]));
+    }
+
+    #[test]
+    fn streamed_in_any_chunk_size() {
+        let image = image();
+        let signed = sign(&key(), &image);
+
+        for chunk_size in [1, 3, 4, 5, 67, 68, 69, 244, 4096] {
+            let mut verifier = Verifier::new();
+            let mut written = Vec::new();
+            for chunk in signed.chunks(chunk_size) {
+                written.extend_from_slice(verifier.update(chunk).unwrap());
+            }
+
+            assert_eq!(written, image, "chunk size {chunk_size}");
+            assert_eq!(verifier.verify(&public_key()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn tampered_image() {
+        let mut signed = sign(&key(), &image());
+        let last = signed.len() - 1;
+        signed[last] ^= 1;
+
+        assert_eq!(verify(&public_key(), &signed), Err(Error::BadSignature));
+    }
+
+    #[test]
+    fn tampered_signature() {
+        let mut signed = sign(&key(), &image());
+        signed[MAGIC.len() + 10] ^= 1;
+
+        assert_eq!(verify(&public_key(), &signed), Err(Error::BadSignature));
+    }
+
+    #[test]
+    fn other_key() {
+        let signed = sign(&SigningKey::from_bytes(&[8; 32]), &image());
+        assert_eq!(verify(&public_key(), &signed), Err(Error::BadSignature));
+    }
+
+    #[test]
+    fn plain_ed25519_signature_is_rejected() {
+        use ed25519_dalek::Signer;
+
+        let image = image();
+        let mut signed = MAGIC.to_vec();
+        signed.extend_from_slice(&key().sign(&image).to_bytes());
+        signed.extend_from_slice(&image);
+
+        assert_eq!(verify(&public_key(), &signed), Err(Error::BadSignature));
+    }
+
+    #[test]
+    fn unsigned_image_fails_early() {
+        let mut verifier = Verifier::new();
+        assert_eq!(verifier.update(b"HS"), Ok(&[][..]));
+        assert_eq!(verifier.update(b"xx"), Err(Error::NotSigned));
+    }
+
+    #[test]
+    fn truncated() {
+        let signed = sign(&key(), &image());
+        let mut verifier = Verifier::new();
+        verifier.update(&signed[..HEADER_LEN - 1]).unwrap();
+
+        assert_eq!(verifier.verify(&public_key()), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn keys() {
+        let hex = encode_key(&public_key());
+        assert_eq!(decode_public_key(&hex), Ok(public_key()));
+        assert_eq!(decode_public_key(&format!("{hex}\n")), Ok(public_key()));
+        assert_eq!(decode_public_key(&hex[1..]), Err(Error::InvalidKey));
+        assert_eq!(decode_public_key(&"zz".repeat(32)), Err(Error::InvalidKey));
+
+        let secret = encode_key(&key().to_bytes());
+        assert_eq!(
+            decode_signing_key(&secret).map(|k| k.to_bytes()),
+            Some(key().to_bytes())
+        );
+    }
+}
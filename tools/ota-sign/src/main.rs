@@ -0,0 +1,98 @@
+//! Signs firmware images for OTA updates - see the library for the format.
+
+use std::{fs::OpenOptions, io::Write, path::Path, process::ExitCode};
+
+use ed25519_dalek::SigningKey;
+use ota_sign::{decode_public_key, decode_signing_key, encode_key};
+
+const USAGE: &str = "\
+usage:
+  ota-sign keygen <name>                     writes <name>.sec and <name>.pub
+  ota-sign sign <key.sec> <image> [<out>]    writes <image>.signed unless <out> is given
+  ota-sign verify <key.pub> <signed image>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let res = match args[..] {
+        ["keygen", name] => keygen(name),
+        ["sign", key, image] => sign(key, image, &format!("{image}.signed")),
+        ["sign", key, image, out] => sign(key, image, out),
+        ["verify", key, signed] => verify(key, signed),
+        _ => Err(USAGE.into()),
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn keygen(name: &str) -> Result<(), String> {
+    let (secret, public) = (format!("{name}.sec"), format!("{name}.pub"));
+    // never clobber a key that images are already signed with
+    for path in [&secret, &public] {
+        if Path::new(path).exists() {
+            return Err(format!("{path} already exists"));
+        }
+    }
+
+    let mut seed = [0; 32];
+    getrandom::getrandom(&mut seed).map_err(|e| format!("failed to get randomness: {e}"))?;
+    let key = SigningKey::from_bytes(&seed);
+
+    write_secret(&secret, encode_key(&key.to_bytes()) + "\n")?;
+    write(&public, encode_key(&key.verifying_key().to_bytes()) + "\n")?;
+    println!("wrote {secret} (keep it private) and {public} (built into the firmware)");
+    Ok(())
+}
+
+fn sign(key: &str, image: &str, out: &str) -> Result<(), String> {
+    let key = decode_signing_key(&read_to_string(key)?)
+        .ok_or_else(|| format!("{key} isn't a secret key"))?;
+    let image = read(image)?;
+    if image.starts_with(&ota_sign::MAGIC) {
+        return Err("the image is already signed".into());
+    }
+
+    write(out, ota_sign::sign(&key, &image))?;
+    println!("wrote {out}");
+    Ok(())
+}
+
+fn verify(key: &str, signed: &str) -> Result<(), String> {
+    let key = decode_public_key(&read_to_string(key)?).map_err(|e| format!("{key}: {e}"))?;
+    let contents = read(signed)?;
+    let image = ota_sign::verify(&key, &contents).map_err(|e| format!("{signed}: {e}"))?;
+    println!("{signed}: good signature over {} bytes", image.len());
+    Ok(())
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("failed to read {path}: {e}"))
+}
+
+fn read_to_string(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("failed to read {path}: {e}"))
+}
+
+fn write(path: &str, contents: impl AsRef<[u8]>) -> Result<(), String> {
+    std::fs::write(path, contents).map_err(|e| format!("failed to write {path}: {e}"))
+}
+
+/// Like [`write`], but only the owner can read the file, and it must not exist yet.
+fn write_secret(path: &str, contents: String) -> Result<(), String> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| format!("failed to write {path}: {e}"))
+}
@@ -0,0 +1,66 @@
+#!/usr/bin/env python3
+# gzips everything in www/ and uploads it to the wand's /littlefs/www
+#   HITACHI_ADMIN_TOKEN=... python3 upload-www.py 192.168.1.50 [port]
+# the token is the one set with `http token set` on the console
+
+import gzip
+import os
+import sys
+import urllib.error
+import urllib.request
+
+WWW = os.path.join(os.path.dirname(os.path.abspath(__file__)), "www")
+# see MAX_ASSET_LEN in conn/www.rs
+MAX_ASSET_LEN = 64 * 1024
+
+
+def request(method, url, token, data=None):
+    req = urllib.request.Request(url, data=data, method=method)
+    req.add_header("Content-Type", "application/octet-stream")
+    req.add_header("Authorization", f"Bearer {token}")
+    try:
+        with urllib.request.urlopen(req, timeout=10) as res:
+            return res.status
+    except urllib.error.HTTPError as e:
+        return e.code
+
+
+def main():
+    if len(sys.argv) < 2:
+        print(f"usage: {sys.argv[0]} <ip> [port]")
+        sys.exit(1)
+
+    token = os.environ.get("HITACHI_ADMIN_TOKEN")
+    if not token:
+        print("set HITACHI_ADMIN_TOKEN to the wand's admin token (`http token set` on the console)")
+        sys.exit(1)
+
+    base = f"http://{sys.argv[1]}:{sys.argv[2] if len(sys.argv) > 2 else 8080}/www"
+
+    for root, _, files in os.walk(WWW):
+        for name in sorted(files):
+            path = os.path.relpath(os.path.join(root, name), WWW).replace(os.sep, "/")
+            with open(os.path.join(root, name), "rb") as f:
+                data = gzip.compress(f.read(), mtime=0)
+
+            if len(data) > MAX_ASSET_LEN:
+                print(f"{path}: {len(data)} bytes gzipped, over the {MAX_ASSET_LEN} byte limit")
+                sys.exit(1)
+
+            status = request("PUT", f"{base}/{path}.gz", token, data)
+            if status == 401:
+                print("the wand rejected HITACHI_ADMIN_TOKEN")
+                sys.exit(1)
+            if status == 403:
+                print("the wand has no admin token yet, set one with `http token set` on the console")
+                sys.exit(1)
+            if status != 201:
+                print(f"{path}: upload failed with {status}")
+                sys.exit(1)
+            # an uncompressed copy from an older upload would only take up space
+            request("DELETE", f"{base}/{path}", token)
+            print(f"{path}: {len(data)} bytes")
+
+
+if __name__ == "__main__":
+    main()
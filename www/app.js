@@ -0,0 +1,198 @@
+"use strict";
+
+// intensity goes over the websocket when it's up (see hitachi-core/src/ws.rs), everything else over the
+// JSON API (see hitachi-core/src/api.rs)
+
+const $ = (id) => document.getElementById(id);
+
+const MSG_INTENSITY = 0x01;
+const MSG_PING = 0x04;
+const MSG_STATE = 0x81;
+const MSG_ERROR = 0xee;
+
+let socket = null;
+let dragging = false;
+
+async function api(method, path, body, headers = {}) {
+  const res = await fetch(path, {
+    method,
+    headers: body === undefined ? headers : { ...headers, "Content-Type": "application/json" },
+    body: body === undefined ? undefined : JSON.stringify(body),
+  });
+  const text = await res.text();
+  const json = text ? JSON.parse(text) : null;
+  if (!res.ok) {
+    throw new Error(json && json.message ? json.message : `${res.status} ${res.statusText}`);
+  }
+  return json;
+}
+
+function command(method, path, body) {
+  api(method, path, body).catch((e) => ($("link").textContent = e.message));
+}
+
+// intensity
+
+let pending = null;
+let inFlight = false;
+
+// without the websocket, at most one PUT at a time - the latest value wins
+function putIntensity(level) {
+  pending = level;
+  if (inFlight) {
+    return;
+  }
+
+  inFlight = true;
+  const next = pending;
+  pending = null;
+  api("PUT", "/api/intensity", { level: next })
+    .catch((e) => ($("link").textContent = e.message))
+    .finally(() => {
+      inFlight = false;
+      if (pending !== null) {
+        putIntensity(pending);
+      }
+    });
+}
+
+function setIntensity(level) {
+  if (socket && socket.readyState === WebSocket.OPEN) {
+    const msg = new DataView(new ArrayBuffer(3));
+    msg.setUint8(0, MSG_INTENSITY);
+    msg.setUint16(1, level, true);
+    socket.send(msg.buffer);
+  } else {
+    putIntensity(level);
+  }
+}
+
+$("intensity").addEventListener("input", (e) => setIntensity(Number(e.target.value)));
+$("intensity").addEventListener("pointerdown", () => (dragging = true));
+$("intensity").addEventListener("pointerup", () => (dragging = false));
+$("stop").addEventListener("click", () => command("POST", "/api/stop"));
+
+// patterns
+
+$("start").addEventListener("click", () =>
+  command("POST", "/api/pattern", {
+    command: "start",
+    pattern: $("pattern").value,
+    period_ms: Number($("period").value),
+  })
+);
+$("next").addEventListener("click", () => command("POST", "/api/pattern", { command: "next" }));
+$("previous").addEventListener("click", () =>
+  command("POST", "/api/pattern", { command: "previous" })
+);
+
+// state
+
+function showLevels(target, actual) {
+  $("target").textContent = target;
+  $("actual").textContent = actual;
+  if (!dragging) {
+    $("intensity").value = target;
+  }
+}
+
+function showState(state) {
+  showLevels(state.intensity.target, state.intensity.actual);
+  $("mode").textContent = state.pattern ? `pattern (${state.pattern.pattern})` : state.mode;
+
+  const temp = (c) => (c === null ? "unknown" : `${c.toFixed(1)} °C`);
+  $("motor-temp").textContent = temp(state.temperature.motor_c);
+  $("chip-temp").textContent = temp(state.temperature.chip_c);
+  $("thermal").textContent = `${state.thermal.state}, cap ${state.thermal.cap}`;
+
+  const control = state.control;
+  $("owner").textContent =
+    (control.owner || "nobody") + (control.paused ? ", remotes paused" : "");
+  $("faults").textContent = state.faults.length ? state.faults.join(", ") : "none";
+}
+
+async function poll() {
+  try {
+    showState(await api("GET", "/api/state"));
+  } catch (e) {
+    $("link").textContent = `can't reach the wand: ${e.message}`;
+  }
+  setTimeout(poll, 1000);
+}
+
+function connect() {
+  socket = new WebSocket(`ws://${location.host}/ws`);
+  socket.binaryType = "arraybuffer";
+
+  socket.onopen = () => ($("link").textContent = "live");
+  socket.onclose = () => {
+    $("link").textContent = "websocket closed, retrying...";
+    socket = null;
+    setTimeout(connect, 2000);
+  };
+  socket.onmessage = (e) => {
+    const msg = new DataView(e.data);
+    switch (msg.getUint8(0)) {
+      case MSG_STATE:
+        // the rest comes with the next poll
+        showLevels(msg.getUint16(1, true), msg.getUint16(3, true));
+        break;
+      case MSG_ERROR:
+        $("link").textContent = new TextDecoder().decode(e.data.slice(1));
+        break;
+    }
+  };
+}
+
+// the wand closes sockets that have been quiet for 15 seconds
+setInterval(() => {
+  if (socket && socket.readyState === WebSocket.OPEN) {
+    socket.send(new Uint8Array([MSG_PING]));
+  }
+}, 5000);
+
+// config
+
+async function loadConfig() {
+  try {
+    const config = await api("GET", "/config");
+    $("config").value = JSON.stringify(config, null, 2);
+    $("config-status").textContent = "";
+  } catch (e) {
+    $("config-status").textContent = `couldn't load the config: ${e.message}`;
+  }
+}
+
+async function saveConfig() {
+  let config;
+  try {
+    config = JSON.parse($("config").value);
+  } catch (e) {
+    $("config-status").textContent = `not valid JSON: ${e.message}`;
+    return;
+  }
+
+  // secrets come back as "********", which the wand takes to mean "unchanged". changes need the
+  // admin token, set with `http token set` on the console
+  const token = $("token").value.trim();
+  try {
+    const saved = await api("PATCH", "/config", config, { Authorization: `Bearer ${token}` });
+    const parts = [];
+    if (saved.applied.length) {
+      parts.push(`applied ${saved.applied.join(", ")}`);
+    }
+    if (saved.restart_required.length) {
+      parts.push(`restart to apply ${saved.restart_required.join(", ")}`);
+    }
+    $("config-status").textContent = parts.length ? parts.join(" - ") : "nothing changed";
+  } catch (e) {
+    $("config-status").textContent = `not saved: ${e.message}`;
+  }
+}
+
+$("config-load").addEventListener("click", loadConfig);
+$("config-save").addEventListener("click", saveConfig);
+
+connect();
+poll();
+loadConfig();